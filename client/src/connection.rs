@@ -0,0 +1,774 @@
+use crate::download::DownloadOptions;
+use crate::encryption::Encryption;
+use crate::output::{say, Event};
+use crate::transfer::{send_file, PendingUploads};
+use crate::{handle_message, negotiate_protocol, read_required_frame, send_message};
+use crate::{ServerReader, ServerWriter};
+use shared::client_error::ClientError;
+use shared::frame::{FrameReader, FrameWriter};
+use shared::protocol::Envelope;
+use shared::tls::{BoxedStream, TlsClient};
+use shared::{serialize_auth_request, AuthRequest, MessageType, AUTH_SUCCESS, DEFAULT_ROOM};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle, JoinSet};
+use tokio::time::{interval_at, sleep, timeout, Duration, Instant};
+
+/// Prodleva před prvním pokusem o obnovení spojení
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Nejdelší prodleva mezi dvěma pokusy o obnovení spojení
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Nejvyšší počet zpráv odložených během výpadku spojení
+const OFFLINE_QUEUE_LIMIT: usize = 1000;
+
+/// Jak dlouho se při ukončení klienta čeká, až server spojení zavře
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Požadavek uživatele, který se má odeslat na server
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outgoing {
+    /// Zpráva, která se odešle beze změny
+    Message(MessageType),
+    /// Soubor nebo obrázek, který se odešle po částech
+    Upload { path: PathBuf, image: bool },
+    /// Soukromá zpráva, která se před odesláním zašifruje klíčem příjemce
+    Direct { recipient: String, text: String },
+    /// Přijetí nabídnutého souboru, o kterém měl rozhodnout uživatel
+    AcceptFile(u64),
+    /// Odmítnutí nabídnutého souboru, o kterém měl rozhodnout uživatel
+    RejectFile(u64),
+}
+
+/// Nastavení připojení k serveru
+#[derive(Clone)]
+pub struct ConnectOptions {
+    pub address: String,
+    /// TLS spojení se serverem; bez něj klient komunikuje nešifrovaně
+    pub tls: Option<TlsClient>,
+    pub username: String,
+    pub password: String,
+    pub max_frame_size: usize,
+    /// Interval mezi pingy posílanými serveru
+    pub heartbeat_interval: Duration,
+    /// Doba bez zprávy od serveru, po které se spojení považuje za ztracené
+    pub heartbeat_timeout: Duration,
+    /// Zda klíč tohoto klienta nahradí klíč zveřejněný jiným zařízením
+    pub replace_key: bool,
+}
+
+/// Navázané a přihlášené spojení se serverem
+pub struct Connected {
+    reader: ServerReader,
+    writer: ServerWriter,
+    version: u16,
+}
+
+/// Stav relace, který přetrvá výpadky spojení
+#[derive(Debug)]
+pub struct Session {
+    /// Místnost, ve které se uživatel nachází
+    pub room: String,
+    /// ID poslední zprávy, kterou klient viděl, podle místnosti
+    last_seen: HashMap<String, i64>,
+    /// Nabídnuté soubory čekající na rozhodnutí uživatele, podle ID přenosu
+    offered_files: HashSet<u64>,
+    /// Soubory, které uživatel přijal, podle ID přenosu
+    accepted_files: HashSet<u64>,
+}
+
+/// Stav relace sdílený čtením zpráv a správou spojení
+pub type SharedSession = Arc<Mutex<Session>>;
+
+impl Session {
+    /// Vytvoří relaci ve výchozí místnosti
+    pub fn shared() -> SharedSession {
+        Arc::new(Mutex::new(Session {
+            room: DEFAULT_ROOM.to_string(),
+            last_seen: HashMap::new(),
+            offered_files: HashSet::new(),
+            accepted_files: HashSet::new(),
+        }))
+    }
+
+    /// Zaznamená zprávu z místnosti.
+    ///
+    /// # Arguments
+    ///
+    /// * `room` - Místnost, do které zpráva patří
+    /// * `id` - ID zprávy přidělené serverem; neuložené zprávy mají ID 0
+    ///
+    /// # Returns
+    ///
+    /// Vrací `false`, pokud klient zprávu už viděl.
+    pub fn observe(&mut self, room: &str, id: i64) -> bool {
+        if id <= 0 {
+            return true;
+        }
+        let last = self.last_seen.entry(room.to_string()).or_default();
+        if id <= *last {
+            return false;
+        }
+        *last = id;
+        true
+    }
+
+    /// Zaznamená nabídku souboru, o které má rozhodnout uživatel.
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - ID přenosu
+    ///
+    /// # Returns
+    ///
+    /// Vrací `true`, pokud jde o novou nabídku, na kterou je třeba uživatele upozornit.
+    pub fn offer_file(&mut self, transfer_id: u64) -> bool {
+        !self.accepted_files.contains(&transfer_id) && self.offered_files.insert(transfer_id)
+    }
+
+    /// Vrací `true`, pokud uživatel soubor z přenosu `transfer_id` přijal
+    pub fn is_file_accepted(&self, transfer_id: u64) -> bool {
+        self.accepted_files.contains(&transfer_id)
+    }
+
+    /// Přijme nabídnutý soubor.
+    ///
+    /// # Returns
+    ///
+    /// Vrací `false`, pokud soubor s tímto ID přenosu nabídnut nebyl.
+    pub fn accept_file(&mut self, transfer_id: u64) -> bool {
+        if self.offered_files.remove(&transfer_id) {
+            self.accepted_files.insert(transfer_id);
+        }
+        self.accepted_files.contains(&transfer_id)
+    }
+
+    /// Odmítne nabídnutý soubor.
+    ///
+    /// # Returns
+    ///
+    /// Vrací `false`, pokud na rozhodnutí o souboru s tímto ID přenosu nic nečekalo.
+    pub fn reject_file(&mut self, transfer_id: u64) -> bool {
+        self.offered_files.remove(&transfer_id)
+    }
+
+    /// Vrací zprávy, kterými se klient po obnovení spojení vrátí do původní
+    /// místnosti a vyžádá si zprávy, které během výpadku zmeškal.
+    pub fn resume_messages(&self) -> Vec<MessageType> {
+        let mut messages = Vec::new();
+        if self.room != DEFAULT_ROOM {
+            messages.push(MessageType::JoinRoom(self.room.clone()));
+        }
+        if let Some(&after_id) = self.last_seen.get(&self.room) {
+            messages.push(MessageType::FetchHistory { after_id });
+        }
+        messages
+    }
+}
+
+/// Připojí se k serveru, dohodne verzi protokolu a přihlásí uživatele.
+///
+/// # Arguments
+///
+/// * `options` - Nastavení připojení
+/// * `register` - Zda se má místo přihlášení zaregistrovat nový účet
+///
+/// # Errors
+///
+/// Vrací `ClientError::Authentication`, pokud server přihlášení odmítl, a
+/// `ClientError::IncompatibleProtocol`, pokud se nedohodne verze protokolu.
+pub async fn connect(options: &ConnectOptions, register: bool) -> Result<Connected, ClientError> {
+    let login = async {
+        let stream = TcpStream::connect(&options.address).await?;
+        let stream: BoxedStream = match &options.tls {
+            Some(tls) => tls.connect(stream).await?,
+            None => Box::new(stream),
+        };
+        let (reader, writer) = tokio::io::split(stream);
+        let mut reader = FrameReader::with_max_frame_size(reader, options.max_frame_size);
+        let mut writer = FrameWriter::with_max_frame_size(writer, options.max_frame_size);
+
+        let version = negotiate_protocol(&mut reader, &mut writer).await?;
+        let (username, password) = (options.username.clone(), options.password.clone());
+        let auth_request = if register {
+            AuthRequest::Register { username, password }
+        } else {
+            AuthRequest::Login { username, password }
+        };
+        writer
+            .write_frame(&serialize_auth_request(&auth_request)?)
+            .await?;
+
+        let response = read_required_frame(&mut reader).await?;
+        let response = String::from_utf8_lossy(&response).to_string();
+        say!("Server response: {}", response);
+        if !response.contains(AUTH_SUCCESS) {
+            return Err(ClientError::Authentication(response));
+        }
+        Ok(Connected {
+            reader,
+            writer,
+            version,
+        })
+    };
+
+    timeout(options.heartbeat_timeout, login)
+        .await
+        .map_err(|_| ClientError::ConnectionError("Server did not answer in time".to_string()))?
+}
+
+/// Správa spojení se serverem, která ho po výpadku obnoví.
+///
+/// Během výpadku se zprávy uživatele odkládají do fronty a po obnovení spojení
+/// se odešlou. Přerušené odesílání souborů se po obnovení naváže.
+pub struct Connection {
+    options: ConnectOptions,
+    session: SharedSession,
+    encryption: Encryption,
+    downloads: DownloadOptions,
+    /// Kanál, kterým se přerušená odesílání vrací do fronty; neudržuje ho
+    /// otevřený, kanál zanikne s poslední odesílací stranou uživatele
+    requeue: mpsc::WeakSender<Outgoing>,
+    next_transfer_id: u64,
+}
+
+impl Connection {
+    /// Vytvoří správu spojení
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - Nastavení připojení
+    /// * `encryption` - Klíče pro šifrované soukromé zprávy
+    /// * `downloads` - Nastavení ukládání přijatých souborů
+    /// * `requeue` - Odesílací strana kanálu, ze kterého správa spojení čte požadavky
+    pub fn new(
+        options: ConnectOptions,
+        encryption: Encryption,
+        downloads: DownloadOptions,
+        requeue: &mpsc::Sender<Outgoing>,
+    ) -> Self {
+        Connection {
+            options,
+            session: Session::shared(),
+            encryption,
+            downloads,
+            requeue: requeue.downgrade(),
+            next_transfer_id: 1,
+        }
+    }
+
+    /// Zpráva, kterou se po připojení zveřejní klíč klienta
+    fn publish_key(&self) -> MessageType {
+        MessageType::PublishKey {
+            key: self.encryption.public_key(),
+            replace: self.options.replace_key,
+        }
+    }
+
+    /// Obsluhuje spojení a po jeho ztrátě ho obnovuje s exponenciálně rostoucí prodlevou.
+    ///
+    /// Skončí, pokud se obnovení nepodaří kvůli odmítnutému přihlášení nebo
+    /// nekompatibilnímu protokolu, nebo pokud zanikne kanál s požadavky uživatele.
+    ///
+    /// # Arguments
+    ///
+    /// * `connected` - Už navázané spojení
+    /// * `outgoing` - Požadavky uživatele
+    pub async fn run(mut self, connected: Connected, mut outgoing: mpsc::Receiver<Outgoing>) {
+        let mut offline = VecDeque::new();
+        let mut connected = Some(connected);
+        loop {
+            let current = match connected.take() {
+                Some(current) => current,
+                None => match self.reconnect(&mut outgoing, &mut offline).await {
+                    Some(current) => current,
+                    None => return,
+                },
+            };
+            if !self.serve(current, &mut outgoing, &mut offline).await {
+                return;
+            }
+            say!("Connection to server lost");
+        }
+    }
+
+    /// Opakovaně se pokouší o obnovení spojení, mezitím odkládá požadavky uživatele.
+    ///
+    /// # Returns
+    ///
+    /// Vrací nové spojení, nebo `None`, pokud obnovení nemá smysl dál zkoušet.
+    async fn reconnect(
+        &mut self,
+        outgoing: &mut mpsc::Receiver<Outgoing>,
+        offline: &mut VecDeque<Outgoing>,
+    ) -> Option<Connected> {
+        let mut delay = INITIAL_BACKOFF;
+        loop {
+            say!("Reconnecting in {} s...", delay.as_secs());
+            let wait = sleep(delay);
+            tokio::pin!(wait);
+            loop {
+                tokio::select! {
+                    _ = &mut wait => break,
+                    request = outgoing.recv() => match request {
+                        Some(request) => queue_offline(offline, request),
+                        None => return None,
+                    },
+                }
+            }
+
+            match connect(&self.options, false).await {
+                Ok(connected) => {
+                    say!("Reconnected to {}", self.options.address);
+                    return Some(connected);
+                }
+                Err(
+                    e @ (ClientError::Authentication(_) | ClientError::IncompatibleProtocol(_)),
+                ) => {
+                    say!("Cannot reconnect: {}", e);
+                    return None;
+                }
+                Err(e) => {
+                    say!("Reconnect failed: {}", e);
+                    delay = (delay * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Obsluhuje jedno spojení, dokud neskončí.
+    ///
+    /// Nejdřív obnoví stav relace a odešle odložené požadavky, potom předává
+    /// požadavky uživatele a odpovědi čtení zpráv na server.
+    ///
+    /// # Returns
+    ///
+    /// Vrací `false`, pokud zanikl kanál s požadavky uživatele a klient končí.
+    async fn serve(
+        &mut self,
+        connected: Connected,
+        outgoing: &mut mpsc::Receiver<Outgoing>,
+        offline: &mut VecDeque<Outgoing>,
+    ) -> bool {
+        let Connected {
+            reader,
+            mut writer,
+            version,
+        } = connected;
+        // Zprávy od úloh tohoto spojení (odpovědi, pingy, části souborů) zanikají s ním
+        let (sender, mut receiver) = mpsc::channel::<MessageType>(32);
+        let uploads = PendingUploads::default();
+
+        let resume = self.session.lock().unwrap().resume_messages();
+        let mut reader_task = task::spawn(handle_message(
+            reader,
+            sender.clone(),
+            Arc::clone(&uploads),
+            Arc::clone(&self.session),
+            self.encryption.clone(),
+            self.downloads.clone(),
+            self.options.heartbeat_timeout,
+        ));
+        spawn_heartbeat(sender.clone(), self.options.heartbeat_interval);
+
+        // Klíč se zveřejňuje při každém připojení; jiný klíč téhož účtu server odmítne
+        let public_key = self.publish_key();
+        let mut connected = self.write(&mut writer, version, public_key).await.is_ok();
+        for message in resume {
+            connected &= self.write(&mut writer, version, message).await.is_ok();
+        }
+        while connected {
+            let Some(request) = offline.pop_front() else {
+                break;
+            };
+            connected = self
+                .dispatch(&mut writer, version, &sender, &uploads, request, offline)
+                .await;
+        }
+
+        let mut running = true;
+        while connected {
+            tokio::select! {
+                message = receiver.recv() => {
+                    let Some(message) = message else { break };
+                    connected = self.write(&mut writer, version, message).await.is_ok();
+                }
+                request = outgoing.recv() => match request {
+                    Some(request) => {
+                        connected = self
+                            .dispatch(&mut writer, version, &sender, &uploads, request, offline)
+                            .await;
+                    }
+                    None => {
+                        running = false;
+                        break;
+                    }
+                },
+                result = &mut reader_task => {
+                    if let Ok(Err(e)) = result {
+                        say!("Error handling message: {}", e);
+                    }
+                    break;
+                }
+            }
+        }
+
+        if !running {
+            close(&mut writer, &mut reader_task).await;
+        }
+        reader_task.abort();
+        // Odesílání souborů čekající na přijetí nabídky se tím dozví o ztrátě spojení
+        uploads.lock().await.clear();
+        self.encryption.cancel_pending().await;
+        running
+    }
+
+    /// Odešle požadavky uživatele jediným spojením a skončí, až je server všechny zpracuje.
+    ///
+    /// Spojení se po výpadku neobnovuje. Požadavky se začnou odesílat až po
+    /// vstupu do místnosti `room`; na konci klient serveru pošle požadavek
+    /// `MessageType::ListUsers` a na odpověď čeká, aby zachytil i chyby hlášené
+    /// k posledním zprávám. Výstup klienta musí být přesměrovaný do `events`.
+    ///
+    /// # Arguments
+    ///
+    /// * `connected` - Už navázané spojení
+    /// * `room` - Místnost, do které se zprávy odešlou
+    /// * `outgoing` - Požadavky uživatele; odesílání skončí zánikem kanálu
+    /// * `events` - Přesměrovaný výstup klienta
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ClientError::Server`, pokud server některý požadavek odmítl, a
+    /// `ClientError::ConnectionError`, pokud spojení skončí dřív, než je vše odesláno.
+    pub async fn deliver(
+        mut self,
+        connected: Connected,
+        room: &str,
+        mut outgoing: mpsc::Receiver<Outgoing>,
+        mut events: mpsc::UnboundedReceiver<Event>,
+    ) -> Result<(), ClientError> {
+        let Connected {
+            reader,
+            mut writer,
+            version,
+        } = connected;
+        let (sender, mut receiver) = mpsc::channel::<MessageType>(32);
+        let uploads = PendingUploads::default();
+        let mut reader_task = task::spawn(handle_message(
+            reader,
+            sender.clone(),
+            Arc::clone(&uploads),
+            Arc::clone(&self.session),
+            self.encryption.clone(),
+            self.downloads.clone(),
+            self.options.heartbeat_timeout,
+        ));
+        spawn_heartbeat(sender.clone(), self.options.heartbeat_interval);
+
+        // Odesílání souborů a šifrovaných zpráv běží souběžně se zápisem na server
+        let mut tasks = JoinSet::new();
+        let mut failure = None;
+        let result = async {
+            let public_key = self.publish_key();
+            self.write(&mut writer, version, public_key).await?;
+            if room != DEFAULT_ROOM {
+                let join = MessageType::JoinRoom(room.to_string());
+                self.write(&mut writer, version, join).await?;
+            }
+
+            let (mut joined, mut reading, mut finishing) = (false, true, false);
+            loop {
+                if joined && !reading && tasks.is_empty() && !finishing {
+                    self.write(&mut writer, version, MessageType::ListUsers)
+                        .await?;
+                    finishing = true;
+                }
+                tokio::select! {
+                    message = receiver.recv() => {
+                        let Some(message) = message else { return Ok(()) };
+                        self.write(&mut writer, version, message).await?;
+                    }
+                    request = outgoing.recv(), if joined && reading => match request {
+                        Some(Outgoing::Message(message)) => {
+                            self.write(&mut writer, version, message).await?
+                        }
+                        Some(Outgoing::Upload { path, image }) => {
+                            let transfer_id = self.next_transfer_id;
+                            self.next_transfer_id += 1;
+                            let (sender, uploads) = (sender.clone(), Arc::clone(&uploads));
+                            tasks.spawn(async move {
+                                send_file(&sender, &uploads, transfer_id, &path, image)
+                                    .await
+                                    .map(|_| ())
+                            });
+                        }
+                        Some(Outgoing::Direct { recipient, text }) => {
+                            let (sender, encryption) = (sender.clone(), self.encryption.clone());
+                            tasks.spawn(async move {
+                                encryption.send_direct(&sender, &recipient, &text).await
+                            });
+                        }
+                        // Příkaz `send` o nabídnutých souborech nerozhoduje
+                        Some(Outgoing::AcceptFile(_) | Outgoing::RejectFile(_)) => {}
+                        None => reading = false,
+                    },
+                    Some(result) = tasks.join_next() => {
+                        let result = result.map_err(|e| ClientError::Other(e.to_string()));
+                        if let Err(e) = result.and_then(|result| result) {
+                            failure.get_or_insert(e);
+                        }
+                    }
+                    event = events.recv() => match event {
+                        Some(Event::RoomJoined(name)) if name == room => joined = true,
+                        // Zprávy se nesmí odeslat do jiné místnosti, než do které patří
+                        Some(Event::ServerError(reason)) if !joined => {
+                            return Err(ClientError::Server(reason))
+                        }
+                        Some(Event::ServerError(reason)) => {
+                            failure.get_or_insert(ClientError::Server(reason));
+                        }
+                        Some(Event::Users(_)) if finishing => return Ok(()),
+                        Some(_) => {}
+                        None => return Ok(()),
+                    },
+                    result = &mut reader_task => {
+                        return Err(match result {
+                            Ok(Err(e)) => e,
+                            _ => ClientError::ConnectionError(
+                                "Connection closed by server".to_string(),
+                            ),
+                        });
+                    }
+                }
+            }
+        }
+        .await;
+
+        if result.is_ok() {
+            close(&mut writer, &mut reader_task).await;
+        }
+        reader_task.abort();
+        result?;
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Odešle požadavek uživatele, případně ho při chybě vrátí do fronty.
+    ///
+    /// # Returns
+    ///
+    /// Vrací `false`, pokud se zprávu nepodařilo zapsat a spojení je ztracené.
+    async fn dispatch(
+        &mut self,
+        writer: &mut ServerWriter,
+        version: u16,
+        sender: &mpsc::Sender<MessageType>,
+        uploads: &PendingUploads,
+        request: Outgoing,
+        offline: &mut VecDeque<Outgoing>,
+    ) -> bool {
+        match request {
+            Outgoing::Message(message) => {
+                if self.write(writer, version, message.clone()).await.is_err() {
+                    offline.push_front(Outgoing::Message(message));
+                    return false;
+                }
+            }
+            Outgoing::Upload { path, image } => {
+                let transfer_id = self.next_transfer_id;
+                self.next_transfer_id += 1;
+                let (sender, uploads, requeue) =
+                    (sender.clone(), Arc::clone(uploads), self.requeue.clone());
+                // Soubor se odesílá po částech na pozadí, aby šlo mezitím psát zprávy
+                task::spawn(async move {
+                    let name = path.display().to_string();
+                    match send_file(&sender, &uploads, transfer_id, &path, image).await {
+                        Ok(0) => say!("Sent {}", name),
+                        Ok(offset) => say!("Sent {} (resumed from byte {})", name, offset),
+                        Err(ClientError::ConnectionError(_)) => {
+                            say!("Sending {} was interrupted, it will resume later", name);
+                            if let Some(requeue) = requeue.upgrade() {
+                                let _ = requeue.send(Outgoing::Upload { path, image }).await;
+                            }
+                        }
+                        Err(e) => say!("Error sending {}: {}", name, e),
+                    }
+                });
+            }
+            Outgoing::Direct { recipient, text } => {
+                let (sender, encryption, requeue) = (
+                    sender.clone(),
+                    self.encryption.clone(),
+                    self.requeue.clone(),
+                );
+                // Na klíč příjemce se čeká na pozadí, čtení zpráv mezitím pokračuje
+                task::spawn(async move {
+                    match encryption.send_direct(&sender, &recipient, &text).await {
+                        Ok(()) => {}
+                        Err(ClientError::ConnectionError(_)) => {
+                            if let Some(requeue) = requeue.upgrade() {
+                                let _ = requeue.send(Outgoing::Direct { recipient, text }).await;
+                            }
+                        }
+                        Err(e) => say!("Cannot send private message to {}: {}", recipient, e),
+                    }
+                });
+            }
+            Outgoing::AcceptFile(transfer_id) => {
+                if !self.session.lock().unwrap().accept_file(transfer_id) {
+                    say!("No file offer with ID {}", transfer_id);
+                    return true;
+                }
+                // Server přenos zopakuje od začátku i s nabídkou
+                let request = MessageType::FileRequest {
+                    transfer_id,
+                    offset: 0,
+                };
+                if self.write(writer, version, request).await.is_err() {
+                    offline.push_front(Outgoing::AcceptFile(transfer_id));
+                    return false;
+                }
+            }
+            Outgoing::RejectFile(transfer_id) => {
+                if self.session.lock().unwrap().reject_file(transfer_id) {
+                    say!("Rejected file offer {}", transfer_id);
+                } else {
+                    say!("No file offer with ID {}", transfer_id);
+                }
+            }
+        }
+        true
+    }
+
+    /// Zapíše zprávu na server.
+    ///
+    /// Zápis má stejný časový limit jako příjem, aby se klient nezasekl na
+    /// spojení, které server už nečte.
+    async fn write(
+        &self,
+        writer: &mut ServerWriter,
+        version: u16,
+        message: MessageType,
+    ) -> Result<(), ClientError> {
+        let envelope = Envelope {
+            version,
+            ..Envelope::new(message, DEFAULT_ROOM)
+        };
+        let result = timeout(
+            self.options.heartbeat_timeout,
+            send_message(writer, &envelope),
+        )
+        .await
+        .unwrap_or_else(|_| Err(ClientError::ConnectionError("Write timed out".to_string())));
+        if let Err(e) = &result {
+            say!("Error sending message: {}", e);
+        }
+        result
+    }
+}
+
+/// Ukončí spojení tak, aby server přečetl vše, co mu klient poslal.
+///
+/// Klient serveru oznámí konec zápisu a dočte zprávy, které server mezitím
+/// poslal. Zavření spojení s nepřečtenými daty by skončilo resetem, při kterém
+/// server může přijít o poslední zprávy klienta.
+///
+/// # Arguments
+///
+/// * `writer` - Zapisovací část spojení se serverem
+/// * `reader_task` - Úloha čtoucí zprávy od serveru; skončí, až server spojení zavře
+async fn close(writer: &mut ServerWriter, reader_task: &mut JoinHandle<Result<(), ClientError>>) {
+    let _ = writer.get_mut().shutdown().await;
+    let _ = timeout(CLOSE_TIMEOUT, reader_task).await;
+}
+
+/// Odloží požadavek uživatele do fronty, dokud se spojení neobnoví.
+///
+/// Pokud je fronta plná, zahodí se nejstarší odložený požadavek.
+///
+/// # Arguments
+///
+/// * `offline` - Fronta odložených požadavků
+/// * `request` - Nový požadavek
+fn queue_offline(offline: &mut VecDeque<Outgoing>, request: Outgoing) {
+    if offline.len() >= OFFLINE_QUEUE_LIMIT {
+        offline.pop_front();
+        say!("Offline queue is full, dropped the oldest message");
+    }
+    offline.push_back(request);
+    say!(
+        "Not connected, {} message(s) will be sent after reconnecting",
+        offline.len()
+    );
+}
+
+/// Spustí pravidelné posílání pingů, aby server poznal živého klienta,
+/// i když uživatel nic nepíše. Úloha skončí se zánikem spojení.
+///
+/// # Arguments
+///
+/// * `sender` - Kanál pro zprávy odesílané na server
+/// * `period` - Interval mezi dvěma pingy
+fn spawn_heartbeat(sender: mpsc::Sender<MessageType>, period: Duration) {
+    task::spawn(async move {
+        let mut ticker = interval_at(Instant::now() + period, period);
+        loop {
+            ticker.tick().await;
+            if sender.send(MessageType::Ping).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_deduplicates_and_resumes() {
+        let session = Session::shared();
+        let mut session = session.lock().unwrap();
+        assert!(session.resume_messages().is_empty());
+
+        assert!(session.observe(DEFAULT_ROOM, 3));
+        assert!(!session.observe(DEFAULT_ROOM, 2));
+        assert!(session.observe(DEFAULT_ROOM, 0));
+        assert_eq!(
+            session.resume_messages(),
+            vec![MessageType::FetchHistory { after_id: 3 }]
+        );
+
+        // Each room remembers its own position
+        session.room = "dev".to_string();
+        assert!(session.observe("dev", 2));
+        assert_eq!(
+            session.resume_messages(),
+            vec![
+                MessageType::JoinRoom("dev".to_string()),
+                MessageType::FetchHistory { after_id: 2 }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_offline_queue_is_bounded() {
+        let mut offline = VecDeque::new();
+        for i in 0..=OFFLINE_QUEUE_LIMIT {
+            queue_offline(
+                &mut offline,
+                Outgoing::Message(MessageType::Text(i.to_string())),
+            );
+        }
+        assert_eq!(offline.len(), OFFLINE_QUEUE_LIMIT);
+        assert_eq!(
+            offline.front(),
+            Some(&Outgoing::Message(MessageType::Text("1".to_string())))
+        );
+    }
+}
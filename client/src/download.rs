@@ -0,0 +1,240 @@
+use shared::client_error::ClientError;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Výchozí největší velikost přijímaného souboru v bajtech (100 MiB)
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Nejdelší název uloženého souboru v bajtech
+const MAX_FILENAME_LENGTH: usize = 200;
+
+/// Kolik očíslovaných variant názvu se nejvýše zkusí, než se ukládání vzdá
+const MAX_NAME_ATTEMPTS: u32 = 10_000;
+
+/// Důvod odmítnutí souboru při `IncomingPolicy::Reject`
+pub const REJECTED: &str = "incoming files are rejected";
+
+/// Co klient udělá se souborem, který mu někdo pošle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum IncomingPolicy {
+    /// Soubor přijme a uloží
+    #[default]
+    Accept,
+    /// Zeptá se uživatele, který soubor přijme příkazem `.accept` nebo odmítne příkazem `.reject`
+    Prompt,
+    /// Soubor odmítne
+    Reject,
+}
+
+/// Nastavení ukládání přijatých souborů
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Adresář, ve kterém se vytvoří složky `files` a `images`
+    pub dir: PathBuf,
+    /// Největší přijímaný soubor v bajtech
+    pub max_size: u64,
+    pub policy: IncomingPolicy,
+}
+
+impl DownloadOptions {
+    /// Ověří, že soubor nepřekračuje povolenou velikost.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Název souboru od odesílatele
+    /// * `size` - Velikost souboru v bajtech
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ClientError::InvalidInput`, pokud je soubor větší než `max_size`.
+    pub fn check_size(&self, filename: &str, size: u64) -> Result<(), ClientError> {
+        if size > self.max_size {
+            return Err(ClientError::InvalidInput(format!(
+                "{} has {} bytes, more than the download limit of {} bytes",
+                filename, size, self.max_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Zjistí, zda se soubor přijatý najednou v jedné zprávě zahodí.
+    ///
+    /// Takový soubor nemá ID přenosu, nejde ho proto nabídnout k `.accept`
+    /// a při `IncomingPolicy::Prompt` se zahodí stejně jako při `IncomingPolicy::Reject`.
+    ///
+    /// # Returns
+    ///
+    /// Vrací důvod zahození pro uživatele, nebo `None`, pokud se soubor uloží.
+    pub fn inline_rejection(&self) -> Option<&'static str> {
+        match self.policy {
+            IncomingPolicy::Accept => None,
+            IncomingPolicy::Prompt => Some(
+                "files sent inline cannot be accepted later, use --incoming-files accept to receive them",
+            ),
+            IncomingPolicy::Reject => Some(REJECTED),
+        }
+    }
+
+    /// Uloží soubor přijatý najednou v jedné zprávě pod volným názvem.
+    ///
+    /// # Arguments
+    ///
+    /// * `folder` - Složka v adresáři pro přijaté soubory (`files` nebo `images`)
+    /// * `filename` - Název souboru od odesílatele; před uložením se upraví
+    /// * `data` - Obsah souboru
+    ///
+    /// # Returns
+    ///
+    /// Vrací cestu, na kterou byl soubor uložen.
+    pub fn save(&self, folder: &str, filename: &str, data: &[u8]) -> Result<PathBuf, ClientError> {
+        self.check_size(filename, data.len() as u64)?;
+        let (path, mut file) = create_unique(&self.dir.join(folder), &sanitize_filename(filename))?;
+        file.write_all(data)?;
+        Ok(path)
+    }
+}
+
+/// Upraví název souboru od odesílatele tak, aby ho šlo bezpečně uložit.
+///
+/// Zahodí cestu před posledním oddělovačem, úvodní tečky (skryté soubory,
+/// `..`), řídicí znaky a znaky, které některé systémy v názvech nepovolují.
+/// Příliš dlouhý název zkrátí, ale zachová jeho příponu.
+///
+/// # Arguments
+///
+/// * `name` - Název souboru od odesílatele
+///
+/// # Returns
+///
+/// Vrací neprázdný název bez oddělovačů adresářů.
+pub fn sanitize_filename(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let name = name
+        .trim()
+        .trim_start_matches('.')
+        .trim_end_matches(['.', ' ']);
+    if name.is_empty() {
+        return "file".to_string();
+    }
+    if name.len() <= MAX_FILENAME_LENGTH {
+        return name.to_string();
+    }
+
+    let (stem, extension) = split_extension(name);
+    let mut end = MAX_FILENAME_LENGTH.saturating_sub(extension.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], extension)
+}
+
+/// Vytvoří nový soubor v adresáři; pokud název už existuje, přidá k němu číslo.
+///
+/// Soubor se vytváří výhradně jako nový, takže se nikdy nepřepíše existující
+/// soubor ani se nenásleduje podstrčený symbolický odkaz.
+///
+/// # Arguments
+///
+/// * `dir` - Adresář, který se v případě potřeby vytvoří
+/// * `name` - Upravený název souboru, například `notes.txt`; další varianty jsou `notes (1).txt`, ...
+///
+/// # Returns
+///
+/// Vrací cestu a otevřený prázdný soubor.
+pub fn create_unique(dir: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+    create_dir_all(dir)?;
+    let (stem, extension) = split_extension(name);
+    for attempt in 0..MAX_NAME_ATTEMPTS {
+        let candidate = match attempt {
+            0 => name.to_string(),
+            n => format!("{} ({}){}", stem, n, extension),
+        };
+        let path = dir.join(candidate);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("no free name for {} in {}", name, dir.display()),
+    ))
+}
+
+/// Rozdělí název na základ a příponu včetně tečky; název bez přípony ji má prázdnou
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(index) if index > 0 && name.len() - index <= 16 => name.split_at(index),
+        _ => (name, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline_rejection_gives_a_reason() {
+        let mut downloads = DownloadOptions {
+            dir: PathBuf::from("."),
+            max_size: DEFAULT_MAX_DOWNLOAD_SIZE,
+            policy: IncomingPolicy::Accept,
+        };
+        assert_eq!(downloads.inline_rejection(), None);
+
+        // Inline files cannot wait for .accept, so prompting skips them with an explanation
+        downloads.policy = IncomingPolicy::Prompt;
+        assert!(downloads
+            .inline_rejection()
+            .is_some_and(|reason| reason.contains("--incoming-files accept")));
+        downloads.policy = IncomingPolicy::Reject;
+        assert_eq!(downloads.inline_rejection(), Some(REJECTED));
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("notes.txt"), "notes.txt");
+        assert_eq!(sanitize_filename("../../.bashrc"), "bashrc");
+        assert_eq!(sanitize_filename("..\\..\\boot.ini"), "boot.ini");
+        assert_eq!(sanitize_filename("/etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("a\nb:c?.txt"), "a_b_c_.txt");
+        assert_eq!(sanitize_filename(".."), "file");
+        assert_eq!(sanitize_filename("dir/"), "file");
+
+        let long = format!("{}.tar.gz", "é".repeat(200));
+        let sanitized = sanitize_filename(&long);
+        assert!(sanitized.len() <= MAX_FILENAME_LENGTH);
+        assert!(sanitized.ends_with("é.gz"));
+    }
+
+    #[test]
+    fn test_create_unique_never_overwrites() -> io::Result<()> {
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path().join("files");
+
+        let (first, _) = create_unique(&dir, "notes.txt")?;
+        std::fs::write(&first, b"first")?;
+        let (second, _) = create_unique(&dir, "notes.txt")?;
+        let (third, _) = create_unique(&dir, "README")?;
+        let (fourth, _) = create_unique(&dir, "README")?;
+
+        assert_eq!(first, dir.join("notes.txt"));
+        assert_eq!(second, dir.join("notes (1).txt"));
+        assert_eq!(third, dir.join("README"));
+        assert_eq!(fourth, dir.join("README (1)"));
+        assert_eq!(std::fs::read(&first)?, b"first");
+        Ok(())
+    }
+}
@@ -0,0 +1,403 @@
+use crate::output::say;
+use crate::transfer::connection_lost;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use shared::client_error::ClientError;
+use shared::{MessageType, SealedMessage};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{timeout, Duration};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Kontext odvození klíče zprávy, aby se společné tajemství nedalo použít jinde
+const KEY_INFO: &[u8] = b"chat direct message v1";
+
+/// Jak dlouho se čeká, než server pošle veřejný klíč příjemce
+const KEY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Výchozí soubor se soukromým klíčem uživatele, `chat/<uživatel>.key`
+/// v konfiguračním adresáři uživatele.
+///
+/// # Arguments
+///
+/// * `username` - Uživatelské jméno
+///
+/// # Errors
+///
+/// Vrací `ClientError::Encryption`, pokud systém konfigurační adresář nemá.
+pub fn default_key_path(username: &str) -> Result<PathBuf, ClientError> {
+    let config_dir = dirs::config_dir().ok_or_else(|| {
+        ClientError::Encryption("No configuration directory, use --key-file".to_string())
+    })?;
+    Ok(config_dir.join("chat").join(format!("{}.key", username)))
+}
+
+/// Výsledek porovnání klíče kontaktu s klíčem, který klient viděl dříve
+#[derive(Debug, PartialEq)]
+pub enum KeyStatus {
+    /// Klíč kontaktu klient vidí poprvé
+    New,
+    /// Klíč se shoduje s dříve uloženým
+    Known,
+    /// Kontakt má jiný klíč než dříve
+    Changed,
+}
+
+/// Vlastní klíč uživatele a klíče jeho kontaktů uložené na disku.
+///
+/// Klíč kontaktu se uloží, když ho klient uvidí poprvé; pozdější změna se
+/// ohlásí, protože může znamenat, že se za kontakt někdo vydává.
+pub struct KeyStore {
+    secret: StaticSecret,
+    public: [u8; 32],
+    contacts_path: PathBuf,
+    contacts: HashMap<String, [u8; 32]>,
+}
+
+impl KeyStore {
+    /// Načte klíče uživatele, případně vygeneruje nový klíč.
+    ///
+    /// Klíče kontaktů se ukládají vedle soukromého klíče do souboru s příponou `.contacts`.
+    /// Chybějící adresář pro klíč se vytvoří přístupný jen vlastníkovi.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_path` - Soubor se soukromým klíčem uživatele
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ClientError::Encryption`, pokud soubor s klíčem nebo kontakty není platný.
+    pub fn open(key_path: &Path) -> Result<Self, ClientError> {
+        let secret = if key_path.exists() {
+            StaticSecret::from(parse_key(&fs::read_to_string(key_path)?)?)
+        } else {
+            if let Some(parent) = key_path.parent().filter(|parent| !parent.exists()) {
+                let mut builder = fs::DirBuilder::new();
+                builder.recursive(true);
+                #[cfg(unix)]
+                std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+                builder.create(parent)?;
+            }
+            let secret = StaticSecret::random_from_rng(OsRng);
+            write_private(key_path, &format!("{}\n", hex::encode(secret.to_bytes())))?;
+            secret
+        };
+
+        let contacts_path = key_path.with_extension("contacts");
+        let mut contacts = HashMap::new();
+        if contacts_path.exists() {
+            for line in fs::read_to_string(&contacts_path)?.lines() {
+                if let Some((username, key)) = line.split_once(' ') {
+                    contacts.insert(username.to_string(), parse_key(key)?);
+                }
+            }
+        }
+
+        Ok(KeyStore {
+            public: PublicKey::from(&secret).to_bytes(),
+            secret,
+            contacts_path,
+            contacts,
+        })
+    }
+
+    /// Veřejný klíč uživatele, který klient zveřejní na serveru
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public
+    }
+
+    /// Porovná klíč kontaktu s dříve uloženým a uloží ho.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Uživatelské jméno kontaktu
+    /// * `key` - Veřejný klíč, který kontakt právě používá
+    pub fn remember(&mut self, username: &str, key: [u8; 32]) -> Result<KeyStatus, ClientError> {
+        let status = match self.contacts.insert(username.to_string(), key) {
+            None => KeyStatus::New,
+            Some(previous) if previous == key => return Ok(KeyStatus::Known),
+            Some(_) => KeyStatus::Changed,
+        };
+
+        let mut lines: Vec<String> = self
+            .contacts
+            .iter()
+            .map(|(username, key)| format!("{} {}\n", username, hex::encode(key)))
+            .collect();
+        lines.sort();
+        fs::write(&self.contacts_path, lines.concat())?;
+        Ok(status)
+    }
+
+    /// Zašifruje text pro příjemce.
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient_key` - Veřejný klíč příjemce
+    /// * `text` - Text zprávy
+    pub fn seal(&self, recipient_key: [u8; 32], text: &str) -> Result<SealedMessage, ClientError> {
+        let cipher = self.cipher(recipient_key, self.public, recipient_key)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, text.as_bytes())
+            .map_err(|_| ClientError::Encryption("Cannot encrypt message".to_string()))?;
+        Ok(SealedMessage {
+            sender_key: self.public,
+            recipient_key,
+            nonce: nonce.into(),
+            ciphertext,
+        })
+    }
+
+    /// Dešifruje zprávu určenou tomuto uživateli.
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ClientError::Encryption`, pokud zpráva byla zašifrována pro jiný
+    /// klíč nebo byla cestou pozměněna.
+    pub fn open_sealed(&self, sealed: &SealedMessage) -> Result<String, ClientError> {
+        if sealed.recipient_key != self.public {
+            return Err(ClientError::Encryption(
+                "Message was encrypted for a different key".to_string(),
+            ));
+        }
+        let cipher = self.cipher(sealed.sender_key, sealed.sender_key, sealed.recipient_key)?;
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&sealed.nonce),
+                sealed.ciphertext.as_slice(),
+            )
+            .map_err(|_| ClientError::Encryption("Message cannot be decrypted".to_string()))?;
+        String::from_utf8(plaintext)
+            .map_err(|_| ClientError::Encryption("Message is not valid text".to_string()))
+    }
+
+    /// Odvodí šifru ze společného tajemství X25519 a klíčů obou stran.
+    fn cipher(
+        &self,
+        peer_key: [u8; 32],
+        sender_key: [u8; 32],
+        recipient_key: [u8; 32],
+    ) -> Result<ChaCha20Poly1305, ClientError> {
+        let shared_secret = self.secret.diffie_hellman(&PublicKey::from(peer_key));
+        if !shared_secret.was_contributory() {
+            return Err(ClientError::Encryption("Invalid public key".to_string()));
+        }
+        let info = [KEY_INFO, &sender_key, &recipient_key].concat();
+        let mut key = Key::default();
+        Hkdf::<Sha256>::new(None, shared_secret.as_bytes())
+            .expand(&info, &mut key)
+            .map_err(|_| ClientError::Encryption("Cannot derive message key".to_string()))?;
+        Ok(ChaCha20Poly1305::new(&key))
+    }
+}
+
+/// Převede klíč v hexadecimálním zápisu na bajty
+fn parse_key(value: &str) -> Result<[u8; 32], ClientError> {
+    hex::decode(value.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| ClientError::Encryption("Invalid key".to_string()))
+}
+
+/// Zapíše soubor čitelný jen pro vlastníka
+fn write_private(path: &Path, contents: &str) -> Result<(), ClientError> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents.as_bytes())?;
+    Ok(())
+}
+
+/// Odesílání čekající na veřejný klíč příjemce, podle uživatelského jména
+type PendingKeys = Arc<Mutex<HashMap<String, Vec<oneshot::Sender<Option<[u8; 32]>>>>>>;
+
+/// Šifrování soukromých zpráv sdílené čtením zpráv a správou spojení
+#[derive(Clone)]
+pub struct Encryption {
+    store: Arc<std::sync::Mutex<KeyStore>>,
+    pending: PendingKeys,
+}
+
+impl Encryption {
+    pub fn new(store: KeyStore) -> Self {
+        Encryption {
+            store: Arc::new(std::sync::Mutex::new(store)),
+            pending: Arc::default(),
+        }
+    }
+
+    /// Veřejný klíč uživatele, který klient zveřejní na serveru
+    pub fn public_key(&self) -> [u8; 32] {
+        self.store.lock().unwrap().public_key()
+    }
+
+    /// Uloží klíč kontaktu a upozorní, pokud se od minula změnil.
+    fn check_contact(&self, username: &str, key: [u8; 32]) -> Result<(), ClientError> {
+        if self.store.lock().unwrap().remember(username, key)? == KeyStatus::Changed {
+            say!(
+                "Warning: the encryption key of {} has changed, verify with them that it was them who changed it",
+                username
+            );
+        }
+        Ok(())
+    }
+
+    /// Zašifruje soukromou zprávu klíčem příjemce a předá ji k odeslání.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - Kanál pro zprávy odesílané na server
+    /// * `recipient` - Uživatelské jméno příjemce
+    /// * `text` - Text zprávy
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ClientError::ConnectionError`, pokud se spojení během odesílání ztratí,
+    /// a `ClientError::Encryption`, pokud příjemce nezveřejnil svůj klíč.
+    pub async fn send_direct(
+        &self,
+        sender: &mpsc::Sender<MessageType>,
+        recipient: &str,
+        text: &str,
+    ) -> Result<(), ClientError> {
+        // Klíč se pokaždé ověřuje u serveru, aby se změna klíče příjemce projevila hned
+        let (key_sender, key_receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .await
+            .entry(recipient.to_string())
+            .or_default()
+            .push(key_sender);
+        sender
+            .send(MessageType::RequestKey(recipient.to_string()))
+            .await
+            .map_err(|_| connection_lost())?;
+
+        let key = match timeout(KEY_TIMEOUT, key_receiver).await {
+            Ok(Ok(key)) => key,
+            Ok(Err(_)) => return Err(connection_lost()),
+            Err(_) => {
+                return Err(ClientError::Other(format!(
+                    "Server did not send the encryption key of {}",
+                    recipient
+                )))
+            }
+        };
+        let key = key.ok_or_else(|| {
+            ClientError::Encryption(format!("{} has not published an encryption key", recipient))
+        })?;
+        self.check_contact(recipient, key)?;
+
+        let sealed = self.store.lock().unwrap().seal(key, text)?;
+        sender
+            .send(MessageType::EncryptedDirect {
+                recipient: recipient.to_string(),
+                sealed,
+            })
+            .await
+            .map_err(|_| connection_lost())
+    }
+
+    /// Dešifruje přijatou soukromou zprávu.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - Uživatelské jméno odesílatele doplněné serverem
+    /// * `sealed` - Zašifrovaná zpráva
+    pub fn receive_direct(
+        &self,
+        sender: &str,
+        sealed: &SealedMessage,
+    ) -> Result<String, ClientError> {
+        self.check_contact(sender, sealed.sender_key)?;
+        self.store.lock().unwrap().open_sealed(sealed)
+    }
+
+    /// Předá veřejný klíč od serveru odesíláním, která na něj čekají.
+    pub async fn key_received(&self, username: &str, key: Option<[u8; 32]>) {
+        if let Some(waiting) = self.pending.lock().await.remove(username) {
+            for key_sender in waiting {
+                let _ = key_sender.send(key);
+            }
+        }
+    }
+
+    /// Zruší čekání na klíče po ztrátě spojení; odesílání se to dozví jako ztrátu spojení.
+    pub async fn cancel_pending(&self) {
+        self.pending.lock().await.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() -> Result<(), ClientError> {
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path();
+        let alice = KeyStore::open(&dir.join("alice.key"))?;
+        let bob = KeyStore::open(&dir.join("bob.key"))?;
+        let mallory = KeyStore::open(&dir.join("mallory.key"))?;
+
+        let sealed = alice.seal(bob.public_key(), "hello bob")?;
+        assert!(!sealed
+            .ciphertext
+            .windows(9)
+            .any(|window| window == b"hello bob"));
+        assert_eq!(bob.open_sealed(&sealed)?, "hello bob");
+
+        // Nobody else can read it and tampering is detected
+        assert!(mallory.open_sealed(&sealed).is_err());
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(bob.open_sealed(&tampered).is_err());
+
+        // The key survives a restart
+        let bob_again = KeyStore::open(&dir.join("bob.key"))?;
+        assert_eq!(bob_again.public_key(), bob.public_key());
+        assert_eq!(bob_again.open_sealed(&sealed)?, "hello bob");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_open_creates_private_key_file() -> Result<(), ClientError> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path().join("chat");
+        let path = dir.join("alice.key");
+        KeyStore::open(&path)?;
+
+        // Only the owner can read the key or list the directory
+        assert_eq!(fs::metadata(&dir)?.permissions().mode() & 0o777, 0o700);
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remember_detects_key_change() -> Result<(), ClientError> {
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path();
+        let path = dir.join("alice.key");
+        let mut store = KeyStore::open(&path)?;
+
+        assert_eq!(store.remember("bob", [1; 32])?, KeyStatus::New);
+        assert_eq!(store.remember("bob", [1; 32])?, KeyStatus::Known);
+
+        // Contacts are persisted next to the key
+        let mut store = KeyStore::open(&path)?;
+        assert_eq!(store.remember("bob", [1; 32])?, KeyStatus::Known);
+        assert_eq!(store.remember("bob", [2; 32])?, KeyStatus::Changed);
+        assert_eq!(store.remember("bob", [2; 32])?, KeyStatus::Known);
+        Ok(())
+    }
+}
@@ -1,16 +1,38 @@
+mod connection;
+mod download;
+mod encryption;
+mod output;
+mod search;
+mod send;
+mod transfer;
+mod tui;
+
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use connection::{ConnectOptions, Connection, Outgoing, SharedSession};
+use download::{
+    sanitize_filename, DownloadOptions, IncomingPolicy, DEFAULT_MAX_DOWNLOAD_SIZE, REJECTED,
+};
+use encryption::{default_key_path, Encryption, KeyStore};
+use output::{say, Event, Format, Record};
+use search::parse_search;
+use send::SendArgs;
 use shared::client_error::ClientError;
-use shared::{deserialize_message, serialize_message, MessageType};
-use std::fs::{create_dir_all, File};
-use std::io::{self, Read, Write};
-use std::path::Path;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, Mutex};
+use shared::frame::{FrameReader, FrameWriter, DEFAULT_MAX_FRAME_SIZE};
+use shared::image::ImageFormat;
+use shared::protocol::{
+    deserialize_envelope, deserialize_handshake_response, serialize_envelope, serialize_handshake,
+    Envelope, Handshake, HandshakeResponse,
+};
+use shared::tls::{BoxedStream, ServerTrust, TlsClient};
+use shared::{HistoryEntry, MessageType, SearchQuery, SEARCH_PAGE_SIZE};
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use tokio::sync::mpsc;
 use tokio::task;
 use tokio::time::{timeout, Duration};
+use transfer::{accept_upload, IncomingTransfers, PendingUploads, Progress};
 
 /// Struktura pro uchování argumentů příkazového řádku
 #[derive(Parser, Debug)]
@@ -21,170 +43,972 @@ struct Args {
 
     #[arg(short, long, default_value = "11111")]
     port: u16,
+
+    /// Uživatelské jméno pro přihlášení
+    #[arg(short, long)]
+    username: String,
+
+    /// Heslo; pokud není zadáno, klient si o něj řekne při spuštění
+    #[arg(long, env = "CHAT_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// Místo přihlášení zaregistruje nový účet
+    #[arg(long)]
+    register: bool,
+
+    /// Maximální velikost jednoho rámce v bajtech
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+
+    /// Interval v sekundách, po kterém klient serveru pošle ping
+    #[arg(long, default_value = "30")]
+    heartbeat_interval: u64,
+
+    /// Počet sekund bez jediné zprávy od serveru, po kterém klient spojení považuje za ztracené
+    #[arg(long, default_value = "90")]
+    heartbeat_timeout: u64,
+
+    /// PEM soubor s certifikační autoritou, kterou musí být podepsaný certifikát serveru; zapne TLS
+    #[arg(long, conflicts_with = "tls_fingerprint")]
+    tls_ca: Option<PathBuf>,
+
+    /// SHA-256 otisk certifikátu serveru, který se přijme bez certifikační autority; zapne TLS
+    #[arg(long)]
+    tls_fingerprint: Option<String>,
+
+    /// Jméno serveru, pro které musí platit jeho certifikát; výchozí je hodnota `--ip`
+    #[arg(long)]
+    tls_server_name: Option<String>,
+
+    /// Soubor se soukromým klíčem pro šifrované soukromé zprávy; výchozí je
+    /// `chat/<uživatel>.key` v konfiguračním adresáři uživatele
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+
+    /// Nahradí na serveru klíč zveřejněný jiným zařízením klíčem tohoto klienta;
+    /// soukromé zprávy pak půjde číst jen zde
+    #[arg(long)]
+    replace_key: bool,
+
+    /// Adresář, do kterého se ukládají přijaté soubory a obrázky
+    #[arg(long, default_value = ".")]
+    download_dir: PathBuf,
+
+    /// Největší přijímaný soubor v bajtech; větší soubory se odmítnou
+    #[arg(long, default_value_t = DEFAULT_MAX_DOWNLOAD_SIZE)]
+    max_download_size: u64,
+
+    /// Co dělat se soubory, které klientovi někdo pošle
+    #[arg(long, value_enum, default_value_t = IncomingPolicy::Accept)]
+    incoming_files: IncomingPolicy,
+
+    /// Spustí celoobrazovkové rozhraní s posuvným oknem zpráv a seznamem místností
+    #[arg(long)]
+    tui: bool,
+
+    /// Formát výpisu na standardní výstup
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    output: Format,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-/// Funkce pro zpracování přijatých zpráv od serveru
+/// Příkazy klienta; bez příkazu se spustí interaktivní režim
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Odešle zprávu nebo soubor a skončí; bez `--text` a `--file` odešle
+    /// jako zprávu každý řádek standardního vstupu
+    Send(SendArgs),
+}
+
+/// Čtecí část spojení se serverem rozdělená na rámce
+type ServerReader = FrameReader<tokio::io::ReadHalf<BoxedStream>>;
+
+/// Zapisovací část spojení se serverem rozdělená na rámce
+type ServerWriter = FrameWriter<tokio::io::WriteHalf<BoxedStream>>;
+
+/// Přečte rámec, který musí od serveru dorazit, než bude spojení navázáno
 ///
 /// # Arguments
 ///
-/// * `reader` - Asynchronní čtecí část TCP spojení
-async fn handle_message(mut reader: tokio::io::ReadHalf<TcpStream>) -> Result<(), ClientError> {
-    loop {
-        let mut len_bytes = [0u8; 4];
-        if reader.read_exact(&mut len_bytes).await.is_err() {
-            println!("Connection closed by server");
-            break;
-        }
-        let len = u32::from_be_bytes(len_bytes) as usize;
+/// * `reader` - Čtecí část spojení se serverem
+async fn read_required_frame(reader: &mut ServerReader) -> Result<Vec<u8>, ClientError> {
+    reader.read_frame().await?.ok_or_else(|| {
+        ClientError::ConnectionError("Connection closed by server during handshake".to_string())
+    })
+}
 
-        let mut buffer = vec![0u8; len];
-        if reader.read_exact(&mut buffer).await.is_err() {
-            println!("Connection closed by server");
-            break;
+/// Dohodne se serverem verzi protokolu.
+///
+/// # Arguments
+///
+/// * `reader` - Čtecí část spojení se serverem
+/// * `writer` - Zapisovací část spojení se serverem
+///
+/// # Errors
+///
+/// Vrací `ClientError::IncompatibleProtocol`, pokud server spojení odmítl
+/// nebo na handshake neodpověděl platnou odpovědí.
+async fn negotiate_protocol(
+    reader: &mut ServerReader,
+    writer: &mut ServerWriter,
+) -> Result<u16, ClientError> {
+    writer
+        .write_frame(&serialize_handshake(&Handshake::new())?)
+        .await?;
+
+    let response = read_required_frame(reader).await?;
+    match deserialize_handshake_response(&response) {
+        Ok(HandshakeResponse::Accepted { version }) => Ok(version),
+        Ok(HandshakeResponse::Rejected { reason, .. }) => {
+            Err(ClientError::IncompatibleProtocol(reason))
         }
+        Err(_) => Err(ClientError::IncompatibleProtocol(
+            "server did not answer the protocol handshake".to_string(),
+        )),
+    }
+}
+
+/// Funkce pro zpracování přijatých zpráv od serveru
+///
+/// Na začátku požádá server o dokončení souborů, jejichž příjem se přerušil.
+/// Server posílá pingy, takže pokud po dobu `idle_timeout` nepřijde žádná zpráva,
+/// spojení se považuje za ztracené.
+///
+/// # Arguments
+///
+/// * `reader` - Čtecí část spojení se serverem
+/// * `sender` - Kanál pro zprávy odesílané na server
+/// * `uploads` - Odesílané soubory čekající na přijetí nabídky
+/// * `session` - Stav relace; zprávy z historie, které klient už viděl, se znovu nevypisují
+/// * `encryption` - Klíče pro dešifrování soukromých zpráv
+/// * `downloads` - Nastavení ukládání přijatých souborů
+/// * `idle_timeout` - Nejdelší doba bez zprávy od serveru
+///
+/// # Errors
+///
+/// Vrací `ClientError::ConnectionError`, pokud server po dobu `idle_timeout` mlčí.
+async fn handle_message(
+    mut reader: ServerReader,
+    sender: mpsc::Sender<MessageType>,
+    uploads: PendingUploads,
+    session: SharedSession,
+    encryption: Encryption,
+    downloads: DownloadOptions,
+    idle_timeout: Duration,
+) -> Result<(), ClientError> {
+    let mut transfers = IncomingTransfers::new(&downloads);
+    for (transfer_id, offset) in transfers.unfinished() {
+        request_file(&sender, transfer_id, offset).await?;
+    }
 
-        let message = match deserialize_message(&buffer) {
-            Ok(msg) => msg,
+    loop {
+        let frame = timeout(idle_timeout, reader.read_frame())
+            .await
+            .map_err(|_| {
+                ClientError::ConnectionError(format!(
+                    "No message from server for {} seconds",
+                    idle_timeout.as_secs()
+                ))
+            })?;
+        let buffer = match frame? {
+            Some(buffer) => buffer,
+            // Ztrátu spojení ohlásí jeho správa
+            None => break,
+        };
+
+        let envelope = match deserialize_envelope(&buffer) {
+            Ok(envelope) => envelope,
             Err(e) => {
-                println!("Error deserializing message: {:?}", e);
+                say!("Error deserializing message: {:?}", e);
                 continue;
             }
         };
+        let time = format_timestamp(envelope.timestamp, "%H:%M:%S");
+        if matches!(
+            envelope.payload,
+            MessageType::Text(_)
+                | MessageType::Image { .. }
+                | MessageType::File(_, _)
+                | MessageType::FileComplete { .. }
+                | MessageType::Attachment { .. }
+        ) {
+            session.lock().unwrap().observe(&envelope.room, envelope.id);
+        }
+        // Na odkaz na uložený soubor si klient data vyžádá sám
+        let reference = matches!(envelope.payload, MessageType::Attachment { .. });
 
-        match message {
-            MessageType::Text(text) => println!("Received: {}", text),
-            MessageType::Image(data) => {
-                println!("Receiving image...");
-
-                let now = chrono::Utc::now();
-                let timestamp_str = now.format("%Y-%m-%d %H:%M:%S").to_string();
+        match envelope.payload {
+            MessageType::Text(text) => {
+                let record = Record::Message {
+                    id: envelope.id,
+                    room: &envelope.room,
+                    sender: &envelope.sender,
+                    timestamp: envelope.timestamp,
+                    kind: "text",
+                    text: &text,
+                    filename: None,
+                    size: None,
+                    history: false,
+                };
+                if !output::json(&record) {
+                    say!("[{}] {}: {}", time, envelope.sender, text)
+                }
+            }
+            MessageType::Image { filename, data, .. } => {
+                // Soubor poslaný najednou nejde vyžádat později, bez souhlasu se zahodí
+                if let Some(reason) = downloads.inline_rejection() {
+                    report_skipped(
+                        &envelope.room,
+                        &envelope.sender,
+                        envelope.timestamp,
+                        &filename,
+                        reason,
+                    );
+                    continue;
+                }
+                // Ohlášenému typu se nevěří, obrázek se uloží podle skutečného obsahu
+                let Some(format) = ImageFormat::detect(&data) else {
+                    output::error(format!(
+                        "Cannot receive {}: not a PNG, JPEG, GIF or WebP image",
+                        filename
+                    ));
+                    continue;
+                };
+                say!(
+                    "[{}] Receiving image {} ({}) from {}...",
+                    time,
+                    filename,
+                    format.mime(),
+                    envelope.sender
+                );
 
-                create_dir_all("images")?;
-                let mut destination_file =
-                    File::create(Path::new(&format!("images/{}.png", timestamp_str)))?;
-                destination_file.write_all(&data)?;
+                let filename = format.file_name(&sanitize_filename(&filename));
+                match downloads.save("images", &filename, &data) {
+                    Ok(path) => {
+                        let record = Record::FileReceived {
+                            room: &envelope.room,
+                            sender: &envelope.sender,
+                            timestamp: envelope.timestamp,
+                            path: path.display().to_string(),
+                        };
+                        if !output::json(&record) {
+                            say!("Saved {}", path.display());
+                        }
+                    }
+                    Err(e) => output::error(format!("Cannot receive {}: {}", filename, e)),
+                }
             }
             MessageType::File(filename, data) => {
-                println!("Receiving {}", filename);
+                if let Some(reason) = downloads.inline_rejection() {
+                    report_skipped(
+                        &envelope.room,
+                        &envelope.sender,
+                        envelope.timestamp,
+                        &filename,
+                        reason,
+                    );
+                    continue;
+                }
+                say!("[{}] Receiving {} from {}", time, filename, envelope.sender);
 
-                create_dir_all("files")?;
-                let mut destination_file = File::create(Path::new(&format!("files/{}", filename)))?;
-                destination_file.write_all(&data)?;
+                match downloads.save("files", &filename, &data) {
+                    Ok(path) => {
+                        let record = Record::FileReceived {
+                            room: &envelope.room,
+                            sender: &envelope.sender,
+                            timestamp: envelope.timestamp,
+                            path: path.display().to_string(),
+                        };
+                        if !output::json(&record) {
+                            say!("Saved {}", path.display());
+                        }
+                    }
+                    Err(e) => output::error(format!("Cannot receive {}: {}", filename, e)),
+                }
+            }
+            MessageType::History(entries) => {
+                let entries: Vec<HistoryEntry> = {
+                    let mut session = session.lock().unwrap();
+                    entries
+                        .into_iter()
+                        .filter(|entry| session.observe(&envelope.room, entry.id))
+                        .collect()
+                };
+                if output::is_json() {
+                    for entry in &entries {
+                        output::json(&Record::history(entry));
+                    }
+                    continue;
+                }
+                if entries.is_empty() {
+                    continue;
+                }
+                say!(
+                    "--- History of {} ({} messages) ---",
+                    envelope.room,
+                    entries.len()
+                );
+                for entry in &entries {
+                    say!("{}", format_history_entry(entry));
+                }
+                say!("--- End of history ---");
+            }
+            MessageType::RoomJoined(room) => {
+                if !output::json(&Record::RoomJoined { room: &room }) {
+                    say!("Joined room {}", room);
+                }
+                output::show(Event::RoomJoined(room.clone()));
+                session.lock().unwrap().room = room;
+            }
+            MessageType::RoomList(rooms) => {
+                // Celoobrazovkové rozhraní zobrazuje místnosti v postranním panelu
+                if !output::show(Event::Rooms(rooms.clone()))
+                    && !output::json(&Record::Rooms { rooms: &rooms })
+                {
+                    say!("Rooms:");
+                    for room in &rooms {
+                        say!("  {} ({} online)", room.name, room.online);
+                    }
+                }
+            }
+            MessageType::Error(reason) => {
+                let record = Record::Error {
+                    message: reason.clone(),
+                };
+                if !output::json(&record) {
+                    say!("Error: {}", reason);
+                }
+                output::show(Event::ServerError(reason));
+            }
+            MessageType::UserJoined(username) => {
+                let record = Record::UserJoined {
+                    room: &envelope.room,
+                    user: &username,
+                    timestamp: envelope.timestamp,
+                };
+                if !output::json(&record) {
+                    say!("[{}] {} joined {}", time, username, envelope.room);
+                }
+                output::show(Event::PresenceChanged);
+            }
+            MessageType::UserLeft(username) => {
+                let record = Record::UserLeft {
+                    room: &envelope.room,
+                    user: &username,
+                    timestamp: envelope.timestamp,
+                };
+                if !output::json(&record) {
+                    say!("[{}] {} left {}", time, username, envelope.room);
+                }
+                output::show(Event::PresenceChanged);
+            }
+            MessageType::Ping => sender
+                .send(MessageType::Pong)
+                .await
+                .map_err(|e| ClientError::Other(e.to_string()))?,
+            MessageType::Pong => {}
+            MessageType::UserList(users) => {
+                if !output::show(Event::Users(users.clone()))
+                    && !output::json(&Record::Users { users: &users })
+                {
+                    say!("Online users:");
+                    for user in &users {
+                        say!("  {} ({})", user.username, user.room);
+                    }
+                }
+            }
+            MessageType::Direct { text, .. } => {
+                let record = Record::DirectMessage {
+                    sender: &envelope.sender,
+                    timestamp: envelope.timestamp,
+                    text: &text,
+                    encrypted: false,
+                };
+                if !output::json(&record) {
+                    say!("[{}] (private) {}: {}", time, envelope.sender, text)
+                }
+            }
+            MessageType::FileOffer {
+                transfer_id,
+                filename,
+                size,
+                image,
+                sha256,
+            }
+            | MessageType::Attachment {
+                transfer_id,
+                filename,
+                size,
+                image,
+                sha256,
+            } => {
+                let policy = match downloads.policy {
+                    IncomingPolicy::Prompt
+                        if session.lock().unwrap().is_file_accepted(transfer_id) =>
+                    {
+                        IncomingPolicy::Accept
+                    }
+                    policy => policy,
+                };
+                let result = match policy {
+                    IncomingPolicy::Accept => {
+                        transfers.offer(transfer_id, &filename, size, image, &sha256)
+                    }
+                    _ => downloads.check_size(&filename, size).map(|_| false),
+                };
+                match result {
+                    Ok(true) => {
+                        say!(
+                            "[{}] Receiving {} ({} bytes) from {}...",
+                            time,
+                            filename,
+                            size,
+                            envelope.sender
+                        );
+                        if reference {
+                            let offset = transfers.received(transfer_id).unwrap_or_default();
+                            request_file(&sender, transfer_id, offset).await?;
+                        }
+                    }
+                    Ok(false) if policy == IncomingPolicy::Prompt => {
+                        if session.lock().unwrap().offer_file(transfer_id) {
+                            let record = Record::FileOffered {
+                                room: &envelope.room,
+                                sender: &envelope.sender,
+                                transfer_id,
+                                filename: &filename,
+                                size,
+                            };
+                            if !output::json(&record) {
+                                say!(
+                                    "[{}] {} offers {} ({} bytes), type .accept {} or .reject {}",
+                                    time,
+                                    envelope.sender,
+                                    filename,
+                                    size,
+                                    transfer_id,
+                                    transfer_id
+                                );
+                            }
+                        }
+                    }
+                    Ok(false) if policy == IncomingPolicy::Reject => report_skipped(
+                        &envelope.room,
+                        &envelope.sender,
+                        envelope.timestamp,
+                        &filename,
+                        REJECTED,
+                    ),
+                    Ok(false) => {}
+                    Err(e) => output::error(format!("Cannot receive {}: {}", filename, e)),
+                }
+            }
+            MessageType::FileChunk {
+                transfer_id,
+                offset,
+                data,
+            } => match transfers.chunk(transfer_id, offset, &data) {
+                Ok(Progress::Missing(offset)) => request_file(&sender, transfer_id, offset).await?,
+                Ok(_) => {}
+                Err(e) => output::error(format!("Error receiving file: {}", e)),
+            },
+            MessageType::FileComplete { transfer_id } => {
+                match transfers.complete(transfer_id).await {
+                    Ok(Progress::Completed(path)) => {
+                        let record = Record::FileReceived {
+                            room: &envelope.room,
+                            sender: &envelope.sender,
+                            timestamp: envelope.timestamp,
+                            path: path.display().to_string(),
+                        };
+                        if !output::json(&record) {
+                            say!("Saved {}", path.display());
+                        }
+                    }
+                    Ok(Progress::Missing(offset)) => {
+                        request_file(&sender, transfer_id, offset).await?
+                    }
+                    Ok(Progress::Pending) => {}
+                    Err(e) => output::error(format!("Error receiving file: {}", e)),
+                }
+            }
+            MessageType::FileAccepted {
+                transfer_id,
+                offset,
+            } => accept_upload(&uploads, transfer_id, offset).await,
+            MessageType::FileAbort {
+                transfer_id,
+                reason,
+            } => {
+                if transfers.abort(transfer_id) {
+                    output::error(format!(
+                        "Transfer from {} was aborted: {}",
+                        envelope.sender, reason
+                    ));
+                }
+            }
+            MessageType::EncryptedDirect { sealed, .. } => {
+                match encryption.receive_direct(&envelope.sender, &sealed) {
+                    Ok(text) => {
+                        let record = Record::DirectMessage {
+                            sender: &envelope.sender,
+                            timestamp: envelope.timestamp,
+                            text: &text,
+                            encrypted: true,
+                        };
+                        if !output::json(&record) {
+                            say!(
+                                "[{}] (private, encrypted) {}: {}",
+                                time,
+                                envelope.sender,
+                                text
+                            )
+                        }
+                    }
+                    Err(e) => output::error(format!(
+                        "[{}] Cannot read private message from {}: {}",
+                        time, envelope.sender, e
+                    )),
+                }
+            }
+            MessageType::SearchResults {
+                page,
+                total,
+                entries,
+            } => {
+                let record = Record::SearchResults {
+                    page,
+                    total,
+                    entries: &entries,
+                };
+                if !output::json(&record) {
+                    print_search_results(page, total, &entries)
+                }
+            }
+            MessageType::PublicKey { username, key } => {
+                encryption.key_received(&username, key).await
+            }
+            MessageType::RoomActivity { room, sender } => {
+                // Upozornění zobrazuje celoobrazovkové rozhraní a výpis ve formátu JSON,
+                // řádkový výpis by zahltilo
+                output::json(&Record::RoomActivity {
+                    room: &room,
+                    sender: &sender,
+                });
+                output::show(Event::Activity { room, sender });
+            }
+            MessageType::JoinRoom(_)
+            | MessageType::LeaveRoom
+            | MessageType::ListRooms
+            | MessageType::ListUsers
+            | MessageType::FetchHistory { .. }
+            | MessageType::FileRequest { .. }
+            | MessageType::PublishKey { .. }
+            | MessageType::RequestKey(_)
+            | MessageType::Search(_) => {
+                say!("Ignoring unexpected request from server");
             }
         }
     }
     Ok(())
 }
 
-/// Funkce pro odesílání zpráv na server
+/// Požádá server o zaslání souboru od zadané pozice
 ///
 /// # Arguments
 ///
-/// * `writer` - Asynchronní zapisovací část TCP spojení
-/// * `message` - Typ zprávy k odeslání
-async fn send_message(
-    writer: Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
-    message: &MessageType,
+/// * `sender` - Kanál pro zprávy odesílané na server
+/// * `transfer_id` - ID přenosu
+/// * `offset` - Pozice, od které klientovi chybí data
+async fn request_file(
+    sender: &mpsc::Sender<MessageType>,
+    transfer_id: u64,
+    offset: u64,
 ) -> Result<(), ClientError> {
-    let stream_lock = timeout(Duration::from_secs(5), writer.lock()).await;
-    match stream_lock {
-        Ok(mut writer) => {
-            let serialized = serialize_message(message).map_err(ClientError::from)?;
+    sender
+        .send(MessageType::FileRequest {
+            transfer_id,
+            offset,
+        })
+        .await
+        .map_err(|e| ClientError::Other(e.to_string()))
+}
 
-            let len = serialized.len() as u32;
-            writer.write(&len.to_be_bytes()).await?;
-            writer.write_all(&serialized).await?;
-        }
-        Err(_) => {
-            println!("Timeout while waiting for lock.");
-            return Err(ClientError::Other(
-                "Timeout while waiting for lock".to_string(),
-            ));
-        }
+/// Ohlásí soubor, který klient podle `--incoming-files` zahodil, i s důvodem
+///
+/// # Arguments
+///
+/// * `room` - Místnost, do které byl soubor poslán
+/// * `sender` - Odesílatel souboru
+/// * `timestamp` - Čas odeslání souboru
+/// * `filename` - Název souboru od odesílatele
+/// * `reason` - Proč se soubor zahodil
+fn report_skipped(room: &str, sender: &str, timestamp: i64, filename: &str, reason: &str) {
+    let record = Record::FileSkipped {
+        room,
+        sender,
+        timestamp,
+        filename,
+        reason,
+    };
+    if !output::json(&record) {
+        let time = format_timestamp(timestamp, "%H:%M:%S");
+        say!(
+            "[{}] Skipped {} from {}: {}",
+            time,
+            filename,
+            sender,
+            reason
+        );
     }
+}
 
-    Ok(())
+/// Převede unixový čas na text v místním časovém pásmu
+///
+/// # Arguments
+///
+/// * `timestamp` - Počet sekund od počátku unixové epochy
+/// * `format` - Formát výstupu ve stylu `strftime`
+fn format_timestamp(timestamp: i64, format: &str) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format(format)
+                .to_string()
+        })
+        .unwrap_or_else(|| "?".to_string())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), ClientError> {
-    let args = Args::parse();
-    let address = format!("{}:{}", args.ip, args.port);
+/// Převede uloženou zprávu z historie na řádek pro výpis
+///
+/// # Arguments
+///
+/// * `entry` - Zpráva z historie
+fn format_history_entry(entry: &HistoryEntry) -> String {
+    let time = format_timestamp(entry.timestamp, "%Y-%m-%d %H:%M:%S");
+    format!(
+        "[history {}] {}: {}",
+        time,
+        entry.sender,
+        entry_content(entry)
+    )
+}
 
-    println!("Arguments: {:?}", args);
+/// Popíše obsah uložené zprávy; u souborů a obrázků jen jejich metadata
+fn entry_content(entry: &HistoryEntry) -> String {
+    let size = entry.size.unwrap_or_default();
+    match entry.kind.as_str() {
+        "image" => match &entry.filename {
+            Some(filename) => format!("sent image {} ({} bytes)", filename, size),
+            None => format!("sent an image ({} bytes)", size),
+        },
+        "file" => format!(
+            "sent file {} ({} bytes)",
+            entry.filename.as_deref().unwrap_or("?"),
+            size
+        ),
+        _ => entry.content.clone(),
+    }
+}
 
-    let stream = TcpStream::connect(&address).await?;
-    let (mut reader, writer) = tokio::io::split(stream);
+/// Vypíše stránku výsledků hledání
+///
+/// # Arguments
+///
+/// * `page` - Číslo stránky od 1
+/// * `total` - Celkový počet nalezených zpráv
+/// * `entries` - Zprávy na této stránce
+fn print_search_results(page: u32, total: u32, entries: &[HistoryEntry]) {
+    if entries.is_empty() {
+        say!(
+            "{}",
+            if page > 1 {
+                "No more results"
+            } else {
+                "No messages found"
+            }
+        );
+        return;
+    }
 
-    let writer = Arc::new(Mutex::new(writer));
+    let pages = total.div_ceil(SEARCH_PAGE_SIZE);
+    say!(
+        "--- Search results, page {} of {} ({} messages) ---",
+        page,
+        pages,
+        total
+    );
+    for entry in entries {
+        let time = format_timestamp(entry.timestamp, "%Y-%m-%d %H:%M:%S");
+        say!(
+            "[{} #{}] {}: {}",
+            time,
+            entry.room,
+            entry.sender,
+            entry_content(entry)
+        );
+    }
+    if page < pages {
+        say!("--- Type .more for the next page ---");
+    } else {
+        say!("--- End of results ---");
+    }
+}
+
+/// Funkce pro odesílání zpráv na server
+///
+/// # Arguments
+///
+/// * `writer` - Zapisovací část spojení se serverem
+/// * `envelope` - Obálka se zprávou k odeslání
+async fn send_message(writer: &mut ServerWriter, envelope: &Envelope) -> Result<(), ClientError> {
+    let serialized = serialize_envelope(envelope).map_err(ClientError::from)?;
+    writer.write_frame(&serialized).await?;
+    Ok(())
+}
 
-    // Poslání autentizačního tokenu
-    {
-        let mut writer = writer.lock().await;
-        let token = b"SECRET_TOKEN";
-        let len = token.len() as u32;
-        writer.write(&len.to_be_bytes()).await?;
-        writer.write_all(token).await?;
+/// Výsledek zpracování řádku, který zadal uživatel
+#[derive(Debug, PartialEq)]
+enum Input {
+    /// Požadavek k odeslání na server
+    Send(Outgoing),
+    /// Uživatel chce klienta ukončit
+    Quit,
+    /// Řádek nic neodesílá, například kvůli chybnému použití příkazu
+    Skip,
+}
+
+/// Převede řádek zadaný uživatelem na zprávu nebo příkaz.
+///
+/// # Arguments
+///
+/// * `line` - Zadaný řádek
+/// * `last_search` - Poslední hledání, jehož další stránku vrátí příkaz `.more`
+fn parse_input(line: &str, last_search: &mut Option<SearchQuery>) -> Input {
+    let message_str = line.trim().to_string();
+    // Příkaz je celé první slovo, `.joinfoo` tedy není příkaz `.join`
+    let (command, arguments) = match message_str.split_once(char::is_whitespace) {
+        Some((command, arguments)) => (command, arguments.trim()),
+        None => (message_str.as_str(), ""),
+    };
+    let message = match command {
+        ".file" | ".image" => {
+            let image = command == ".image";
+            let filename = arguments.to_string();
+            // Obrázek se pozná podle obsahu, přípona souboru nerozhoduje
+            if image {
+                match ImageFormat::detect_file(Path::new(&filename)) {
+                    Ok(Some(_)) => {}
+                    Ok(None) => {
+                        say!("Only PNG, JPEG, GIF and WebP images are supported.");
+                        return Input::Skip;
+                    }
+                    Err(e) => {
+                        say!("Cannot read {}: {}", filename, e);
+                        return Input::Skip;
+                    }
+                }
+            }
 
-        let mut response_len_bytes = [0u8; 4];
-        reader.read_exact(&mut response_len_bytes).await?;
-        let response_len = u32::from_be_bytes(response_len_bytes) as usize;
-        let mut response = vec![0u8; response_len];
-        reader.read_exact(&mut response).await?;
-        let response_str = String::from_utf8_lossy(&response);
-        println!("Server response: {}", response_str);
+            Outgoing::Upload {
+                path: PathBuf::from(filename),
+                image,
+            }
+        }
+        ".join" => {
+            if arguments.is_empty() {
+                say!("Usage: .join <room>");
+                return Input::Skip;
+            }
+            Outgoing::Message(MessageType::JoinRoom(arguments.to_string()))
+        }
+        ".msg" => match arguments.split_once(char::is_whitespace) {
+            Some((recipient, text)) if !text.trim().is_empty() => Outgoing::Direct {
+                recipient: recipient.to_string(),
+                text: text.trim().to_string(),
+            },
+            _ => {
+                say!("Usage: .msg <user> <text>");
+                return Input::Skip;
+            }
+        },
+        ".accept" | ".reject" => match arguments.parse() {
+            Ok(transfer_id) if command == ".accept" => Outgoing::AcceptFile(transfer_id),
+            Ok(transfer_id) => Outgoing::RejectFile(transfer_id),
+            Err(_) => {
+                say!("Usage: .accept <id> or .reject <id>");
+                return Input::Skip;
+            }
+        },
+        ".leave" => Outgoing::Message(MessageType::LeaveRoom),
+        ".rooms" => Outgoing::Message(MessageType::ListRooms),
+        ".who" => Outgoing::Message(MessageType::ListUsers),
+        ".search" => match parse_search(arguments) {
+            Ok(query) => {
+                *last_search = Some(query.clone());
+                Outgoing::Message(MessageType::Search(query))
+            }
+            Err(e) => {
+                say!("{}", e);
+                return Input::Skip;
+            }
+        },
+        ".more" => match last_search.as_mut() {
+            Some(query) => {
+                query.page += 1;
+                Outgoing::Message(MessageType::Search(query.clone()))
+            }
+            None => {
+                say!("Nothing to continue, use .search first");
+                return Input::Skip;
+            }
+        },
+        ".quit" => return Input::Quit,
+        _ => Outgoing::Message(MessageType::Text(message_str)),
+    };
+    Input::Send(message)
+}
 
-        if !response_str.contains("Authentication Successful") {
-            println!("Authentication failed.");
-            return Ok(());
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.exit_code())
         }
     }
+}
 
-    let (sender, mut receiver) = mpsc::channel::<MessageType>(32);
+/// Spustí klienta podle argumentů příkazového řádku
+///
+/// # Errors
+///
+/// Vrací chybu, kvůli které klient skončil; určuje návratový kód procesu.
+async fn run(args: Args) -> Result<(), ClientError> {
+    let address = format!("{}:{}", args.ip, args.port);
+    if args.tui && args.command.is_some() {
+        return Err(ClientError::InvalidInput(
+            "--tui cannot be combined with a command".to_string(),
+        ));
+    }
+    if args.tui && args.output == Format::Json {
+        return Err(ClientError::InvalidInput(
+            "--tui cannot be combined with --output json".to_string(),
+        ));
+    }
+    if args.tui && !io::stdout().is_terminal() {
+        return Err(ClientError::InvalidInput(
+            "--tui needs an interactive terminal".to_string(),
+        ));
+    }
 
-    let writer_clone = Arc::clone(&writer);
-    task::spawn(async move {
-        while let Some(message) = receiver.recv().await {
-            if let Err(e) = send_message(writer_clone.clone(), &message).await {
-                println!("Error sending message: {:?}", e);
-            }
+    output::set_format(args.output);
+    let send = args.command.map(|Command::Send(send)| send);
+    // Příkaz `send` ve skriptech nevypisuje nic kromě chyb, celoobrazovkové
+    // rozhraní zobrazuje výstup ve svém okně
+    let events = if send.is_some() || args.tui {
+        output::redirect()
+    } else {
+        None
+    };
+
+    say!(
+        "Connecting to {} as {}{}",
+        address,
+        args.username,
+        if args.register { " (registering)" } else { "" }
+    );
+
+    let key_file = match args.key_file.clone() {
+        Some(key_file) => key_file,
+        None => default_key_path(&args.username)?,
+    };
+    let encryption = Encryption::new(KeyStore::open(&key_file)?);
+
+    let password = match args.password {
+        Some(password) => password,
+        None => rpassword::prompt_password("Password: ")?,
+    };
+    let trust = match (args.tls_ca, args.tls_fingerprint) {
+        (Some(path), _) => Some(ServerTrust::CaFile(path)),
+        (None, Some(fingerprint)) => Some(ServerTrust::Fingerprint(fingerprint)),
+        (None, None) => None,
+    };
+    let tls = match trust {
+        Some(trust) => {
+            let server_name = args.tls_server_name.as_deref().unwrap_or(&args.ip);
+            Some(TlsClient::new(&trust, server_name)?)
         }
-    });
+        None => None,
+    };
 
-    task::spawn(async move {
-        if let Err(e) = handle_message(reader).await {
-            println!("Error handling message: {:?}", e);
+    let options = ConnectOptions {
+        address,
+        tls,
+        username: args.username,
+        password,
+        max_frame_size: args.max_frame_size,
+        heartbeat_interval: Duration::from_secs(args.heartbeat_interval.max(1)),
+        heartbeat_timeout: Duration::from_secs(args.heartbeat_timeout.max(1)),
+        replace_key: args.replace_key,
+    };
+
+    // Dohodnutí verze protokolu a přihlášení nebo registrace uživatele
+    let connected = connection::connect(&options, args.register).await?;
+
+    let (sender, receiver) = mpsc::channel::<Outgoing>(32);
+    let downloads = DownloadOptions {
+        dir: args.download_dir,
+        max_size: args.max_download_size,
+        policy: args.incoming_files,
+    };
+    let connection = Connection::new(options, encryption, downloads, &sender);
+    match (send, events) {
+        (Some(send), Some(events)) => {
+            send::queue_requests(&send, sender)?;
+            connection
+                .deliver(connected, &send.room, receiver, events)
+                .await
+        }
+        (_, events) => {
+            // Po prvním přihlášení se spojení při výpadku obnovuje na pozadí
+            let connection = task::spawn(connection.run(connected, receiver));
+            let result = match events {
+                Some(events) => tui::run(sender, events).await,
+                None => read_commands(sender).await,
+            };
+            // Zánik kanálu s požadavky správu spojení ukončí, až odešle, co v něm zbylo
+            let _ = connection.await;
+            result
         }
-    });
+    }
+}
 
+/// Čte příkazy a zprávy uživatele ze standardního vstupu, dokud klienta neukončí.
+///
+/// # Arguments
+///
+/// * `sender` - Kanál pro požadavky odesílané na server
+async fn read_commands(sender: mpsc::Sender<Outgoing>) -> Result<(), ClientError> {
+    let mut last_search: Option<SearchQuery> = None;
     loop {
         let mut user_input = String::new();
-        io::stdin().read_line(&mut user_input)?;
-
-        let message_str = user_input.trim().to_string();
-        let message = if message_str.starts_with(".file") {
-            let filename = message_str.trim_start_matches(".file").trim().to_string();
-            let mut source_file = File::open(Path::new(&filename))?;
-            let mut buffer = Vec::new();
-            source_file.read_to_end(&mut buffer)?;
-            MessageType::File(filename, buffer)
-        } else if message_str.starts_with(".image") {
-            let filename = message_str.trim_start_matches(".image").trim().to_string();
-            if !filename.ends_with(".png") {
-                println!("Only PNG images are supported.");
-                continue;
-            }
-            let mut source_file = File::open(Path::new(&filename))?;
-            let mut buffer = Vec::new();
-            source_file.read_to_end(&mut buffer)?;
-            MessageType::Image(buffer)
-        } else if message_str.starts_with(".quit") {
-            println!("Quitting...");
+        // Konec vstupu, například u přesměrovaného souboru, klienta ukončí
+        if io::stdin().read_line(&mut user_input)? == 0 {
             break;
-        } else {
-            MessageType::Text(message_str)
-        };
+        }
 
+        let message = match parse_input(&user_input, &mut last_search) {
+            Input::Send(message) => message,
+            Input::Quit => {
+                say!("Quitting...");
+                break;
+            }
+            Input::Skip => continue,
+        };
         sender
             .send(message)
             .await
@@ -197,19 +1021,64 @@ async fn main() -> Result<(), ClientError> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use shared::DEFAULT_ROOM;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpListener;
     use tokio::net::TcpStream;
 
+    /// Creates encryption keys stored in `dir`
+    fn test_encryption(dir: &Path) -> Encryption {
+        Encryption::new(KeyStore::open(&dir.join("test.key")).unwrap())
+    }
+
+    /// Download options that accept files into `dir`
+    fn test_downloads(dir: &Path) -> DownloadOptions {
+        DownloadOptions {
+            dir: dir.to_path_buf(),
+            max_size: DEFAULT_MAX_DOWNLOAD_SIZE,
+            policy: IncomingPolicy::Accept,
+        }
+    }
+
+    #[test]
+    fn test_parse_input_matches_whole_command() {
+        let mut last_search = None;
+        let mut parse = |line: &str| parse_input(line, &mut last_search);
+
+        assert_eq!(
+            parse(".join  dev "),
+            Input::Send(Outgoing::Message(MessageType::JoinRoom("dev".to_string())))
+        );
+        assert_eq!(
+            parse(".msg bob  hi there"),
+            Input::Send(Outgoing::Direct {
+                recipient: "bob".to_string(),
+                text: "hi there".to_string(),
+            })
+        );
+        assert_eq!(parse(".accept 7"), Input::Send(Outgoing::AcceptFile(7)));
+        assert_eq!(parse(".quit"), Input::Quit);
+        assert_eq!(parse(".join"), Input::Skip);
+
+        // A longer word that merely starts with a command is plain text
+        for line in [".joinfoo", ".msgx bob hi", ".quitting now", ".whoami"] {
+            assert_eq!(
+                parse(line),
+                Input::Send(Outgoing::Message(MessageType::Text(line.to_string())))
+            );
+        }
+    }
+
     #[tokio::test]
     async fn test_handle_message() -> Result<(), ClientError> {
+        let temp = tempfile::tempdir()?;
         let listener = TcpListener::bind("127.0.0.1:0").await?;
         let addr = listener.local_addr()?;
 
         let server_task = tokio::spawn(async move {
             let (mut server_socket, _) = listener.accept().await.unwrap();
-            let message = MessageType::Text("Hello".to_string());
-            let serialized = serialize_message(&message)
+            let message = Envelope::new(MessageType::Text("Hello".to_string()), DEFAULT_ROOM);
+            let serialized = serialize_envelope(&message)
                 .map_err(ClientError::from)
                 .unwrap();
             let len = serialized.len() as u32;
@@ -218,14 +1087,62 @@ mod tests {
         });
 
         let client_socket = TcpStream::connect(addr).await?;
-        let (reader, _writer) = tokio::io::split(client_socket);
+        let (reader, _writer) = tokio::io::split(Box::new(client_socket) as BoxedStream);
 
-        handle_message(reader).await?;
+        let (sender, _receiver) = mpsc::channel(1);
+        handle_message(
+            FrameReader::new(reader),
+            sender,
+            PendingUploads::default(),
+            connection::Session::shared(),
+            test_encryption(temp.path()),
+            test_downloads(temp.path()),
+            Duration::from_secs(5),
+        )
+        .await?;
 
         server_task.await.unwrap();
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_handle_message_heartbeat() -> Result<(), ClientError> {
+        let temp = tempfile::tempdir()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server_task = tokio::spawn(async move {
+            let (server_socket, _) = listener.accept().await.unwrap();
+            let mut writer = FrameWriter::new(server_socket);
+            let ping = Envelope::new(MessageType::Ping, "");
+            writer
+                .write_frame(&serialize_envelope(&ping).unwrap())
+                .await
+                .unwrap();
+            // Keep the connection open but silent until the client gives up
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let client_socket = TcpStream::connect(addr).await?;
+        let (reader, _writer) = tokio::io::split(Box::new(client_socket) as BoxedStream);
+        let (sender, mut receiver) = mpsc::channel(1);
+        let result = handle_message(
+            FrameReader::new(reader),
+            sender,
+            PendingUploads::default(),
+            connection::Session::shared(),
+            test_encryption(temp.path()),
+            test_downloads(temp.path()),
+            Duration::from_millis(200),
+        )
+        .await;
+
+        assert_eq!(receiver.recv().await, Some(MessageType::Pong));
+        assert!(matches!(result, Err(ClientError::ConnectionError(_))));
+        server_task.abort();
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_send_message() -> Result<(), ClientError> {
         let listener = TcpListener::bind("127.0.0.1:0").await?;
@@ -240,19 +1157,80 @@ mod tests {
             let mut buffer = vec![0u8; len];
             server_socket.read_exact(&mut buffer).await.unwrap();
 
-            let received_message = deserialize_message(&buffer)
+            let received_message = deserialize_envelope(&buffer)
                 .map_err(ClientError::from)
                 .unwrap();
             let expected_message = MessageType::Text("Hello, World!".to_string());
-            assert_eq!(expected_message, received_message);
+            assert_eq!(expected_message, received_message.payload);
         });
 
         let client_socket = TcpStream::connect(addr).await?;
-        let (_reader, writer) = tokio::io::split(client_socket);
-        let writer = Arc::new(Mutex::new(writer));
+        let (_reader, writer) = tokio::io::split(Box::new(client_socket) as BoxedStream);
+        let mut writer = FrameWriter::new(writer);
+
+        let message = Envelope::new(MessageType::Text("Hello, World!".to_string()), DEFAULT_ROOM);
+        send_message(&mut writer, &message).await?;
+
+        server_task.await.unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_args() {
+        use clap::CommandFactory;
+        Args::command().debug_assert();
+
+        let args = Args::parse_from(["client", "-u", "ci", "send", "--text", "done"]);
+        assert!(matches!(
+            args.command,
+            Some(Command::Send(SendArgs { text: Some(text), .. })) if text == "done"
+        ));
+    }
 
-        let message = MessageType::Text("Hello, World!".to_string());
-        send_message(writer, &message).await?;
+    #[test]
+    fn test_format_history_entry() {
+        let entry = HistoryEntry {
+            id: 0,
+            sender: "alice".to_string(),
+            room: "general".to_string(),
+            timestamp: 0,
+            kind: "file".to_string(),
+            content: String::new(),
+            filename: Some("notes.txt".to_string()),
+            size: Some(42),
+        };
+
+        let line = format_history_entry(&entry);
+        assert!(line.starts_with("[history "));
+        assert!(line.ends_with("alice: sent file notes.txt (42 bytes)"));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_protocol_rejected() -> Result<(), ClientError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server_task = tokio::spawn(async move {
+            let (server_socket, _) = listener.accept().await.unwrap();
+            let (reader, writer) = tokio::io::split(server_socket);
+            let (mut reader, mut writer) = (FrameReader::new(reader), FrameWriter::new(writer));
+            reader.read_frame().await.unwrap();
+            let response = HandshakeResponse::Rejected {
+                version: 99,
+                min_version: 99,
+                reason: "too old".to_string(),
+            };
+            let serialized = shared::protocol::serialize_handshake_response(&response).unwrap();
+            writer.write_frame(&serialized).await.unwrap();
+        });
+
+        let client_socket = TcpStream::connect(addr).await?;
+        let (reader, writer) = tokio::io::split(Box::new(client_socket) as BoxedStream);
+        let (mut reader, mut writer) = (FrameReader::new(reader), FrameWriter::new(writer));
+        let result = negotiate_protocol(&mut reader, &mut writer).await;
+        assert!(
+            matches!(result, Err(ClientError::IncompatibleProtocol(reason)) if reason == "too old")
+        );
 
         server_task.await.unwrap();
         Ok(())
@@ -0,0 +1,265 @@
+use shared::{HistoryEntry, OnlineUser, RoomInfo};
+use std::sync::OnceLock;
+use tokio::sync::mpsc;
+
+/// Událost, kterou klient předává místo výpisu na standardní výstup
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Řádek textu, který by se jinak vypsal
+    Line(String),
+    /// Uživatel vstoupil do místnosti
+    RoomJoined(String),
+    /// Seznam místností se počty připojených uživatelů
+    Rooms(Vec<RoomInfo>),
+    /// Seznam připojených uživatelů
+    Users(Vec<OnlineUser>),
+    /// Někdo vstoupil do aktuální místnosti nebo ji opustil
+    PresenceChanged,
+    /// Nová zpráva v jiné místnosti, kterou uživatel navštívil
+    Activity { room: String, sender: String },
+    /// Server odmítl požadavek klienta
+    ServerError(String),
+}
+
+/// Formát, ve kterém klient vypisuje na standardní výstup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Řádky textu pro člověka
+    #[default]
+    Text,
+    /// Jeden objekt JSON na řádek pro další zpracování
+    Json,
+}
+
+/// Záznam o události vypisovaný ve formátu JSON; druh události je v poli `event`
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Record<'a> {
+    /// Zpráva v místnosti, přijatá živě nebo v historii
+    Message {
+        id: i64,
+        room: &'a str,
+        sender: &'a str,
+        timestamp: i64,
+        /// Druh zprávy (`text`, `image` nebo `file`)
+        kind: &'a str,
+        text: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        filename: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<i64>,
+        /// Zda zpráva přišla v historii místnosti
+        history: bool,
+    },
+    /// Soukromá zpráva
+    DirectMessage {
+        sender: &'a str,
+        timestamp: i64,
+        text: &'a str,
+        encrypted: bool,
+    },
+    /// Přijatý a uložený soubor nebo obrázek
+    FileReceived {
+        room: &'a str,
+        sender: &'a str,
+        timestamp: i64,
+        path: String,
+    },
+    /// Nabídnutý soubor, o kterém rozhodne uživatel příkazem `.accept` nebo `.reject`
+    FileOffered {
+        room: &'a str,
+        sender: &'a str,
+        transfer_id: u64,
+        filename: &'a str,
+        size: u64,
+    },
+    /// Soubor, který klient podle `--incoming-files` zahodil
+    FileSkipped {
+        room: &'a str,
+        sender: &'a str,
+        timestamp: i64,
+        filename: &'a str,
+        reason: &'a str,
+    },
+    UserJoined {
+        room: &'a str,
+        user: &'a str,
+        timestamp: i64,
+    },
+    UserLeft {
+        room: &'a str,
+        user: &'a str,
+        timestamp: i64,
+    },
+    /// Uživatel vstoupil do místnosti
+    RoomJoined {
+        room: &'a str,
+    },
+    Rooms {
+        rooms: &'a [RoomInfo],
+    },
+    Users {
+        users: &'a [OnlineUser],
+    },
+    SearchResults {
+        page: u32,
+        total: u32,
+        entries: &'a [HistoryEntry],
+    },
+    /// Nová zpráva v jiné místnosti, kterou uživatel navštívil
+    RoomActivity {
+        room: &'a str,
+        sender: &'a str,
+    },
+    /// Chyba hlášená serverem nebo chyba při zpracování přijaté zprávy
+    Error {
+        message: String,
+    },
+    /// Ostatní výpis klienta, například stav spojení
+    Info {
+        text: &'a str,
+    },
+}
+
+impl<'a> Record<'a> {
+    /// Vytvoří záznam o zprávě z historie místnosti
+    pub fn history(entry: &'a HistoryEntry) -> Self {
+        Record::Message {
+            id: entry.id,
+            room: &entry.room,
+            sender: &entry.sender,
+            timestamp: entry.timestamp,
+            kind: &entry.kind,
+            text: &entry.content,
+            filename: entry.filename.as_deref(),
+            size: entry.size,
+            history: true,
+        }
+    }
+}
+
+/// Formát výstupu zvolený při spuštění; výchozí je text
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+/// Kanál, kterým se předávají události; dokud není nastaven, vypisuje se na standardní výstup
+static EVENTS: OnceLock<mpsc::UnboundedSender<Event>> = OnceLock::new();
+
+/// Přesměruje veškerý výstup klienta do kanálu, například pro celoobrazovkové rozhraní.
+///
+/// # Returns
+///
+/// Vrací přijímací stranu kanálu s událostmi, nebo `None`, pokud už výstup přesměrován je.
+pub fn redirect() -> Option<mpsc::UnboundedReceiver<Event>> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    EVENTS.set(sender).ok().map(|_| receiver)
+}
+
+/// Nastaví formát výstupu; lze jen jednou, před prvním výpisem
+pub fn set_format(format: Format) {
+    let _ = FORMAT.set(format);
+}
+
+/// Vrací `true`, pokud se na standardní výstup vypisují záznamy ve formátu JSON
+pub fn is_json() -> bool {
+    FORMAT.get() == Some(&Format::Json) && EVENTS.get().is_none()
+}
+
+/// Vypíše záznam jako JSON, pokud klient vypisuje ve formátu JSON a výstup
+/// není přesměrovaný.
+///
+/// # Returns
+///
+/// Vrací `false`, pokud se záznam nevypsal a volající má vypsat text.
+pub fn json(record: &Record) -> bool {
+    if !is_json() {
+        return false;
+    }
+    match serde_json::to_string(record) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Error serializing output: {}", e),
+    }
+    true
+}
+
+/// Předá událost, pokud je výstup přesměrovaný.
+///
+/// # Returns
+///
+/// Vrací `false`, pokud výstup přesměrovaný není a událost musí vypsat volající.
+pub fn show(event: Event) -> bool {
+    match EVENTS.get() {
+        Some(events) => {
+            // Po ukončení příjemce už výstup nemá kdo zobrazit
+            let _ = events.send(event);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Vypíše řádek textu na standardní výstup, nebo ho předá přesměrovanému výstupu
+pub fn line(text: String) {
+    match EVENTS.get() {
+        Some(events) => {
+            let _ = events.send(Event::Line(text));
+        }
+        None => {
+            if !json(&Record::Info { text: &text }) {
+                println!("{}", text);
+            }
+        }
+    }
+}
+
+/// Vypíše chybu při zpracování přijaté zprávy; ve formátu JSON jako `Record::Error`
+pub fn error(message: String) {
+    if !json(&Record::Error {
+        message: message.clone(),
+    }) {
+        line(message);
+    }
+}
+
+/// Vypíše zformátovaný řádek stejně jako `println!`, při přesměrovaném
+/// výstupu ho ale předá jako `Event::Line` a ve formátu JSON vypíše jako `Record::Info`
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::output::line(format!($($arg)*))
+    };
+}
+
+pub(crate) use say;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_json() {
+        let record = Record::UserJoined {
+            room: "dev",
+            user: "alice",
+            timestamp: 100,
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"event":"user_joined","room":"dev","user":"alice","timestamp":100}"#
+        );
+
+        let entry = HistoryEntry {
+            id: 7,
+            sender: "bob".to_string(),
+            room: "general".to_string(),
+            timestamp: 200,
+            kind: "text".to_string(),
+            content: "hi".to_string(),
+            filename: None,
+            size: None,
+        };
+        let json: serde_json::Value = serde_json::to_value(Record::history(&entry)).unwrap();
+        assert_eq!(json["event"], "message");
+        assert_eq!(json["history"], true);
+        // Missing file details are left out instead of being null
+        assert!(json.get("filename").is_none());
+    }
+}
@@ -0,0 +1,91 @@
+use chrono::{Days, Local, NaiveDate, TimeZone};
+use shared::SearchQuery;
+
+/// Nápověda k příkazu `.search`
+pub const SEARCH_USAGE: &str =
+    "Usage: .search [user:<name>] [room:<room>] [from:YYYY-MM-DD] [to:YYYY-MM-DD] [page:<n>] <words>";
+
+/// Převede argumenty příkazu `.search` na dotaz pro server.
+///
+/// Filtry se zapisují jako `klíč:hodnota`, ostatní slova se hledají v textu zpráv.
+/// Datum `to` se počítá včetně celého dne.
+///
+/// # Arguments
+///
+/// * `arguments` - Text za příkazem `.search`
+///
+/// # Errors
+///
+/// Vrací popis chyby, pokud filtr nemá platnou hodnotu nebo dotaz nic nehledá.
+pub fn parse_search(arguments: &str) -> Result<SearchQuery, String> {
+    let mut query = SearchQuery {
+        page: 1,
+        ..SearchQuery::default()
+    };
+    let mut words = Vec::new();
+
+    for word in arguments.split_whitespace() {
+        match word.split_once(':') {
+            Some(("user", name)) if !name.is_empty() => query.sender = Some(name.to_string()),
+            Some(("room", room)) if !room.is_empty() => query.room = Some(room.to_string()),
+            Some(("from", date)) => query.since = Some(day_start(date, 0)?),
+            Some(("to", date)) => query.until = Some(day_start(date, 1)?),
+            Some(("page", page)) => {
+                query.page = page
+                    .parse()
+                    .ok()
+                    .filter(|page| *page > 0)
+                    .ok_or_else(|| format!("Invalid page {}", page))?
+            }
+            _ => words.push(word),
+        }
+    }
+    query.text = words.join(" ");
+
+    let filtered = query.sender.is_some()
+        || query.room.is_some()
+        || query.since.is_some()
+        || query.until.is_some();
+    if query.text.is_empty() && !filtered {
+        return Err(SEARCH_USAGE.to_string());
+    }
+    Ok(query)
+}
+
+/// Vrací Unix timestamp začátku dne `date` posunutého o `offset_days` dní v místním čase
+fn day_start(date: &str, offset_days: u64) -> Result<i64, String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.checked_add_days(Days::new(offset_days)))
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|start| Local.from_local_datetime(&start).earliest())
+        .map(|start| start.timestamp())
+        .ok_or_else(|| format!("Invalid date {}, expected YYYY-MM-DD", date))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_search() {
+        let query = parse_search("user:alice deploy room:dev failed page:2").unwrap();
+        assert_eq!(query.text, "deploy failed");
+        assert_eq!(query.sender.as_deref(), Some("alice"));
+        assert_eq!(query.room.as_deref(), Some("dev"));
+        assert_eq!(query.page, 2);
+
+        // The end date includes the whole day
+        let query = parse_search("from:2024-03-01 to:2024-03-01").unwrap();
+        assert_eq!(query.until.unwrap() - query.since.unwrap(), 24 * 60 * 60);
+        assert!(query.text.is_empty());
+
+        // Words with a colon that is not a filter are searched for
+        assert_eq!(parse_search("note:todo").unwrap().text, "note:todo");
+
+        assert!(parse_search("").is_err());
+        assert!(parse_search("page:3").is_err());
+        assert!(parse_search("from:yesterday deploy").is_err());
+        assert!(parse_search("page:0 deploy").is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use crate::connection::Outgoing;
+use shared::client_error::ClientError;
+use shared::{MessageType, DEFAULT_ROOM};
+use std::path::PathBuf;
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::task;
+
+/// Argumenty příkazu `send`, který odešle zprávy a skončí
+#[derive(clap::Args, Debug)]
+pub struct SendArgs {
+    /// Text zprávy
+    #[arg(long)]
+    pub text: Option<String>,
+
+    /// Soubor, který se odešle
+    #[arg(long)]
+    pub file: Option<PathBuf>,
+
+    /// Místnost, do které se zprávy odešlou
+    #[arg(long, default_value = DEFAULT_ROOM)]
+    pub room: String,
+}
+
+/// Vrací požadavky zadané argumenty `--text` a `--file`.
+///
+/// # Errors
+///
+/// Vrací `ClientError::InvalidInput`, pokud zadaný soubor neexistuje.
+fn requests(args: &SendArgs) -> Result<Vec<Outgoing>, ClientError> {
+    let mut requests = Vec::new();
+    if let Some(text) = &args.text {
+        requests.push(Outgoing::Message(MessageType::Text(text.clone())));
+    }
+    if let Some(path) = &args.file {
+        if !path.is_file() {
+            return Err(ClientError::InvalidInput(format!(
+                "{} is not a file",
+                path.display()
+            )));
+        }
+        requests.push(Outgoing::Upload {
+            path: path.clone(),
+            image: false,
+        });
+    }
+    Ok(requests)
+}
+
+/// Na pozadí předá požadavky příkazu `send` správě spojení.
+///
+/// Bez `--text` a `--file` se jako zpráva odešle každý neprázdný řádek
+/// standardního vstupu. Kanál zanikne, až jsou předány všechny požadavky.
+///
+/// # Arguments
+///
+/// * `args` - Argumenty příkazu `send`
+/// * `sender` - Kanál pro požadavky odesílané na server
+///
+/// # Errors
+///
+/// Vrací `ClientError::InvalidInput`, pokud zadaný soubor neexistuje.
+pub fn queue_requests(args: &SendArgs, sender: mpsc::Sender<Outgoing>) -> Result<(), ClientError> {
+    let requests = requests(args)?;
+    task::spawn(async move {
+        if !requests.is_empty() {
+            for request in requests {
+                if sender.send(request).await.is_err() {
+                    return;
+                }
+            }
+            return;
+        }
+
+        let mut lines = BufReader::new(stdin()).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) if line.trim().is_empty() => {}
+                Ok(Some(line)) => {
+                    let request = Outgoing::Message(MessageType::Text(line));
+                    if sender.send(request).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("Error reading standard input: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        let args = SendArgs {
+            text: Some("build finished".to_string()),
+            file: Some(PathBuf::from("Cargo.toml")),
+            room: DEFAULT_ROOM.to_string(),
+        };
+        assert_eq!(
+            requests(&args).unwrap(),
+            vec![
+                Outgoing::Message(MessageType::Text("build finished".to_string())),
+                Outgoing::Upload {
+                    path: PathBuf::from("Cargo.toml"),
+                    image: false
+                }
+            ]
+        );
+
+        // Without arguments the messages are read from standard input
+        let args = SendArgs {
+            text: None,
+            file: None,
+            room: DEFAULT_ROOM.to_string(),
+        };
+        assert!(requests(&args).unwrap().is_empty());
+
+        let args = SendArgs {
+            file: Some(PathBuf::from("missing.log")),
+            ..args
+        };
+        assert!(matches!(requests(&args), Err(ClientError::InvalidInput(_))));
+    }
+}
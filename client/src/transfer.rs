@@ -0,0 +1,644 @@
+use crate::download::{create_unique, sanitize_filename, DownloadOptions};
+use shared::checksum::sha256_file;
+use shared::client_error::ClientError;
+use shared::image::ImageFormat;
+use shared::{MessageType, FILE_CHUNK_SIZE};
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, read_dir, rename, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{timeout, Duration};
+
+/// Adresář, do kterého se ukládají rozpracované soubory podle ID přenosu
+const PARTIAL_DIR: &str = ".partial";
+
+/// Po jaké době bez zápisu se rozpracovaný soubor zahodí místo dalšího navazování
+const PARTIAL_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Jak dlouho odesílatel čeká, než server přijme nabídku souboru
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Odesílané soubory čekající na přijetí nabídky serverem, podle ID přenosu
+pub type PendingUploads = Arc<Mutex<HashMap<u64, oneshot::Sender<u64>>>>;
+
+/// Soubor, který klient právě přijímá
+struct IncomingTransfer {
+    /// Upravený název souboru od odesílatele
+    filename: String,
+    image: bool,
+    /// Dočasný soubor, do kterého se zapisují přijaté části
+    part_path: PathBuf,
+    file: File,
+    size: u64,
+    received: u64,
+    sha256: String,
+    /// Zda už klient požádal server o chybějící data
+    requested: bool,
+}
+
+/// Stav přijímaného souboru po zpracování zprávy přenosu
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    /// Přenos pokračuje, případně jde o neznámý přenos
+    Pending,
+    /// Klientovi chybí data od dané pozice a má si o ně říct serveru
+    Missing(u64),
+    /// Soubor byl přijat, ověřen a uložen na uvedenou cestu
+    Completed(PathBuf),
+}
+
+/// Přijímané soubory podle ID přenosu.
+///
+/// Každá přijatá část se hned zapíše na disk, v paměti se tak drží
+/// nejvýše jedna část souboru. Rozpracované soubory zůstávají po odpojení
+/// v adresáři `.partial`, takže je lze po opětovném připojení dokončit.
+pub struct IncomingTransfers {
+    options: DownloadOptions,
+    active: HashMap<u64, IncomingTransfer>,
+    /// Přenosy dokončené v tomto spojení; opožděně doručená data se k nim ignorují
+    finished: HashSet<u64>,
+}
+
+impl IncomingTransfers {
+    /// Vytvoří úložiště přijímaných souborů
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - Adresář pro přijaté soubory a jejich největší povolená velikost
+    pub fn new(options: &DownloadOptions) -> Self {
+        IncomingTransfers {
+            options: options.clone(),
+            active: HashMap::new(),
+            finished: HashSet::new(),
+        }
+    }
+
+    /// Vrací rozpracované soubory z minulých spojení jako dvojice ID přenosu
+    /// a počtu už přijatých bajtů.
+    ///
+    /// Soubory, do kterých se déle než `PARTIAL_MAX_AGE` nezapisovalo, se smažou.
+    pub fn unfinished(&self) -> Vec<(u64, u64)> {
+        let Ok(entries) = read_dir(self.options.dir.join(PARTIAL_DIR)) else {
+            return Vec::new();
+        };
+
+        let mut unfinished: Vec<(u64, u64)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let id = entry.file_name().to_str()?.parse().ok()?;
+                let metadata = entry.metadata().ok()?;
+                let age = metadata.modified().ok()?.elapsed().unwrap_or_default();
+                if age > PARTIAL_MAX_AGE && !self.active.contains_key(&id) {
+                    let _ = std::fs::remove_file(entry.path());
+                    return None;
+                }
+                Some((id, metadata.len()))
+            })
+            .filter(|(id, _)| !self.active.contains_key(id))
+            .collect();
+        unfinished.sort();
+        unfinished
+    }
+
+    /// Začne přijímat nabídnutý soubor, případně naváže na rozpracovaný soubor.
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - ID přenosu
+    /// * `filename` - Název souboru od odesílatele
+    /// * `size` - Ohlášená velikost souboru
+    /// * `image` - Zda jde o obrázek
+    /// * `sha256` - Kontrolní součet celého souboru
+    ///
+    /// # Returns
+    ///
+    /// Vrací `false`, pokud klient tento přenos už přijímá nebo ho už dokončil.
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ClientError::InvalidInput`, pokud je soubor větší než povolený limit.
+    pub fn offer(
+        &mut self,
+        transfer_id: u64,
+        filename: &str,
+        size: u64,
+        image: bool,
+        sha256: &str,
+    ) -> Result<bool, ClientError> {
+        if self.active.contains_key(&transfer_id) || self.finished.contains(&transfer_id) {
+            return Ok(false);
+        }
+        self.options.check_size(filename, size)?;
+
+        let partial_dir = self.options.dir.join(PARTIAL_DIR);
+        create_dir_all(&partial_dir)?;
+        let part_path = partial_dir.join(transfer_id.to_string());
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part_path)?;
+        let mut received = file.metadata()?.len();
+        if received > size {
+            file.set_len(0)?;
+            received = 0;
+        }
+
+        self.active.insert(
+            transfer_id,
+            IncomingTransfer {
+                filename: sanitize_filename(filename),
+                image,
+                part_path,
+                file,
+                size,
+                received,
+                sha256: sha256.to_ascii_lowercase(),
+                requested: false,
+            },
+        );
+        Ok(true)
+    }
+
+    /// Vrací, kolik bajtů přenosu už klient má, nebo `None`, pokud přenos nepřijímá
+    pub fn received(&self, transfer_id: u64) -> Option<u64> {
+        self.active
+            .get(&transfer_id)
+            .map(|transfer| transfer.received)
+    }
+
+    /// Zapíše přijatou část souboru.
+    ///
+    /// Části nepatřící žádnému přenosu (například z přenosu, který začal před
+    /// vstupem do místnosti) se ignorují, stejně jako už přijatá data.
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - ID přenosu
+    /// * `offset` - Pozice části v souboru
+    /// * `data` - Obsah části
+    pub fn chunk(
+        &mut self,
+        transfer_id: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<Progress, ClientError> {
+        let Some(transfer) = self.active.get_mut(&transfer_id) else {
+            return Ok(Progress::Pending);
+        };
+
+        if offset > transfer.received {
+            // Část dat chybí, například po přerušeném spojení; stačí o ni požádat jednou
+            if transfer.requested {
+                return Ok(Progress::Pending);
+            }
+            transfer.requested = true;
+            return Ok(Progress::Missing(transfer.received));
+        }
+
+        let end = offset + data.len() as u64;
+        if end > transfer.size {
+            self.abort(transfer_id);
+            return Err(ClientError::InvalidInput(format!(
+                "Transfer {} is larger than announced",
+                transfer_id
+            )));
+        }
+        if end > transfer.received {
+            let skip = (transfer.received - offset) as usize;
+            transfer.file.write_all(&data[skip..])?;
+            transfer.received = end;
+            transfer.requested = false;
+        }
+        Ok(Progress::Pending)
+    }
+
+    /// Dokončí přenos, ověří kontrolní součet a přesune soubor pod volný název
+    /// do složky `files`, případně `images`; existující soubory se nepřepisují.
+    /// Obrázek dostane příponu podle formátu rozpoznaného z obsahu.
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - ID přenosu
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ClientError::InvalidInput`, pokud soubor neodpovídá kontrolnímu součtu
+    /// nebo obrázek není v podporovaném formátu; rozpracovaný soubor se v tom případě smaže.
+    pub async fn complete(&mut self, transfer_id: u64) -> Result<Progress, ClientError> {
+        let Some(transfer) = self.active.get_mut(&transfer_id) else {
+            return Ok(Progress::Pending);
+        };
+
+        if transfer.received < transfer.size {
+            // Zbytek dat dorazí na dřívější žádost, pokud o něj klient už požádal
+            if transfer.requested {
+                return Ok(Progress::Pending);
+            }
+            transfer.requested = true;
+            return Ok(Progress::Missing(transfer.received));
+        }
+
+        let mut transfer = self.active.remove(&transfer_id).unwrap();
+        transfer.file.flush()?;
+        drop(transfer.file);
+
+        if sha256_file(&transfer.part_path).await? != transfer.sha256 {
+            let _ = std::fs::remove_file(&transfer.part_path);
+            return Err(ClientError::InvalidInput(format!(
+                "{} does not match its SHA-256 hash and was discarded",
+                transfer.filename
+            )));
+        }
+
+        let (folder, filename) = if transfer.image {
+            match ImageFormat::detect_file(&transfer.part_path)? {
+                Some(format) => ("images", format.file_name(&transfer.filename)),
+                None => {
+                    let _ = std::fs::remove_file(&transfer.part_path);
+                    return Err(ClientError::InvalidInput(format!(
+                        "{} is not a PNG, JPEG, GIF or WebP image and was discarded",
+                        transfer.filename
+                    )));
+                }
+            }
+        } else {
+            ("files", transfer.filename)
+        };
+        // Vytvořený prázdný soubor jen rezervuje název, přejmenování ho nahradí
+        let (path, _) = create_unique(&self.options.dir.join(folder), &filename)?;
+        rename(&transfer.part_path, &path)?;
+        self.finished.insert(transfer_id);
+        Ok(Progress::Completed(path))
+    }
+
+    /// Zruší přenos a smaže jeho rozpracovaný soubor.
+    ///
+    /// Smaže i rozpracovaný soubor z minulého spojení, o jehož dokončení klient
+    /// požádal a server ho odmítl, aby se o něj klient nepokoušel znovu.
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - ID přenosu
+    ///
+    /// # Returns
+    ///
+    /// Vrací `true`, pokud klient přenos přijímal nebo měl jeho rozpracovaný soubor.
+    pub fn abort(&mut self, transfer_id: u64) -> bool {
+        let part_path = match self.active.remove(&transfer_id) {
+            Some(transfer) => transfer.part_path,
+            None => self
+                .options
+                .dir
+                .join(PARTIAL_DIR)
+                .join(transfer_id.to_string()),
+        };
+        std::fs::remove_file(part_path).is_ok()
+    }
+}
+
+/// Odešle soubor po částech.
+///
+/// Nejdřív spočítá kontrolní součet souboru a nabídne ho serveru; server odpoví
+/// pozicí, od které má data posílat, takže přerušené odesílání téhož souboru
+/// pokračuje tam, kde skončilo. Soubor se čte postupně, v paměti je najednou
+/// nejvýše tolik částí, kolik se vejde do kanálu. Ostatní zprávy poslané do
+/// stejného kanálu se mezi části souboru průběžně vkládají.
+///
+/// # Arguments
+///
+/// * `sender` - Kanál pro zprávy odesílané na server
+/// * `uploads` - Odesílané soubory čekající na přijetí nabídky
+/// * `transfer_id` - ID přenosu zvolené klientem
+/// * `path` - Cesta k odesílanému souboru
+/// * `image` - Zda jde o obrázek
+///
+/// # Returns
+///
+/// Vrací pozici, od které se soubor odesílal.
+///
+/// # Errors
+///
+/// Vrací `ClientError::ConnectionError`, pokud se spojení se serverem ztratí;
+/// odesílání lze po obnovení spojení zopakovat a naváže na přijatá data.
+pub async fn send_file(
+    sender: &mpsc::Sender<MessageType>,
+    uploads: &PendingUploads,
+    transfer_id: u64,
+    path: &Path,
+    image: bool,
+) -> Result<u64, ClientError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let size = file.metadata().await?.len();
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| ClientError::InvalidInput(format!("{} is not a file", path.display())))?;
+    let sha256 = sha256_file(path).await?;
+
+    let (accepted_sender, accepted) = oneshot::channel();
+    uploads.lock().await.insert(transfer_id, accepted_sender);
+    send(
+        sender,
+        MessageType::FileOffer {
+            transfer_id,
+            filename,
+            size,
+            image,
+            sha256,
+        },
+    )
+    .await?;
+
+    let start = match timeout(ACCEPT_TIMEOUT, accepted).await {
+        Ok(Ok(offset)) if offset <= size => offset,
+        Ok(Ok(offset)) => {
+            return Err(ClientError::Other(format!(
+                "Server asked for data from offset {} of a {} byte file",
+                offset, size
+            )))
+        }
+        // Čekání zrušila ztráta spojení, odesílání lze po obnovení zopakovat
+        Ok(Err(_)) => return Err(connection_lost()),
+        Err(_) => {
+            uploads.lock().await.remove(&transfer_id);
+            return Err(ClientError::Other(
+                "Server did not accept the file".to_string(),
+            ));
+        }
+    };
+
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    let mut offset = start;
+    let mut buffer = vec![0u8; FILE_CHUNK_SIZE];
+    loop {
+        let read = match file.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(read) if offset + read as u64 <= size => read,
+            result => {
+                let reason = match result {
+                    Err(e) => e.to_string(),
+                    Ok(_) => "file grew while it was being sent".to_string(),
+                };
+                send(
+                    sender,
+                    MessageType::FileAbort {
+                        transfer_id,
+                        reason: reason.clone(),
+                    },
+                )
+                .await?;
+                return Err(ClientError::Other(reason));
+            }
+        };
+
+        send(
+            sender,
+            MessageType::FileChunk {
+                transfer_id,
+                offset,
+                data: buffer[..read].to_vec(),
+            },
+        )
+        .await?;
+        offset += read as u64;
+    }
+
+    send(sender, MessageType::FileComplete { transfer_id }).await?;
+    Ok(start)
+}
+
+/// Předá odesílateli souboru pozici, od které má server data přijímat.
+///
+/// # Arguments
+///
+/// * `uploads` - Odesílané soubory čekající na přijetí nabídky
+/// * `transfer_id` - ID přenosu zvolené klientem
+/// * `offset` - Pozice, kterou server potvrdil
+pub async fn accept_upload(uploads: &PendingUploads, transfer_id: u64, offset: u64) {
+    if let Some(accepted) = uploads.lock().await.remove(&transfer_id) {
+        let _ = accepted.send(offset);
+    }
+}
+
+async fn send(sender: &mpsc::Sender<MessageType>, message: MessageType) -> Result<(), ClientError> {
+    sender.send(message).await.map_err(|_| connection_lost())
+}
+
+/// Chyba vracená operacím, které přerušila ztráta spojení se serverem
+pub fn connection_lost() -> ClientError {
+    ClientError::ConnectionError("Connection to server lost".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SHA-256 of "hello!"
+    const HELLO_SHA256: &str = "ce06092fb948d9ffac7d1a376e404b26b7575bcc11ee05a4615fef4fec3a308b";
+
+    /// Download options that store received files in `dir` and accept up to 1 KiB
+    fn downloads(dir: &Path) -> DownloadOptions {
+        DownloadOptions {
+            dir: dir.to_path_buf(),
+            max_size: 1024,
+            policy: crate::download::IncomingPolicy::Accept,
+        }
+    }
+
+    /// Sends `path` and answers its offer with `offset`, returning everything that was sent
+    async fn send_and_collect(path: PathBuf, offset: u64) -> Result<Vec<MessageType>, ClientError> {
+        let uploads = PendingUploads::default();
+        let (sender, mut receiver) = mpsc::channel(4);
+        let task_uploads = Arc::clone(&uploads);
+        let task =
+            tokio::spawn(async move { send_file(&sender, &task_uploads, 3, &path, false).await });
+
+        let mut messages = Vec::new();
+        while let Some(message) = receiver.recv().await {
+            if matches!(message, MessageType::FileOffer { .. }) {
+                accept_upload(&uploads, 3, offset).await;
+            }
+            messages.push(message);
+        }
+        assert_eq!(task.await.unwrap()?, offset);
+        Ok(messages)
+    }
+
+    #[tokio::test]
+    async fn test_send_file_in_chunks() -> Result<(), ClientError> {
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path();
+        let path = dir.join("big.bin");
+        let content: Vec<u8> = (0..FILE_CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        std::fs::write(&path, &content)?;
+
+        let messages = send_and_collect(path.clone(), 0).await?;
+        assert_eq!(
+            messages.first(),
+            Some(&MessageType::FileOffer {
+                transfer_id: 3,
+                filename: "big.bin".to_string(),
+                size: content.len() as u64,
+                image: false,
+                sha256: sha256_file(&path).await?,
+            })
+        );
+        assert_eq!(
+            messages.last(),
+            Some(&MessageType::FileComplete { transfer_id: 3 })
+        );
+
+        let mut received = Vec::new();
+        for message in &messages[1..messages.len() - 1] {
+            match message {
+                MessageType::FileChunk { offset, data, .. } => {
+                    assert_eq!(*offset, received.len() as u64);
+                    assert!(data.len() <= FILE_CHUNK_SIZE);
+                    received.extend_from_slice(data);
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert_eq!(received, content);
+
+        // A resumed upload only sends what the server does not have yet
+        let messages = send_and_collect(path, FILE_CHUNK_SIZE as u64 * 2).await?;
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(
+            &messages[1],
+            MessageType::FileChunk { offset, data, .. }
+                if *offset == FILE_CHUNK_SIZE as u64 * 2 && data.len() == 10
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incoming_transfer() -> Result<(), ClientError> {
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path();
+        let mut transfers = IncomingTransfers::new(&downloads(dir));
+
+        assert!(transfers.offer(1, "notes.txt", 6, false, HELLO_SHA256)?);
+        assert!(!transfers.offer(1, "notes.txt", 6, false, HELLO_SHA256)?);
+        transfers.chunk(1, 0, b"hel")?;
+
+        // A gap is requested once, data that was already received is skipped
+        assert_eq!(transfers.chunk(1, 4, b"o!")?, Progress::Missing(3));
+        assert_eq!(transfers.chunk(1, 5, b"!")?, Progress::Pending);
+        transfers.chunk(1, 2, b"llo")?;
+        assert_eq!(transfers.complete(1).await?, Progress::Missing(5));
+        transfers.chunk(1, 5, b"!")?;
+
+        let path = dir.join("files").join("notes.txt");
+        assert_eq!(
+            transfers.complete(1).await?,
+            Progress::Completed(path.clone())
+        );
+        assert_eq!(std::fs::read(&path)?, b"hello!");
+
+        // A late re-sent offer of a finished transfer does not start it again
+        assert!(!transfers.offer(1, "notes.txt", 6, false, HELLO_SHA256)?);
+
+        // Another file with the same name is stored next to the first one, and a
+        // name with a path cannot escape the download directory
+        transfers.offer(3, "../notes.txt", 6, false, HELLO_SHA256)?;
+        transfers.chunk(3, 0, b"hello!")?;
+        assert_eq!(
+            transfers.complete(3).await?,
+            Progress::Completed(dir.join("files").join("notes (1).txt"))
+        );
+        assert_eq!(std::fs::read(&path)?, b"hello!");
+
+        // Files over the limit are refused before anything is written
+        assert!(transfers
+            .offer(4, "big.bin", 2048, false, HELLO_SHA256)
+            .is_err());
+        assert!(transfers.unfinished().is_empty());
+
+        // Chunks of unknown transfers are ignored
+        assert_eq!(transfers.chunk(2, 0, b"ignored")?, Progress::Pending);
+        assert_eq!(transfers.complete(2).await?, Progress::Pending);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incoming_image_is_named_by_content() -> Result<(), ClientError> {
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path();
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10, b'J', b'F', b'I', b'F'];
+        let source = dir.join("source");
+        std::fs::write(&source, jpeg)?;
+        let jpeg_sha256 = sha256_file(&source).await?;
+
+        // A JPEG sent with a PNG extension is stored as .jpg
+        let mut transfers = IncomingTransfers::new(&downloads(dir));
+        transfers.offer(1, "photo.png", jpeg.len() as u64, true, &jpeg_sha256)?;
+        transfers.chunk(1, 0, &jpeg)?;
+        assert_eq!(
+            transfers.complete(1).await?,
+            Progress::Completed(dir.join("images").join("photo.jpg"))
+        );
+
+        // Content that is not an image is discarded even with an image extension
+        transfers.offer(2, "fake.png", 6, true, HELLO_SHA256)?;
+        transfers.chunk(2, 0, b"hello!")?;
+        assert!(transfers.complete(2).await.is_err());
+        assert!(!dir.join("images").join("fake.png").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incoming_transfer_resume_and_integrity() -> Result<(), ClientError> {
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path();
+
+        // A transfer interrupted in an earlier session is picked up again
+        let mut transfers = IncomingTransfers::new(&downloads(dir));
+        transfers.offer(5, "notes.txt", 6, false, HELLO_SHA256)?;
+        transfers.chunk(5, 0, b"hel")?;
+        drop(transfers);
+
+        let mut transfers = IncomingTransfers::new(&downloads(dir));
+        assert_eq!(transfers.unfinished(), vec![(5, 3)]);
+        transfers.offer(5, "notes.txt", 6, false, HELLO_SHA256)?;
+        assert!(transfers.unfinished().is_empty());
+        transfers.chunk(5, 3, b"lo!")?;
+        assert!(matches!(
+            transfers.complete(5).await?,
+            Progress::Completed(_)
+        ));
+
+        // A file that does not match its hash is discarded
+        transfers.offer(6, "evil.txt", 6, false, HELLO_SHA256)?;
+        transfers.chunk(6, 0, b"HELLO!")?;
+        assert!(transfers.complete(6).await.is_err());
+        assert!(!dir.join("files").join("evil.txt").exists());
+        assert!(transfers.unfinished().is_empty());
+
+        // A resume the server refuses removes the partial file for good
+        transfers.offer(7, "gone.txt", 6, false, HELLO_SHA256)?;
+        transfers.chunk(7, 0, b"hel")?;
+        drop(transfers);
+        let mut transfers = IncomingTransfers::new(&downloads(dir));
+        assert_eq!(transfers.unfinished(), vec![(7, 3)]);
+        assert!(transfers.abort(7));
+        assert!(transfers.unfinished().is_empty());
+        assert!(!transfers.abort(7));
+
+        // Partial files nobody wrote to for a long time expire
+        let stale = dir.join(PARTIAL_DIR).join("8");
+        File::create(&stale)?.set_modified(
+            std::time::SystemTime::now() - PARTIAL_MAX_AGE - Duration::from_secs(60),
+        )?;
+        assert!(transfers.unfinished().is_empty());
+        assert!(!stale.exists());
+
+        Ok(())
+    }
+}
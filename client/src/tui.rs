@@ -0,0 +1,664 @@
+use crate::connection::Outgoing;
+use crate::output::Event;
+use crate::{parse_input, Input};
+use crossterm::event::{
+    self, Event as TerminalEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph};
+use ratatui::{Frame, Terminal};
+use shared::client_error::ClientError;
+use shared::{MessageType, OnlineUser, RoomInfo, SearchQuery};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Nejvyšší počet řádků, které si okno se zprávami pamatuje
+const SCROLLBACK_LIMIT: usize = 5000;
+
+/// Nejvyšší počet zadaných řádků, ke kterým se lze vrátit šipkami
+const INPUT_HISTORY_LIMIT: usize = 500;
+
+/// Šířka postranního panelu s místnostmi a uživateli
+const SIDEBAR_WIDTH: u16 = 26;
+
+/// Jak často vlákno čtoucí klávesnici kontroluje, zda má skončit
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Řádek pro psaní zpráv s historií dříve zadaných řádků
+#[derive(Debug, Default)]
+struct InputLine {
+    text: String,
+    /// Pozice kurzoru ve znacích
+    cursor: usize,
+    history: Vec<String>,
+    /// Právě zobrazená položka historie; `None` při psaní nového řádku
+    browsing: Option<usize>,
+    /// Rozepsaný řádek odložený během procházení historie
+    draft: String,
+}
+
+impl InputLine {
+    /// Vrací pozici kurzoru v bajtech
+    fn byte_index(&self) -> usize {
+        self.text
+            .char_indices()
+            .nth(self.cursor)
+            .map_or(self.text.len(), |(index, _)| index)
+    }
+
+    fn insert(&mut self, c: char) {
+        let index = self.byte_index();
+        self.text.insert(index, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.text.remove(self.byte_index());
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.text.chars().count() {
+            self.text.remove(self.byte_index());
+        }
+    }
+
+    fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.text.chars().count());
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.text.chars().count();
+    }
+
+    /// Smaže text před kurzorem
+    fn clear_before_cursor(&mut self) {
+        self.text.replace_range(..self.byte_index(), "");
+        self.cursor = 0;
+    }
+
+    /// Nahradí text řádku a přesune kurzor na jeho konec
+    fn set(&mut self, text: String) {
+        self.text = text;
+        self.end();
+    }
+
+    /// Zobrazí předchozí zadaný řádek
+    fn previous(&mut self) {
+        let index = match self.browsing {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.text);
+                self.history.len() - 1
+            }
+        };
+        self.browsing = Some(index);
+        self.set(self.history[index].clone());
+    }
+
+    /// Zobrazí následující zadaný řádek, za posledním se vrátí k rozepsanému
+    fn next(&mut self) {
+        let Some(index) = self.browsing else { return };
+        if index + 1 < self.history.len() {
+            self.browsing = Some(index + 1);
+            self.set(self.history[index + 1].clone());
+        } else {
+            self.browsing = None;
+            let draft = std::mem::take(&mut self.draft);
+            self.set(draft);
+        }
+    }
+
+    /// Odebere zadaný řádek z pole pro psaní a uloží ho do historie
+    fn submit(&mut self) -> String {
+        let text = std::mem::take(&mut self.text);
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+        if !text.trim().is_empty() && self.history.last() != Some(&text) {
+            if self.history.len() >= INPUT_HISTORY_LIMIT {
+                self.history.remove(0);
+            }
+            self.history.push(text.clone());
+        }
+        text
+    }
+}
+
+/// Co má rozhraní udělat po stisku klávesy
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// Zpracovat zadaný řádek
+    Submit(String),
+    /// Ukončit klienta
+    Quit,
+}
+
+/// Stav celoobrazovkového rozhraní
+#[derive(Debug, Default)]
+struct App {
+    messages: VecDeque<String>,
+    /// O kolik řádků obrazovky je okno se zprávami odrolováno od nejnovější zprávy
+    scroll: usize,
+    /// Rozměry vnitřku okna se zprávami při posledním vykreslení
+    width: usize,
+    height: usize,
+    input: InputLine,
+    room: String,
+    rooms: Vec<RoomInfo>,
+    users: Vec<OnlineUser>,
+    /// Počet nepřečtených zpráv v ostatních místnostech
+    unread: HashMap<String, u32>,
+    /// Poslední upozornění na zprávu v jiné místnosti
+    notice: Option<String>,
+}
+
+impl App {
+    /// Přidá řádek do okna se zprávami.
+    ///
+    /// Pokud je okno odrolované, zůstane zobrazená stejná část zpráv.
+    fn push_line(&mut self, text: &str) {
+        for line in text.lines() {
+            if self.scroll > 0 {
+                self.scroll += wrap(line, self.width).len();
+            }
+            if self.messages.len() >= SCROLLBACK_LIMIT {
+                self.messages.pop_front();
+            }
+            self.messages.push_back(line.to_string());
+        }
+    }
+
+    /// Zpracuje událost klienta.
+    ///
+    /// # Returns
+    ///
+    /// Vrací `true`, pokud se má obnovit seznam místností a uživatelů.
+    fn apply(&mut self, event: Event) -> bool {
+        match event {
+            Event::Line(text) => {
+                self.push_line(&text);
+                false
+            }
+            Event::RoomJoined(room) => {
+                self.unread.remove(&room);
+                self.notice = None;
+                self.room = room;
+                true
+            }
+            Event::Rooms(rooms) => {
+                self.rooms = rooms;
+                false
+            }
+            Event::Users(mut users) => {
+                users.sort();
+                self.users = users;
+                false
+            }
+            Event::PresenceChanged => true,
+            // Chyba se v okně se zprávami zobrazí jako řádek
+            Event::ServerError(_) => false,
+            Event::Activity { room, sender } => {
+                if room == self.room {
+                    return false;
+                }
+                *self.unread.entry(room.clone()).or_default() += 1;
+                self.notice = Some(format!("New message in #{} from {}", room, sender));
+                !self.rooms.iter().any(|known| known.name == room)
+            }
+        }
+    }
+
+    /// Zpracuje stisk klávesy
+    fn key(&mut self, key: KeyEvent) -> Option<Action> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if control => return Some(Action::Quit),
+            KeyCode::Char('d') if control && self.input.text.is_empty() => {
+                return Some(Action::Quit)
+            }
+            KeyCode::Char('a') if control => self.input.home(),
+            KeyCode::Char('e') if control => self.input.end(),
+            KeyCode::Char('u') if control => self.input.clear_before_cursor(),
+            KeyCode::Char(c) if !control => self.input.insert(c),
+            KeyCode::Enter => {
+                let text = self.input.submit();
+                if !text.trim().is_empty() {
+                    return Some(Action::Submit(text));
+                }
+            }
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.home(),
+            KeyCode::End => self.input.end(),
+            KeyCode::Up => self.input.previous(),
+            KeyCode::Down => self.input.next(),
+            KeyCode::PageUp => self.scroll += self.page(),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.page()),
+            _ => {}
+        }
+        None
+    }
+
+    /// Počet řádků, o které se okno se zprávami posune jednou stránkou
+    fn page(&self) -> usize {
+        self.height.saturating_sub(1).max(1)
+    }
+
+    /// Vrací řádky obrazovky, které se vejdou do okna se zprávami, a upraví
+    /// odrolování, pokud sahá před nejstarší zprávu
+    fn visible_rows(&mut self) -> Vec<String> {
+        let wanted = self.height.saturating_add(self.scroll);
+        let mut rows = VecDeque::new();
+        for message in self.messages.iter().rev() {
+            for row in wrap(message, self.width).into_iter().rev() {
+                rows.push_front(row);
+            }
+            if rows.len() >= wanted {
+                break;
+            }
+        }
+        self.scroll = self.scroll.min(rows.len().saturating_sub(self.height));
+        let end = rows.len() - self.scroll;
+        let start = end.saturating_sub(self.height);
+        rows.range(start..end).cloned().collect()
+    }
+}
+
+/// Rozdělí řádek na části, které se vejdou do zadané šířky obrazovky
+///
+/// # Arguments
+///
+/// * `text` - Řádek textu
+/// * `width` - Šířka ve sloupcích terminálu
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let mut row = String::new();
+    let mut row_width = 0;
+    for c in text.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if row_width + char_width > width && !row.is_empty() {
+            rows.push(std::mem::take(&mut row));
+            row_width = 0;
+        }
+        row.push(c);
+        row_width += char_width;
+    }
+    rows.push(row);
+    rows
+}
+
+/// Vykreslí celé rozhraní
+fn draw(frame: &mut Frame, app: &mut App) {
+    let [main, sidebar] =
+        Layout::horizontal([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)])
+            .areas(frame.area());
+    let [messages, input] =
+        Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(main);
+    let [rooms, users] =
+        Layout::vertical([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(sidebar);
+
+    draw_messages(frame, app, messages);
+    draw_input(frame, app, input);
+    draw_rooms(frame, app, rooms);
+    draw_users(frame, app, users);
+}
+
+fn draw_messages(frame: &mut Frame, app: &mut App, area: Rect) {
+    app.width = area.width.saturating_sub(2) as usize;
+    app.height = area.height.saturating_sub(2) as usize;
+    let rows: Vec<Line> = app.visible_rows().into_iter().map(Line::from).collect();
+
+    let mut title = format!(" #{} ", app.room);
+    if app.scroll > 0 {
+        title.push_str("(scrolled, PgDn to return) ");
+    }
+    let block = Block::default().borders(Borders::ALL).title(title);
+    frame.render_widget(Paragraph::new(rows).block(block), area);
+}
+
+fn draw_input(frame: &mut Frame, app: &App, area: Rect) {
+    let block = match &app.notice {
+        Some(notice) => Block::default()
+            .borders(Borders::ALL)
+            .title(format!(" {} ", notice))
+            .title_style(
+                Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD),
+            ),
+        None => Block::default()
+            .borders(Borders::ALL)
+            .title(" Message (Ctrl+C to quit) "),
+    };
+
+    // Dlouhý řádek se posune tak, aby byl kurzor vždy vidět
+    let width = area.width.saturating_sub(3) as usize;
+    let before_cursor: String = app.input.text.chars().take(app.input.cursor).collect();
+    let mut skipped = 0;
+    let mut cursor_width = before_cursor.width();
+    for c in before_cursor.chars() {
+        if cursor_width <= width {
+            break;
+        }
+        cursor_width -= c.width().unwrap_or(0);
+        skipped += 1;
+    }
+    let visible: String = app.input.text.chars().skip(skipped).collect();
+
+    frame.render_widget(Paragraph::new(visible).block(block), area);
+    frame.set_cursor_position(Position::new(area.x + 1 + cursor_width as u16, area.y + 1));
+}
+
+fn draw_rooms(frame: &mut Frame, app: &App, area: Rect) {
+    let items: Vec<ListItem> = app
+        .rooms
+        .iter()
+        .map(|room| {
+            let current = room.name == app.room;
+            let mut spans = vec![Span::styled(
+                format!(
+                    "{}{} ({})",
+                    if current { "> " } else { "  " },
+                    room.name,
+                    room.online
+                ),
+                if current {
+                    Style::default().add_modifier(Modifier::BOLD)
+                } else {
+                    Style::default()
+                },
+            )];
+            if let Some(count) = app.unread.get(&room.name) {
+                spans.push(Span::styled(
+                    format!(" +{}", count),
+                    Style::default()
+                        .fg(Color::Yellow)
+                        .add_modifier(Modifier::BOLD),
+                ));
+            }
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+    let block = Block::default().borders(Borders::ALL).title(" Rooms ");
+    frame.render_widget(List::new(items).block(block), area);
+}
+
+fn draw_users(frame: &mut Frame, app: &App, area: Rect) {
+    let items: Vec<ListItem> = app
+        .users
+        .iter()
+        .map(|user| {
+            let style = if user.room == app.room {
+                Style::default()
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            ListItem::new(Line::styled(
+                format!("{} #{}", user.username, user.room),
+                style,
+            ))
+        })
+        .collect();
+    let block = Block::default().borders(Borders::ALL).title(" Online ");
+    frame.render_widget(List::new(items).block(block), area);
+}
+
+/// Terminál přepnutý do celoobrazovkového režimu; při zániku se obnoví
+struct Screen {
+    terminal: Terminal<CrosstermBackend<io::Stdout>>,
+}
+
+impl Screen {
+    fn enter() -> io::Result<Self> {
+        enable_raw_mode()?;
+        let terminal = execute!(io::stdout(), EnterAlternateScreen)
+            .and_then(|_| Terminal::new(CrosstermBackend::new(io::stdout())));
+        match terminal {
+            Ok(terminal) => Ok(Screen { terminal }),
+            Err(e) => {
+                let _ = disable_raw_mode();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+/// Spustí vlákno, které čte události terminálu a předává je rozhraní.
+///
+/// Čtení je blokující, vlákno proto pravidelně kontroluje příznak `stop`.
+fn spawn_terminal_reader(
+    events: mpsc::UnboundedSender<TerminalEvent>,
+    stop: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+            let event = match event::poll(POLL_INTERVAL) {
+                Ok(true) => event::read(),
+                Ok(false) => continue,
+                Err(e) => Err(e),
+            };
+            match event {
+                Ok(event) if !events.is_closed() => {
+                    let _ = events.send(event);
+                }
+                _ => break,
+            }
+        }
+    })
+}
+
+/// Požádá server o aktuální seznam místností a připojených uživatelů
+async fn refresh_sidebar(sender: &mpsc::Sender<Outgoing>) -> Result<(), ClientError> {
+    for message in [MessageType::ListRooms, MessageType::ListUsers] {
+        send(sender, Outgoing::Message(message)).await?;
+    }
+    Ok(())
+}
+
+async fn send(sender: &mpsc::Sender<Outgoing>, request: Outgoing) -> Result<(), ClientError> {
+    sender
+        .send(request)
+        .await
+        .map_err(|e| ClientError::Other(e.to_string()))
+}
+
+/// Spustí celoobrazovkové rozhraní a obsluhuje ho, dokud ho uživatel neukončí.
+///
+/// # Arguments
+///
+/// * `sender` - Kanál pro požadavky odesílané na server
+/// * `events` - Výstup klienta přesměrovaný funkcí `output::redirect`
+///
+/// # Errors
+///
+/// Vrací `ClientError::Io`, pokud terminál nejde přepnout do celoobrazovkového režimu.
+pub async fn run(
+    sender: mpsc::Sender<Outgoing>,
+    mut events: mpsc::UnboundedReceiver<Event>,
+) -> Result<(), ClientError> {
+    let mut screen = Screen::enter()?;
+    let (terminal_sender, mut terminal_events) = mpsc::unbounded_channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let reader = spawn_terminal_reader(terminal_sender, Arc::clone(&stop));
+
+    let mut app = App::default();
+    let mut last_search: Option<SearchQuery> = None;
+    let result = async {
+        refresh_sidebar(&sender).await?;
+        loop {
+            screen.terminal.draw(|frame| draw(frame, &mut app))?;
+            let mut refresh = false;
+            tokio::select! {
+                event = terminal_events.recv() => match event {
+                    Some(TerminalEvent::Key(key)) if key.kind == KeyEventKind::Press => {
+                        match app.key(key) {
+                            Some(Action::Submit(text)) => {
+                                match parse_input(&text, &mut last_search) {
+                                    Input::Send(request) => send(&sender, request).await?,
+                                    Input::Quit => return Ok(()),
+                                    Input::Skip => {}
+                                }
+                            }
+                            Some(Action::Quit) => return Ok(()),
+                            None => {}
+                        }
+                    }
+                    Some(_) => {}
+                    None => return Ok(()),
+                },
+                Some(event) = events.recv() => {
+                    refresh = app.apply(event);
+                    // Dávka zpráv, například historie, se vykreslí najednou
+                    while let Ok(event) = events.try_recv() {
+                        refresh |= app.apply(event);
+                    }
+                }
+            }
+            if refresh {
+                refresh_sidebar(&sender).await?;
+            }
+        }
+    }
+    .await;
+
+    stop.store(true, Ordering::Relaxed);
+    let _ = reader.join();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+
+    #[test]
+    fn test_input_line_editing_and_history() {
+        let mut input = InputLine::default();
+        for c in "hllo".chars() {
+            input.insert(c);
+        }
+        input.home();
+        input.right();
+        input.insert('e');
+        assert_eq!(input.text, "hello");
+        input.end();
+        input.backspace();
+        input.left();
+        input.delete();
+        assert_eq!(input.text, "hel");
+
+        assert_eq!(input.submit(), "hel");
+        input.set(".join dev".to_string());
+        input.submit();
+        // Empty lines and repeated lines are not remembered
+        input.submit();
+        input.set(".join dev".to_string());
+        input.submit();
+        assert_eq!(input.history, vec!["hel", ".join dev"]);
+
+        input.set("draft".to_string());
+        input.previous();
+        assert_eq!(input.text, ".join dev");
+        input.previous();
+        input.previous();
+        assert_eq!(input.text, "hel");
+        input.next();
+        input.next();
+        assert_eq!(input.text, "draft");
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(wrap("", 4), vec![""]);
+        // Wide characters never overflow the row
+        assert_eq!(wrap("ab日本", 3), vec!["ab", "日", "本"]);
+    }
+
+    #[test]
+    fn test_notifications_and_scrollback() {
+        let mut app = App::default();
+        assert!(app.apply(Event::RoomJoined("general".to_string())));
+        app.apply(Event::Rooms(vec![
+            RoomInfo {
+                name: "general".to_string(),
+                online: 2,
+            },
+            RoomInfo {
+                name: "dev".to_string(),
+                online: 1,
+            },
+        ]));
+
+        // Activity in the current room is shown as the message itself
+        let activity = |room: &str| Event::Activity {
+            room: room.to_string(),
+            sender: "bob".to_string(),
+        };
+        assert!(!app.apply(activity("general")));
+        assert!(!app.apply(activity("dev")));
+        app.apply(activity("dev"));
+        // A room missing from the sidebar makes it refresh
+        assert!(app.apply(activity("ops")));
+        assert_eq!(app.unread.get("dev"), Some(&2));
+
+        for i in 0..30 {
+            app.apply(Event::Line(format!("line {}", i)));
+        }
+        let mut terminal = Terminal::new(TestBackend::new(60, 12)).unwrap();
+        terminal.draw(|frame| draw(frame, &mut app)).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        assert!(screen.contains("dev (1) +2"));
+        assert!(screen.contains("line 29"));
+        assert!(screen.contains("New message in #ops from bob"));
+
+        // Scrolling up keeps the same lines visible when new ones arrive
+        app.scroll = 5;
+        let visible = app.visible_rows();
+        app.apply(Event::Line("line 30".to_string()));
+        assert_eq!(app.visible_rows(), visible);
+        app.scroll = usize::MAX;
+        assert_eq!(app.visible_rows()[0], "line 0");
+
+        app.apply(Event::RoomJoined("dev".to_string()));
+        assert_eq!(app.unread.get("dev"), None);
+        assert_eq!(app.notice, None);
+    }
+}
@@ -0,0 +1,200 @@
+use shared::checksum::{is_sha256_hex, sha256_bytes};
+use shared::protocol::unix_timestamp;
+use shared::server_error::ServerError;
+use sqlx::sqlite::SqlitePool;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs::{self, File};
+
+/// Podadresář adresáře pro nahrané soubory, ve kterém jsou uložená data příloh
+const BLOB_DIR: &str = "blobs";
+
+/// Počítadlo dočasných souborů, aby se souběžné zápisy stejného obsahu nepletly
+static TEMPORARY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Vytvoří tabulku uložených příloh.
+///
+/// Obsah každé přílohy je na disku uložený jen jednou pod svým SHA-256, bez
+/// ohledu na to, kolikrát a pod jakým názvem byl nahrán.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+pub async fn init_attachments(pool: &SqlitePool) -> Result<(), ServerError> {
+    sqlx::query(
+        "
+        CREATE TABLE IF NOT EXISTS attachments (
+            sha256 TEXT PRIMARY KEY,
+            size INTEGER NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Cesta k datům přílohy; přílohy se rozdělují do podadresářů podle prvních dvou znaků hashe
+///
+/// # Errors
+///
+/// Vrací `ServerError::InvalidRequest`, pokud `sha256` není platný hash, aby
+/// z něj nešlo poskládat cestu mimo úložiště.
+pub fn blob_path(upload_dir: &Path, sha256: &str) -> Result<PathBuf, ServerError> {
+    if !is_sha256_hex(sha256) {
+        return Err(ServerError::InvalidRequest(format!(
+            "{} is not a SHA-256 hash",
+            sha256
+        )));
+    }
+    let sha256 = sha256.to_ascii_lowercase();
+    Ok(upload_dir.join(BLOB_DIR).join(&sha256[..2]).join(sha256))
+}
+
+/// Zjistí, zda server obsah se zadaným hashem a velikostí už má uložený.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool s přílohami
+/// * `upload_dir` - Adresář pro nahrané soubory
+/// * `sha256` - SHA-256 obsahu
+/// * `size` - Velikost obsahu v bajtech
+pub async fn contains(
+    pool: &SqlitePool,
+    upload_dir: &Path,
+    sha256: &str,
+    size: u64,
+) -> Result<bool, ServerError> {
+    let row = sqlx::query("SELECT 1 FROM attachments WHERE sha256 = ? AND size = ?")
+        .bind(sha256.to_ascii_lowercase())
+        .bind(size as i64)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.is_some() && fs::try_exists(blob_path(upload_dir, sha256)?).await?)
+}
+
+/// Přesune do úložiště soubor, jehož kontrolní součet už byl ověřen.
+///
+/// Pokud server stejný obsah už má, soubor se jen smaže.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool s přílohami
+/// * `upload_dir` - Adresář pro nahrané soubory
+/// * `source` - Soubor s daty, například dokončený přenos
+/// * `sha256` - SHA-256 obsahu souboru
+/// * `size` - Velikost souboru v bajtech
+pub async fn store_file(
+    pool: &SqlitePool,
+    upload_dir: &Path,
+    source: &Path,
+    sha256: &str,
+    size: u64,
+) -> Result<(), ServerError> {
+    let path = blob_path(upload_dir, sha256)?;
+    if fs::try_exists(&path).await? {
+        fs::remove_file(source).await?;
+    } else {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(source, &path).await?;
+    }
+    record(pool, sha256, size).await
+}
+
+/// Uloží přílohu přijatou celou v jedné zprávě.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool s přílohami
+/// * `upload_dir` - Adresář pro nahrané soubory
+/// * `data` - Obsah přílohy
+///
+/// # Returns
+///
+/// Vrací SHA-256 obsahu, pod kterým je příloha uložená.
+pub async fn store_bytes(
+    pool: &SqlitePool,
+    upload_dir: &Path,
+    data: &[u8],
+) -> Result<String, ServerError> {
+    let sha256 = sha256_bytes(data);
+    let path = blob_path(upload_dir, &sha256)?;
+    if !fs::try_exists(&path).await? {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // Data se zapisují pod dočasným názvem, aby nikdo nečetl napůl zapsanou přílohu
+        let counter = TEMPORARY_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let temporary = path.with_extension(format!("tmp{}", counter));
+        fs::write(&temporary, data).await?;
+        fs::rename(&temporary, &path).await?;
+    }
+    record(pool, &sha256, data.len() as u64).await?;
+    Ok(sha256)
+}
+
+/// Otevře data uložené přílohy pro čtení.
+///
+/// # Arguments
+///
+/// * `upload_dir` - Adresář pro nahrané soubory
+/// * `sha256` - SHA-256 obsahu přílohy
+pub async fn open(upload_dir: &Path, sha256: &str) -> Result<File, ServerError> {
+    Ok(File::open(blob_path(upload_dir, sha256)?).await?)
+}
+
+/// Zaznamená uloženou přílohu; už zaznamenaný obsah se nemění
+async fn record(pool: &SqlitePool, sha256: &str, size: u64) -> Result<(), ServerError> {
+    sqlx::query("INSERT OR IGNORE INTO attachments (sha256, size, created_at) VALUES (?, ?, ?)")
+        .bind(sha256.to_ascii_lowercase())
+        .bind(size as i64)
+        .bind(unix_timestamp())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_attachments_are_stored_once() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_attachments(&pool).await?;
+        let uploads = tempfile::tempdir()?;
+        let upload_dir = uploads.path();
+
+        let sha256 = store_bytes(&pool, upload_dir, b"hello").await?;
+        assert!(contains(&pool, upload_dir, &sha256, 5).await?);
+        assert!(!contains(&pool, upload_dir, &sha256, 6).await?);
+
+        // The same content uploaded as a file replaces nothing and leaves no copy behind
+        let source = upload_dir.join("upload.part");
+        std::fs::write(&source, b"hello")?;
+        store_file(&pool, upload_dir, &source, &sha256, 5).await?;
+        assert!(!source.exists());
+        assert_eq!(store_bytes(&pool, upload_dir, b"hello").await?, sha256);
+
+        let blobs: usize = std::fs::read_dir(upload_dir.join(BLOB_DIR).join(&sha256[..2]))?.count();
+        assert_eq!(blobs, 1);
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM attachments")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(rows, 1);
+
+        let mut data = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut open(upload_dir, &sha256).await?, &mut data)
+            .await?;
+        assert_eq!(data, b"hello");
+
+        // A hash is never turned into a path outside the store
+        assert!(open(upload_dir, "../../etc/passwd").await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use shared::protocol::SYSTEM_SENDER;
+use shared::server_error::ServerError;
+use sqlx::sqlite::SqlitePool;
+use sqlx::Row;
+
+/// Minimální délka hesla při registraci
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Maximální délka uživatelského jména
+const MAX_USERNAME_LENGTH: usize = 32;
+
+/// Identita přihlášeného uživatele, ke které je vázáno spojení
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+}
+
+/// Ověří, že uživatelské jméno obsahuje pouze povolené znaky.
+///
+/// # Arguments
+///
+/// * `username` - Uživatelské jméno k ověření
+fn validate_username(username: &str) -> Result<(), ServerError> {
+    let valid = !username.is_empty()
+        && username.len() <= MAX_USERNAME_LENGTH
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if username == SYSTEM_SENDER {
+        return Err(ServerError::Authentication(format!(
+            "Username '{}' is reserved",
+            username
+        )));
+    }
+    if !valid {
+        return Err(ServerError::Authentication(format!(
+            "Username must be 1-{} characters of letters, digits, '_' or '-'",
+            MAX_USERNAME_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Ověří, že heslo má alespoň minimální délku.
+///
+/// # Arguments
+///
+/// * `password` - Heslo k ověření
+fn validate_password(password: &str) -> Result<(), ServerError> {
+    if password.len() < MIN_PASSWORD_LENGTH {
+        return Err(ServerError::Authentication(format!(
+            "Password must be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Vytvoří solený hash hesla pomocí Argon2.
+///
+/// # Arguments
+///
+/// * `password` - Heslo v čitelné podobě
+fn hash_password(password: &str) -> Result<String, ServerError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| ServerError::Other(format!("Password hashing failed: {}", e)))
+}
+
+/// Ověří heslo proti uloženému hashi.
+///
+/// # Arguments
+///
+/// * `password` - Heslo v čitelné podobě
+/// * `hash` - Hash uložený v databázi
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|parsed| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// Zaregistruje nového uživatele.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `username` - Požadované uživatelské jméno
+/// * `password` - Heslo nového uživatele
+///
+/// # Errors
+///
+/// Vrací `ServerError::Authentication`, pokud je jméno neplatné nebo obsazené, případně je heslo příliš krátké.
+pub async fn register_user(
+    pool: &SqlitePool,
+    username: &str,
+    password: &str,
+) -> Result<User, ServerError> {
+    validate_username(username)?;
+    validate_password(password)?;
+    let password_hash = hash_password(password)?;
+
+    // Jediný příkaz, takže dvě souběžné registrace stejného jména nemohou uspět obě.
+    // Uživatel bez hesla ze starší verze databáze je také obsazený, heslo mu
+    // nastaví jen správce přes `--set-password`.
+    let result = sqlx::query(
+        "INSERT INTO users (username, password_hash) VALUES (?, ?)
+         ON CONFLICT(username) DO NOTHING",
+    )
+    .bind(username)
+    .bind(&password_hash)
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::Authentication(format!(
+            "Username '{}' is already taken",
+            username
+        )));
+    }
+
+    Ok(User {
+        id: result.last_insert_rowid(),
+        username: username.to_string(),
+    })
+}
+
+/// Nastaví existujícímu uživateli nové heslo.
+///
+/// Slouží správci serveru, například k doplnění hesla uživatelům ze starší
+/// verze databáze, kteří heslo nemají a přihlásit se nemohou.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `username` - Uživatelské jméno
+/// * `password` - Nové heslo uživatele
+///
+/// # Errors
+///
+/// Vrací `ServerError::Authentication`, pokud uživatel neexistuje nebo je heslo příliš krátké.
+pub async fn set_password(
+    pool: &SqlitePool,
+    username: &str,
+    password: &str,
+) -> Result<User, ServerError> {
+    validate_password(password)?;
+    let password_hash = hash_password(password)?;
+
+    let row = sqlx::query("UPDATE users SET password_hash = ? WHERE username = ? RETURNING id")
+        .bind(&password_hash)
+        .bind(username)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| ServerError::Authentication(format!("Unknown user '{}'", username)))?;
+
+    Ok(User {
+        id: row.get("id"),
+        username: username.to_string(),
+    })
+}
+
+/// Přihlásí existujícího uživatele.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `username` - Uživatelské jméno
+/// * `password` - Heslo uživatele
+///
+/// # Errors
+///
+/// Vrací `ServerError::Authentication`, pokud uživatel neexistuje nebo heslo nesouhlasí.
+pub async fn login_user(
+    pool: &SqlitePool,
+    username: &str,
+    password: &str,
+) -> Result<User, ServerError> {
+    let row = sqlx::query("SELECT id, password_hash FROM users WHERE username = ?")
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+
+    match row {
+        Some(row) => {
+            let hash: Option<String> = row.get("password_hash");
+            if hash.is_some_and(|hash| verify_password(password, &hash)) {
+                Ok(User {
+                    id: row.get("id"),
+                    username: username.to_string(),
+                })
+            } else {
+                Err(ServerError::Authentication(
+                    "Invalid username or password".to_string(),
+                ))
+            }
+        }
+        None => Err(ServerError::Authentication(
+            "Invalid username or password".to_string(),
+        )),
+    }
+}
+
+/// Najde registrovaného uživatele podle uživatelského jména.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `username` - Hledané uživatelské jméno
+pub async fn find_user(pool: &SqlitePool, username: &str) -> Result<Option<User>, ServerError> {
+    let row = sqlx::query("SELECT id, username FROM users WHERE username = ?")
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.map(|row| User {
+        id: row.get("id"),
+        username: row.get("username"),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_db;
+
+    #[tokio::test]
+    async fn test_register_and_login() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+
+        let registered = register_user(&pool, "alice", "correct horse").await?;
+        let logged_in = login_user(&pool, "alice", "correct horse").await?;
+        assert_eq!(registered, logged_in);
+
+        assert!(login_user(&pool, "alice", "wrong password").await.is_err());
+        assert!(login_user(&pool, "bob", "correct horse").await.is_err());
+        assert!(register_user(&pool, "alice", "another one").await.is_err());
+
+        assert_eq!(find_user(&pool, "alice").await?, Some(registered));
+        assert_eq!(find_user(&pool, "bob").await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_legacy_user_needs_password_from_admin() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+        sqlx::query("INSERT INTO users (username) VALUES ('carol')")
+            .execute(&pool)
+            .await?;
+
+        // Nobody can claim an account without a password by registering it
+        assert!(register_user(&pool, "carol", "first come").await.is_err());
+        assert!(login_user(&pool, "carol", "first come").await.is_err());
+
+        let carol = set_password(&pool, "carol", "from the admin").await?;
+        assert_eq!(login_user(&pool, "carol", "from the admin").await?, carol);
+        assert!(set_password(&pool, "dave", "long enough").await.is_err());
+        assert!(set_password(&pool, "carol", "short").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_registrations() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+
+        let (first, second) = tokio::join!(
+            register_user(&pool, "alice", "first password"),
+            register_user(&pool, "alice", "second password")
+        );
+        assert!(first.is_ok() != second.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_register_validation() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+
+        assert!(register_user(&pool, "", "long enough").await.is_err());
+        assert!(register_user(&pool, "../alice", "long enough")
+            .await
+            .is_err());
+        assert!(register_user(&pool, "alice", "short").await.is_err());
+        assert!(register_user(&pool, SYSTEM_SENDER, "long enough")
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_password_hash_is_salted() -> Result<(), ServerError> {
+        let first = hash_password("secret password")?;
+        let second = hash_password("secret password")?;
+        assert_ne!(first, second);
+        assert!(verify_password("secret password", &first));
+        assert!(!verify_password("other password", &first));
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+use crate::auth::User;
+use shared::protocol::Envelope;
+use shared::server_error::ServerError;
+use shared::{deserialize_sealed_message, serialize_sealed_message, MessageType};
+use sqlx::sqlite::SqlitePool;
+use sqlx::Row;
+
+/// Vytvoří tabulku soukromých zpráv.
+///
+/// Šifrované zprávy mají prázdný `content` a zašifrovaný obsah ve sloupci `sealed`.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+pub async fn init_direct_messages(pool: &SqlitePool) -> Result<(), ServerError> {
+    sqlx::query(
+        "
+        CREATE TABLE IF NOT EXISTS direct_messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            sender_id INTEGER NOT NULL,
+            sender TEXT NOT NULL,
+            recipient_id INTEGER NOT NULL,
+            recipient TEXT NOT NULL,
+            content TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            delivered INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY(sender_id) REFERENCES users(id),
+            FOREIGN KEY(recipient_id) REFERENCES users(id)
+        );
+        CREATE INDEX IF NOT EXISTS idx_direct_messages_pending
+            ON direct_messages(recipient_id, delivered);
+        ",
+    )
+    .execute(pool)
+    .await?;
+    crate::ensure_column(pool, "direct_messages", "sealed", "BLOB").await?;
+
+    Ok(())
+}
+
+/// Chyba pro nešifrovanou soukromou zprávu `MessageType::Direct`, kterou server odmítá.
+pub fn plaintext_rejected() -> ServerError {
+    ServerError::InvalidRequest("Direct messages must be end-to-end encrypted".to_string())
+}
+
+/// Uloží zašifrovanou soukromou zprávu jako dosud nedoručenou.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `envelope` - Obálka se zprávou typu `MessageType::EncryptedDirect`
+/// * `sender` - Přihlášený uživatel, který zprávu odeslal
+/// * `recipient` - Příjemce zprávy
+///
+/// # Returns
+///
+/// Vrací ID uložené zprávy, nebo `None`, pokud obálka neobsahuje soukromou zprávu.
+///
+/// # Errors
+///
+/// Vrací `ServerError::InvalidRequest` pro nešifrovanou zprávu `MessageType::Direct`,
+/// která se neuloží.
+pub async fn store_direct_message(
+    pool: &SqlitePool,
+    envelope: &Envelope,
+    sender: &User,
+    recipient: &User,
+) -> Result<Option<i64>, ServerError> {
+    // Server zná jen zašifrovaný obsah, text soukromé zprávy nikdy nevidí
+    let sealed = match &envelope.payload {
+        MessageType::EncryptedDirect { sealed, .. } => serialize_sealed_message(sealed)?,
+        MessageType::Direct { .. } => return Err(plaintext_rejected()),
+        _ => return Ok(None),
+    };
+
+    let result = sqlx::query(
+        "INSERT INTO direct_messages
+            (sender_id, sender, recipient_id, recipient, content, sealed, timestamp)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(sender.id)
+    .bind(&sender.username)
+    .bind(recipient.id)
+    .bind(&recipient.username)
+    .bind("")
+    .bind(sealed)
+    .bind(envelope.timestamp)
+    .execute(pool)
+    .await?;
+
+    Ok(Some(result.last_insert_rowid()))
+}
+
+/// Načte nedoručené soukromé zprávy uživatele seřazené od nejstarší.
+///
+/// Nešifrované zprávy uložené dřívějšími verzemi serveru se doručí jako
+/// `MessageType::Direct`.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `recipient` - Uživatel, kterému jsou zprávy určeny
+/// * `version` - Verze protokolu, se kterou se obálky vytvoří
+pub async fn load_pending(
+    pool: &SqlitePool,
+    recipient: &User,
+    version: u16,
+) -> Result<Vec<Envelope>, ServerError> {
+    let rows = sqlx::query(
+        "SELECT id, sender, content, sealed, timestamp FROM direct_messages
+         WHERE recipient_id = ? AND delivered = 0 ORDER BY id ASC",
+    )
+    .bind(recipient.id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut pending = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let recipient = recipient.username.clone();
+        let payload = match row.get::<Option<Vec<u8>>, _>("sealed") {
+            Some(sealed) => MessageType::EncryptedDirect {
+                recipient,
+                sealed: deserialize_sealed_message(&sealed)?,
+            },
+            None => MessageType::Direct {
+                recipient,
+                text: row.get("content"),
+            },
+        };
+        pending.push(Envelope {
+            version,
+            id: row.get("id"),
+            sender: row.get("sender"),
+            timestamp: row.get("timestamp"),
+            room: String::new(),
+            payload,
+        });
+    }
+    Ok(pending)
+}
+
+/// Označí soukromou zprávu jako doručenou.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `id` - ID doručené zprávy
+pub async fn mark_delivered(pool: &SqlitePool, id: i64) -> Result<(), ServerError> {
+    sqlx::query("UPDATE direct_messages SET delivered = 1 WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_db;
+    use shared::SealedMessage;
+
+    #[tokio::test]
+    async fn test_pending_direct_messages() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+        sqlx::query("INSERT INTO users (id, username) VALUES (1, 'alice'), (2, 'bob')")
+            .execute(&pool)
+            .await?;
+        let alice = User {
+            id: 1,
+            username: "alice".to_string(),
+        };
+        let bob = User {
+            id: 2,
+            username: "bob".to_string(),
+        };
+
+        let direct = |text: &str| Envelope {
+            sender: "alice".to_string(),
+            ..Envelope::new(
+                MessageType::Direct {
+                    recipient: "bob".to_string(),
+                    text: text.to_string(),
+                },
+                "",
+            )
+        };
+        let encrypted = |byte: u8| Envelope {
+            sender: "alice".to_string(),
+            ..Envelope::new(
+                MessageType::EncryptedDirect {
+                    recipient: "bob".to_string(),
+                    sealed: SealedMessage {
+                        sender_key: [1; 32],
+                        recipient_key: [2; 32],
+                        nonce: [3; 12],
+                        ciphertext: vec![byte; 20],
+                    },
+                },
+                "",
+            )
+        };
+
+        // Plaintext is refused; rows written by older servers are still delivered
+        assert!(matches!(
+            store_direct_message(&pool, &direct("secret"), &alice, &bob).await,
+            Err(ServerError::InvalidRequest(_))
+        ));
+        let first = sqlx::query(
+            "INSERT INTO direct_messages
+                (sender_id, sender, recipient_id, recipient, content, timestamp)
+             VALUES (1, 'alice', 2, 'bob', 'first', ?)",
+        )
+        .bind(direct("first").timestamp)
+        .execute(&pool)
+        .await?
+        .last_insert_rowid();
+        let second = store_direct_message(&pool, &encrypted(4), &alice, &bob).await?;
+        let third = store_direct_message(&pool, &encrypted(5), &alice, &bob).await?;
+        assert!(second.is_some() && third.is_some());
+
+        let text = Envelope::new(MessageType::Text("public".to_string()), "general");
+        assert_eq!(
+            store_direct_message(&pool, &text, &alice, &bob).await?,
+            None
+        );
+
+        assert!(load_pending(&pool, &alice, 1).await?.is_empty());
+
+        let pending = load_pending(&pool, &bob, 1).await?;
+        assert_eq!(pending.len(), 3);
+        assert_eq!(pending[0].id, first);
+        assert_eq!(pending[0].sender, "alice");
+        assert_eq!(pending[0].payload, direct("first").payload);
+
+        mark_delivered(&pool, pending[0].id).await?;
+        let pending = load_pending(&pool, &bob, 1).await?;
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].id, second.unwrap());
+
+        // Only the ciphertext is stored and it comes back unchanged
+        assert_eq!(pending[0].payload, encrypted(4).payload);
+        assert_eq!(pending[1].payload, encrypted(5).payload);
+        let content: String = sqlx::query("SELECT content FROM direct_messages WHERE id = ?")
+            .bind(third.unwrap())
+            .fetch_one(&pool)
+            .await?
+            .get("content");
+        assert!(content.is_empty());
+
+        Ok(())
+    }
+}
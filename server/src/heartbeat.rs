@@ -0,0 +1,74 @@
+use crate::queue::OutboundQueue;
+use shared::protocol::Envelope;
+use shared::MessageType;
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, Duration, Instant};
+
+/// Úloha, která klientovi v pravidelných intervalech posílá `MessageType::Ping`.
+///
+/// Úloha se ukončí při zahození této struktury, takže nedrží odchozí frontu
+/// klienta otevřenou déle než jeho spojení.
+pub struct Heartbeat {
+    task: JoinHandle<()>,
+}
+
+impl Heartbeat {
+    /// Spustí posílání pingů.
+    ///
+    /// # Arguments
+    ///
+    /// * `outbound` - Odchozí fronta klienta
+    /// * `period` - Interval mezi dvěma pingy
+    pub fn spawn(outbound: OutboundQueue, period: Duration) -> Self {
+        let task = tokio::spawn(async move {
+            let mut ticker = interval_at(Instant::now() + period, period);
+            loop {
+                ticker.tick().await;
+                // Zavřená fronta znamená konec spojení, pingy už nemá kdo číst
+                if outbound
+                    .send(&Envelope::system(MessageType::Ping, ""))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        Heartbeat { task }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{outbound_queue, OverflowPolicy};
+    use shared::protocol::deserialize_envelope;
+
+    #[tokio::test]
+    async fn test_heartbeat_pings_until_dropped() {
+        let (outbound, mut receiver) = outbound_queue(8, OverflowPolicy::DropOldest);
+        let heartbeat = Heartbeat::spawn(outbound, Duration::from_millis(10));
+
+        for _ in 0..2 {
+            let frame = receiver.recv().await.expect("a ping should be queued");
+            assert_eq!(
+                deserialize_envelope(&frame).unwrap().payload,
+                MessageType::Ping
+            );
+        }
+
+        // Stopping the heartbeat releases its handle, so the queue drains and ends
+        drop(heartbeat);
+        while let Some(frame) = receiver.recv().await {
+            assert_eq!(
+                deserialize_envelope(&frame).unwrap().payload,
+                MessageType::Ping
+            );
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::auth::User;
+use shared::protocol::unix_timestamp;
+use shared::server_error::ServerError;
+use sqlx::sqlite::SqlitePool;
+use sqlx::Row;
+
+/// Vytvoří tabulku veřejných klíčů pro šifrované soukromé zprávy.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+pub async fn init_public_keys(pool: &SqlitePool) -> Result<(), ServerError> {
+    sqlx::query(
+        "
+        CREATE TABLE IF NOT EXISTS public_keys (
+            user_id INTEGER PRIMARY KEY,
+            public_key BLOB NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY(user_id) REFERENCES users(id)
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Uloží veřejný klíč uživatele.
+///
+/// Účet má jediný klíč, tedy jediné zařízení, které umí soukromé zprávy
+/// přečíst. Jiný klíč by zprávy pro dosavadní zařízení znečitelnil, proto
+/// se dosavadní klíč nahradí jen na výslovnou žádost.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `user` - Přihlášený uživatel, který klíč zveřejnil
+/// * `key` - Veřejný klíč X25519
+/// * `replace` - Zda smí nahradit jiný dosud zveřejněný klíč
+///
+/// # Returns
+///
+/// Vrací `true`, pokud se klíč uživatele tímto změnil.
+///
+/// # Errors
+///
+/// Vrací `ServerError::InvalidRequest`, pokud má uživatel zveřejněný jiný klíč
+/// a `replace` není nastaveno.
+pub async fn publish_key(
+    pool: &SqlitePool,
+    user: &User,
+    key: &[u8; 32],
+    replace: bool,
+) -> Result<bool, ServerError> {
+    match find_key(pool, &user.username).await? {
+        Some(previous) if previous == *key => return Ok(false),
+        Some(_) if !replace => {
+            return Err(ServerError::InvalidRequest(
+                "Another device already published an encryption key for this account; \
+                 direct messages can only be read there. Start the client with --replace-key \
+                 to use this device instead"
+                    .to_string(),
+            ))
+        }
+        _ => {}
+    }
+
+    sqlx::query(
+        "INSERT INTO public_keys (user_id, public_key, updated_at) VALUES (?, ?, ?)
+         ON CONFLICT(user_id) DO UPDATE SET
+            public_key = excluded.public_key,
+            updated_at = excluded.updated_at",
+    )
+    .bind(user.id)
+    .bind(key.as_slice())
+    .bind(unix_timestamp())
+    .execute(pool)
+    .await?;
+
+    Ok(true)
+}
+
+/// Najde veřejný klíč uživatele.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `username` - Uživatelské jméno
+///
+/// # Returns
+///
+/// Vrací `None`, pokud uživatel neexistuje nebo klíč nezveřejnil.
+pub async fn find_key(pool: &SqlitePool, username: &str) -> Result<Option<[u8; 32]>, ServerError> {
+    let row = sqlx::query(
+        "SELECT public_key FROM public_keys
+         JOIN users ON users.id = public_keys.user_id
+         WHERE users.username = ?",
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|row| row.get::<Vec<u8>, _>("public_key").try_into().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_db;
+
+    #[tokio::test]
+    async fn test_publish_and_find_key() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+        sqlx::query("INSERT INTO users (id, username) VALUES (1, 'alice')")
+            .execute(&pool)
+            .await?;
+        let alice = User {
+            id: 1,
+            username: "alice".to_string(),
+        };
+
+        assert_eq!(find_key(&pool, "alice").await?, None);
+        assert_eq!(find_key(&pool, "nobody").await?, None);
+
+        assert!(publish_key(&pool, &alice, &[1; 32], false).await?);
+        assert!(!publish_key(&pool, &alice, &[1; 32], false).await?);
+        assert!(!publish_key(&pool, &alice, &[1; 32], true).await?);
+        assert_eq!(find_key(&pool, "alice").await?, Some([1; 32]));
+
+        // Another device must not silently take over the account's key
+        assert!(matches!(
+            publish_key(&pool, &alice, &[2; 32], false).await,
+            Err(ServerError::InvalidRequest(_))
+        ));
+        assert_eq!(find_key(&pool, "alice").await?, Some([1; 32]));
+
+        // Unless the replacement is explicitly requested
+        assert!(publish_key(&pool, &alice, &[2; 32], true).await?);
+        assert_eq!(find_key(&pool, "alice").await?, Some([2; 32]));
+        Ok(())
+    }
+}
@@ -1,15 +1,43 @@
+mod attachments;
+mod auth;
+mod direct;
+mod heartbeat;
+mod keys;
+mod queue;
+mod rooms;
+mod search;
+mod transfer;
+
 use anyhow::Result;
+use auth::User;
 use clap::Parser;
 use dotenv::dotenv;
+use heartbeat::Heartbeat;
+use queue::{OutboundQueue, OverflowPolicy};
+use shared::frame::{FrameReader, FrameWriter, DEFAULT_MAX_FRAME_SIZE};
+use shared::image::ImageFormat;
+use shared::protocol::{
+    deserialize_envelope, deserialize_handshake, negotiate_version, serialize_envelope,
+    serialize_handshake_response, unix_timestamp, Envelope, HandshakeResponse,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use shared::server_error::ServerError;
-use shared::{deserialize_message, serialize_message, MessageType};
+use shared::tls::{self, BoxedStream, TlsAcceptor};
+use shared::{
+    deserialize_auth_request, AuthRequest, HistoryEntry, MessageType, OnlineUser, RoomInfo,
+    AUTH_SUCCESS, DEFAULT_ROOM, FILE_CHUNK_SIZE, SEARCH_PAGE_SIZE,
+};
 use sqlx::sqlite::SqlitePool;
-use std::collections::HashMap;
+use sqlx::Row;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, Mutex};
 use tokio::task;
+use tokio::time::{timeout, Duration};
+use transfer::{Processed, StoredTransfer, Transfers};
 
 /// Struktura pro uchování argumentů příkazového řádku
 #[derive(Parser, Debug)]
@@ -23,68 +51,939 @@ struct Args {
 
     #[arg(short, long, default_value = "sqlite:chat.db")]
     database_url: String,
+
+    /// Počet posledních zpráv, které server pošle nově připojenému klientovi
+    #[arg(long, default_value = "20")]
+    history: u32,
+
+    /// Maximální velikost jednoho rámce v bajtech
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+
+    /// Adresář, do kterého se ukládají soubory přenášené po částech
+    #[arg(long, default_value = "uploads")]
+    upload_dir: PathBuf,
+
+    /// Největší soubor v bajtech, který server od klienta přijme
+    #[arg(long, default_value_t = transfer::DEFAULT_MAX_FILE_SIZE)]
+    max_file_size: u64,
+
+    /// Maximální počet zpráv čekajících na odeslání jednomu klientovi
+    #[arg(long, default_value = "256")]
+    outbound_queue: usize,
+
+    /// Co udělat s klientem, který nestíhá přijímat zprávy
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::DropOldest)]
+    slow_client_policy: OverflowPolicy,
+
+    /// Interval v sekundách, po kterém server klientovi pošle ping
+    #[arg(long, default_value = "30")]
+    heartbeat_interval: u64,
+
+    /// Počet sekund bez jediné zprávy od klienta, po kterém server klienta odpojí
+    #[arg(long, default_value = "90")]
+    heartbeat_timeout: u64,
+
+    /// PEM soubor s certifikátem serveru; spolu s `--tls-key` zapne TLS
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM soubor se soukromým klíčem k certifikátu serveru
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Nastaví uživateli heslo z `CHAT_PASSWORD` a skončí, server se nespustí
+    #[arg(long, value_name = "USERNAME")]
+    set_password: Option<String>,
+
+    /// Nové heslo pro `--set-password`
+    #[arg(long, env = "CHAT_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
 }
 
-/// Funkce pro zpracování přijatých zpráv od klienta
+/// Nastavení serveru sdílené všemi spojeními
+#[derive(Debug, Clone)]
+struct ServerConfig {
+    /// Počet posledních zpráv odeslaných nově připojeným klientům
+    history_limit: u32,
+    /// Maximální velikost jednoho rámce v bajtech
+    max_frame_size: usize,
+    /// Adresář se soubory přenášenými po částech
+    upload_dir: PathBuf,
+    /// Největší soubor v bajtech, který server přijme
+    max_file_size: u64,
+    /// Maximální počet zpráv čekajících na odeslání jednomu klientovi
+    outbound_queue: usize,
+    /// Chování při zaplnění odchozí fronty klienta
+    slow_client_policy: OverflowPolicy,
+    /// Interval mezi pingy posílanými klientům
+    heartbeat_interval: Duration,
+    /// Doba bez zprávy od klienta, po které se klient odpojí
+    heartbeat_timeout: Duration,
+}
+
+impl From<&Args> for ServerConfig {
+    fn from(args: &Args) -> Self {
+        ServerConfig {
+            history_limit: args.history,
+            max_frame_size: args.max_frame_size,
+            upload_dir: args.upload_dir.clone(),
+            max_file_size: args.max_file_size,
+            outbound_queue: args.outbound_queue,
+            slow_client_policy: args.slow_client_policy,
+            heartbeat_interval: Duration::from_secs(args.heartbeat_interval.max(1)),
+            heartbeat_timeout: Duration::from_secs(args.heartbeat_timeout.max(1)),
+        }
+    }
+}
+
+/// Nejvyšší počet zmeškaných zpráv, které server pošle na jednu žádost `MessageType::FetchHistory`
+const MISSED_HISTORY_LIMIT: u32 = 1000;
+
+/// Jak dlouho se nejvýše čeká na místo v odchozí frontě příjemce soukromé zprávy
+const DIRECT_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Čtecí část spojení s klientem rozdělená na rámce
+type ClientReader = FrameReader<tokio::io::ReadHalf<BoxedStream>>;
+
+/// Zapisovací část spojení s klientem rozdělená na rámce
+type ClientWriter = FrameWriter<tokio::io::WriteHalf<BoxedStream>>;
+
+/// Přihlášený klient, kterému server doručuje zprávy
+struct ConnectedClient {
+    /// Odchozí fronta, ze které zprávy odesílá zapisovací úloha klienta
+    outbound: OutboundQueue,
+    username: String,
+    /// Místnost, ve které se klient právě nachází; prázdná, dokud do žádné nevstoupil
+    room: String,
+    /// Místnosti, do kterých klient během spojení vstoupil a neopustil je;
+    /// o nových zprávách v nich dostává upozornění
+    visited: HashSet<String>,
+}
+
+/// Sdílená mapa připojených klientů podle jejich adresy
+type Clients = Arc<Mutex<HashMap<std::net::SocketAddr, ConnectedClient>>>;
+
+/// Kanál, kterým spojení předávají zprávy k uložení a doručení
+type MessageSender = mpsc::Sender<(Envelope, std::net::SocketAddr, User)>;
+
+/// Přečte rámec, který musí od klienta dorazit, než bude spojení navázáno
+///
+/// # Arguments
+///
+/// * `reader` - Čtecí část spojení s klientem
+async fn read_required_frame(reader: &mut ClientReader) -> Result<Vec<u8>, ServerError> {
+    reader
+        .read_frame()
+        .await?
+        .ok_or_else(|| ServerError::Other("Connection closed during handshake".to_string()))
+}
+
+/// Dohodne s klientem verzi protokolu.
 ///
 /// # Arguments
 ///
 /// * `reader` - Asynchronní čtecí část TCP spojení
 /// * `writer` - Asynchronní zapisovací část TCP spojení
-/// * `sender` - Kanál pro odesílání zpráv
+///
+/// # Errors
+///
+/// Vrací `ServerError::IncompatibleProtocol`, pokud klient neposlal platný handshake
+/// nebo nepodporuje žádnou verzi protokolu společnou se serverem.
+async fn negotiate_protocol(
+    reader: &mut ClientReader,
+    writer: &mut ClientWriter,
+) -> Result<u16, ServerError> {
+    let buffer = read_required_frame(reader).await?;
+    let negotiated = deserialize_handshake(&buffer)
+        .ok()
+        .map(|handshake| (negotiate_version(&handshake), handshake));
+
+    let (response, result) = match negotiated {
+        Some((Some(version), _)) => (HandshakeResponse::Accepted { version }, Ok(version)),
+        Some((None, handshake)) => {
+            let reason = format!(
+                "client supports protocol versions {}-{}, server supports {}-{}",
+                handshake.min_version, handshake.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            );
+            (
+                HandshakeResponse::Rejected {
+                    version: PROTOCOL_VERSION,
+                    min_version: MIN_PROTOCOL_VERSION,
+                    reason: reason.clone(),
+                },
+                Err(ServerError::IncompatibleProtocol(reason)),
+            )
+        }
+        None => {
+            let reason = "client did not send a valid handshake".to_string();
+            (
+                HandshakeResponse::Rejected {
+                    version: PROTOCOL_VERSION,
+                    min_version: MIN_PROTOCOL_VERSION,
+                    reason: reason.clone(),
+                },
+                Err(ServerError::IncompatibleProtocol(reason)),
+            )
+        }
+    };
+
+    writer
+        .write_frame(&serialize_handshake_response(&response)?)
+        .await?;
+    result
+}
+
+/// Ověří klienta podle jeho přihlašovacího nebo registračního požadavku.
+///
+/// # Arguments
+///
+/// * `reader` - Asynchronní čtecí část TCP spojení
+/// * `writer` - Asynchronní zapisovací část TCP spojení
+/// * `pool` - Databázový pool s tabulkou uživatelů
+async fn authenticate(
+    reader: &mut ClientReader,
+    writer: &mut ClientWriter,
+    pool: &SqlitePool,
+) -> Result<User, ServerError> {
+    let buffer = read_required_frame(reader).await?;
+    let request = deserialize_auth_request(&buffer)
+        .map_err(|_| ServerError::Authentication("Invalid login request".to_string()));
+
+    let result = match request {
+        Ok(AuthRequest::Login { username, password }) => {
+            auth::login_user(pool, &username, &password).await
+        }
+        Ok(AuthRequest::Register { username, password }) => {
+            auth::register_user(pool, &username, &password).await
+        }
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(user) => {
+            writer.write_frame(AUTH_SUCCESS.as_bytes()).await?;
+            Ok(user)
+        }
+        Err(err) => {
+            // Důvod posíláme klientovi jen u chyb přihlášení, interní chyby nezveřejňujeme
+            let reason = match &err {
+                ServerError::Authentication(reason) => reason.clone(),
+                _ => "Internal server error".to_string(),
+            };
+            writer
+                .write_frame(format!("FAIL: {}", reason).as_bytes())
+                .await?;
+            Err(err)
+        }
+    }
+}
+
+/// Přesune klienta do místnosti a pošle mu její historii.
+///
+/// Ostatním v původní i nové místnosti se oznámí odchod a příchod uživatele.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool s místnostmi a zprávami
+/// * `clients` - Mapa připojených klientů
+/// * `outbound` - Odchozí fronta klienta
+/// * `addr` - Adresa klienta
+/// * `user` - Přihlášený uživatel
+/// * `room` - Název místnosti
+/// * `history_limit` - Počet posledních zpráv místnosti, které klient dostane
+async fn join_room(
+    pool: &SqlitePool,
+    clients: &Clients,
+    outbound: &OutboundQueue,
+    addr: std::net::SocketAddr,
+    user: &User,
+    room: &str,
+    history_limit: u32,
+) -> Result<(), ServerError> {
+    rooms::ensure_room(pool, room, user.id, unix_timestamp()).await?;
+    let previous = match clients.lock().await.get_mut(&addr) {
+        Some(client) => {
+            client.visited.insert(room.to_string());
+            std::mem::replace(&mut client.room, room.to_string())
+        }
+        None => String::new(),
+    };
+
+    let joined = Envelope::system(MessageType::RoomJoined(room.to_string()), room);
+    outbound.send(&joined)?;
+
+    // Odeslání historie, aby klient viděl i zprávy odeslané před jeho vstupem do místnosti
+    let history = load_history(pool, room, history_limit).await?;
+    if !history.is_empty() {
+        let envelope = Envelope::system(MessageType::History(history), room);
+        outbound.send(&envelope)?;
+    }
+
+    if previous != room {
+        let username = &user.username;
+        if !previous.is_empty() {
+            let left = MessageType::UserLeft(username.clone());
+            announce_presence(clients, left, &previous, addr, username).await;
+        }
+        let joined = MessageType::UserJoined(username.clone());
+        announce_presence(clients, joined, room, addr, username).await;
+    }
+    Ok(())
+}
+
+/// Oznámí ostatním klientům v místnosti příchod nebo odchod uživatele.
+///
+/// Pokud je uživatel v místnosti připojen ještě z jiného spojení, nic se neoznamuje.
+///
+/// # Arguments
+///
+/// * `clients` - Mapa připojených klientů
+/// * `payload` - Zpráva `MessageType::UserJoined` nebo `MessageType::UserLeft`
+/// * `room` - Místnost, které se změna týká
+/// * `addr` - Adresa spojení, které do místnosti vstoupilo nebo ji opustilo
+/// * `username` - Uživatelské jméno
+async fn announce_presence(
+    clients: &Clients,
+    payload: MessageType,
+    room: &str,
+    addr: std::net::SocketAddr,
+    username: &str,
+) {
+    let other_session = clients.lock().await.iter().any(|(client_addr, client)| {
+        *client_addr != addr && client.username == username && client.room == room
+    });
+    if !other_session {
+        broadcast_message(clients, &Envelope::system(payload, room), addr).await;
+    }
+}
+
+/// Odebere klienta z mapy připojených klientů a oznámí jeho odchod.
+///
+/// Volá se při každém ukončení spojení, ať už skončilo chybou, nebo ne.
+///
+/// # Arguments
+///
+/// * `clients` - Mapa připojených klientů
+/// * `addr` - Adresa odpojeného klienta
+async fn disconnect_client(clients: &Clients, addr: std::net::SocketAddr) {
+    let removed = {
+        let mut clients = clients.lock().await;
+        let removed = clients.remove(&addr);
+        if removed.is_some() {
+            println!(
+                "Client {} disconnected, connected clients: {}",
+                addr,
+                clients.len()
+            );
+        }
+        removed
+    };
+
+    if let Some(client) = removed {
+        if !client.room.is_empty() {
+            let left = MessageType::UserLeft(client.username.clone());
+            announce_presence(clients, left, &client.room, addr, &client.username).await;
+        }
+    }
+}
+
+/// Místnosti, jejichž zprávy a přílohy klient smí číst.
+///
+/// Jsou to místnost, ve které klient je, a místnosti, do kterých během
+/// spojení vstoupil a neopustil je. Stejné pravidlo platí pro stahování
+/// příloh i pro hledání.
+///
+/// # Arguments
+///
+/// * `clients` - Mapa připojených klientů
+/// * `addr` - Adresa klienta
+async fn joined_rooms(clients: &Clients, addr: std::net::SocketAddr) -> Vec<String> {
+    clients
+        .lock()
+        .await
+        .get(&addr)
+        .map(|client| {
+            std::iter::once(&client.room)
+                .chain(client.visited.iter().filter(|room| **room != client.room))
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Zjistí, zda klient smí stáhnout přílohu z dané místnosti, viz `joined_rooms`.
+///
+/// # Arguments
+///
+/// * `clients` - Mapa připojených klientů
+/// * `addr` - Adresa klienta, který o přílohu žádá
+/// * `room` - Místnost, do které byla příloha poslána
+async fn has_joined(clients: &Clients, addr: std::net::SocketAddr, room: &str) -> bool {
+    joined_rooms(clients, addr)
+        .await
+        .iter()
+        .any(|joined| joined == room)
+}
+
+/// Pošle klientovi seznam připojených uživatelů a místností, ve kterých jsou.
+///
+/// # Arguments
+///
+/// * `clients` - Mapa připojených klientů
+/// * `outbound` - Odchozí fronta klienta
+/// * `room` - Místnost, ve které se klient nachází
+async fn send_user_list(
+    clients: &Clients,
+    outbound: &OutboundQueue,
+    room: &str,
+) -> Result<(), ServerError> {
+    let mut users: Vec<OnlineUser> = clients
+        .lock()
+        .await
+        .values()
+        .filter(|client| !client.room.is_empty())
+        .map(|client| OnlineUser {
+            username: client.username.clone(),
+            room: client.room.clone(),
+        })
+        .collect();
+    // Uživatel připojený z více míst se v jedné místnosti vypíše jen jednou
+    users.sort();
+    users.dedup();
+
+    outbound.send(&Envelope::system(MessageType::UserList(users), room))
+}
+
+/// Pošle klientovi seznam místností s počtem připojených uživatelů.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool s místnostmi
+/// * `clients` - Mapa připojených klientů
+/// * `outbound` - Odchozí fronta klienta
+/// * `room` - Místnost, ve které se klient nachází
+async fn send_room_list(
+    pool: &SqlitePool,
+    clients: &Clients,
+    outbound: &OutboundQueue,
+    room: &str,
+) -> Result<(), ServerError> {
+    let names = rooms::list_rooms(pool).await?;
+    let list = {
+        let clients = clients.lock().await;
+        names
+            .into_iter()
+            .map(|name| {
+                let online = clients.values().filter(|c| c.room == name).count() as u32;
+                RoomInfo { name, online }
+            })
+            .collect()
+    };
+
+    let envelope = Envelope::system(MessageType::RoomList(list), room);
+    outbound.send(&envelope)
+}
+
+/// Doplní metadata obálky přijaté od klienta.
+///
+/// Metadata obálky určuje server, klientovi v nich nevěříme.
+///
+/// # Arguments
+///
+/// * `envelope` - Obálka přijatá od klienta
+/// * `version` - Verze protokolu dohodnutá s klientem
+/// * `user` - Přihlášený uživatel, který zprávu odeslal
+/// * `room` - Místnost, do které zpráva patří
+fn prepare_envelope(mut envelope: Envelope, version: u16, user: &User, room: &str) -> Envelope {
+    envelope.version = version;
+    envelope.id = 0;
+    envelope.sender = user.username.clone();
+    envelope.timestamp = unix_timestamp();
+    envelope.room = room.to_string();
+    envelope
+}
+
+/// Předá zprávu k uložení a doručení.
+///
+/// # Arguments
+///
+/// * `sender` - Kanál pro odesílání zpráv spolu s identitou odesílatele
+/// * `envelope` - Obálka s metadaty doplněnými serverem
+/// * `addr` - Adresa klienta
+/// * `user` - Přihlášený uživatel, který zprávu odeslal
+async fn forward_message(
+    sender: &MessageSender,
+    envelope: Envelope,
+    addr: std::net::SocketAddr,
+    user: &User,
+) -> Result<(), ServerError> {
+    sender
+        .send((envelope, addr, user.clone()))
+        .await
+        .map_err(|e| ServerError::Other(e.to_string()))
+}
+
+/// Zpracuje zprávu přenosu souboru po částech.
+///
+/// Na nabídku souboru server odesílateli odpoví pozicí, od které má posílat data.
+/// Příjemci dostanou až po dokončení přenosu odkaz na uložený soubor, data si
+/// vyžádají sami; chybný přenos se zruší.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool s přenosy a zprávami
+/// * `sender` - Kanál pro odesílání zpráv spolu s identitou odesílatele
+/// * `outbound` - Odchozí fronta odesílatele
+/// * `transfers` - Rozpracované přenosy tohoto spojení
+/// * `envelope` - Obálka s metadaty doplněnými serverem
 /// * `addr` - Adresa klienta
+/// * `user` - Přihlášený uživatel, který soubor odesílá
+async fn handle_transfer(
+    pool: &SqlitePool,
+    sender: &MessageSender,
+    outbound: &OutboundQueue,
+    transfers: &mut Transfers,
+    mut envelope: Envelope,
+    addr: std::net::SocketAddr,
+    user: &User,
+) -> Result<(), ServerError> {
+    let local_id = transfer::transfer_id(&envelope.payload);
+    match transfers.process(pool, &mut envelope, user).await {
+        Ok(Processed::Accepted { offset }) => {
+            let accepted = MessageType::FileAccepted {
+                transfer_id: local_id.unwrap_or_default(),
+                offset,
+            };
+            let reply = Envelope::system(accepted, &envelope.room);
+            outbound.send(&reply)
+        }
+        Ok(Processed::Pending) => Ok(()),
+        Ok(Processed::Completed(attachment)) => {
+            forward_message(sender, attachment, addr, user).await
+        }
+        Err(err) => {
+            if let Some(id) = local_id {
+                transfers.abort(pool, id).await?;
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Pošle klientovi uložený soubor od zadané pozice.
+///
+/// Klient nejdřív dostane nabídku souboru a potom data, která server zatím má;
+/// u dokončeného přenosu následuje i zpráva o jeho konci. Vyžádaná data se
+/// nezahazují, při plné odchozí frontě se čeká, až klient část zpráv přijme.
+///
+/// # Arguments
+///
+/// * `outbound` - Odchozí fronta klienta
+/// * `upload_dir` - Adresář s přijatými soubory
+/// * `transfer` - Požadovaný přenos
+/// * `offset` - Pozice, od které klient data potřebuje
+/// * `version` - Verze protokolu dohodnutá s klientem
+async fn serve_transfer(
+    outbound: &OutboundQueue,
+    upload_dir: &Path,
+    transfer: StoredTransfer,
+    mut offset: u64,
+    version: u16,
+) -> Result<(), ServerError> {
+    let offer = transfer.offer(version);
+    outbound.send_wait(&offer).await?;
+
+    let mut file = transfer::open_data(upload_dir, &transfer).await?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut buffer = vec![0u8; FILE_CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+
+        let chunk = MessageType::FileChunk {
+            transfer_id: transfer.id as u64,
+            offset,
+            data: buffer[..read].to_vec(),
+        };
+        let envelope = Envelope {
+            payload: chunk,
+            ..offer.clone()
+        };
+        outbound.send_wait(&envelope).await?;
+        offset += read as u64;
+    }
+
+    if transfer.completed {
+        let complete = MessageType::FileComplete {
+            transfer_id: transfer.id as u64,
+        };
+        let envelope = Envelope {
+            payload: complete,
+            ..offer
+        };
+        outbound.send_wait(&envelope).await?;
+    }
+    Ok(())
+}
+
+/// Pošle klientovi soukromé zprávy, které mu přišly, když nebyl připojen.
+///
+/// Zpráva se označí za doručenou až poté, co ji zapisovací úloha zapíše do
+/// spojení; pokud se klient odpojí dřív, dostane ji po příštím přihlášení.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool se soukromými zprávami
+/// * `outbound` - Odchozí fronta klienta
+/// * `pending` - Nedoručené zprávy načtené při registraci klienta
+async fn deliver_pending_direct_messages(
+    pool: &SqlitePool,
+    outbound: &OutboundQueue,
+    pending: Vec<Envelope>,
+) -> Result<(), ServerError> {
+    let mut written = Vec::with_capacity(pending.len());
+    for envelope in &pending {
+        let confirmation = outbound.send_timeout(envelope, DIRECT_SEND_TIMEOUT).await?;
+        written.push((envelope.id, confirmation));
+    }
+
+    let pool = pool.clone();
+    task::spawn(async move {
+        for (id, confirmation) in written {
+            if confirmation.await.is_err() {
+                break;
+            }
+            if let Err(e) = direct::mark_delivered(&pool, id).await {
+                println!("Error marking direct message {} delivered: {:?}", id, e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Popíše zprávu pro výpis serveru druhem a velikostí obsahu.
+///
+/// Obsah zprávy se nevypisuje: data souborů by zahltila výpis a soukromé
+/// zprávy do výpisu nepatří ani zašifrované.
+///
+/// # Arguments
+///
+/// * `payload` - Obsah zprávy
+fn describe_payload(payload: &MessageType) -> String {
+    let (kind, size) = match payload {
+        MessageType::Text(text) => ("text", text.len()),
+        MessageType::Image { data, .. } => ("image", data.len()),
+        MessageType::File(_, data) => ("file", data.len()),
+        MessageType::FileChunk { data, .. } => ("file chunk", data.len()),
+        MessageType::EncryptedDirect { sealed, .. } => {
+            ("encrypted direct message", sealed.ciphertext.len())
+        }
+        MessageType::Attachment { size, .. } => ("attachment", *size as usize),
+        _ => return "control message".to_string(),
+    };
+    format!("{} ({} bytes)", kind, size)
+}
+
+/// Funkce pro zpracování přijatých zpráv od klienta
+///
+/// Klient je zařazen mezi příjemce zpráv až po úspěšném handshaku a přihlášení.
+/// Potom dostane vlastní odchozí frontu a zapisovací úlohu, takže pomalý klient
+/// nezdržuje doručování ostatním. Požadavky na místnosti vyřizuje přímo, ostatní
+/// zprávy předává k rozeslání ostatním klientům v jeho aktuální místnosti.
+///
+/// # Arguments
+///
+/// * `reader` - Čtecí část spojení s klientem
+/// * `writer` - Zapisovací část spojení s klientem, po přihlášení ji převezme zapisovací úloha
+/// * `clients` - Mapa připojených klientů, do které se klient po přihlášení přidá
+/// * `sender` - Kanál pro odesílání zpráv spolu s identitou odesílatele
+/// * `addr` - Adresa klienta
+/// * `pool` - Databázový pool pro ověření uživatele, místnosti a historii
+/// * `config` - Nastavení serveru
 async fn handle_client(
-    reader: Arc<Mutex<tokio::net::tcp::OwnedReadHalf>>,
-    writer: Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
-    sender: mpsc::Sender<(MessageType, std::net::SocketAddr)>,
+    reader: Arc<Mutex<ClientReader>>,
+    mut writer: ClientWriter,
+    clients: Clients,
+    sender: MessageSender,
     addr: std::net::SocketAddr,
-    _pool: SqlitePool,
+    pool: SqlitePool,
+    config: ServerConfig,
 ) -> Result<(), ServerError> {
-    // Ověření klienta
-    {
+    let (version, user) = {
         let mut reader = reader.lock().await;
-        let mut writer = writer.lock().await;
-        let mut len_bytes = [0u8; 4];
-        reader.read_exact(&mut len_bytes).await?;
-        let len = u32::from_be_bytes(len_bytes) as usize;
 
-        let mut buffer = vec![0u8; len];
-        reader.read_exact(&mut buffer).await?;
-        let token = String::from_utf8(buffer)
-            .map_err(|_| ServerError::Other("Invalid token format".to_string()))?;
+        // Klient, který se v časovém limitu nepřihlásí, zbytečně drží spojení
+        let login = async {
+            let version = negotiate_protocol(&mut reader, &mut writer).await?;
+            let user = authenticate(&mut reader, &mut writer, &pool).await?;
+            Ok::<_, ServerError>((version, user))
+        };
+        let (version, user) = timeout(config.heartbeat_timeout, login)
+            .await
+            .map_err(|_| ServerError::HeartbeatTimeout(config.heartbeat_timeout.as_secs()))??;
+        println!(
+            "Client {} authenticated as {} (protocol v{})",
+            addr, user.username, version
+        );
+        (version, user)
+    };
 
-        if token != "SECRET_TOKEN" {
-            // Zde ověřujeme token
-            writer.write_all(&4u32.to_be_bytes()).await?;
-            writer.write_all(b"FAIL").await?;
-            return Err(ServerError::Other("Authentication failed".to_string()));
+    let (outbound, receiver) =
+        queue::outbound_queue(config.outbound_queue, config.slow_client_policy);
+    task::spawn(async move {
+        if let Err(e) = queue::write_frames(receiver, writer).await {
+            println!("Error writing to client {}: {:?}", addr, e);
         }
+    });
 
-        let serialized = b"Authentication Successful";
-        let len = serialized.len() as u32;
-        writer.write(&len.to_be_bytes()).await?;
-        writer.write_all(serialized).await?;
-    }
+    let _heartbeat = Heartbeat::spawn(outbound.clone(), config.heartbeat_interval);
+
+    let mut room = DEFAULT_ROOM.to_string();
+    let pending = {
+        let mut clients_guard = clients.lock().await;
+        clients_guard.insert(
+            addr,
+            ConnectedClient {
+                outbound: outbound.clone(),
+                username: user.username.clone(),
+                // Místnost se nastaví až při vstupu, který ostatním oznámí příchod
+                room: String::new(),
+                visited: HashSet::new(),
+            },
+        );
+        println!("Connected clients: {}", clients_guard.len());
+        // Nedoručené zprávy se načtou pod stejným zámkem, pod kterým se soukromé
+        // zprávy ukládají, takže žádnou zprávu klient nedostane dvakrát
+        direct::load_pending(&pool, &user, version).await?
+    };
+    join_room(
+        &pool,
+        &clients,
+        &outbound,
+        addr,
+        &user,
+        &room,
+        config.history_limit,
+    )
+    .await?;
+    deliver_pending_direct_messages(&pool, &outbound, pending).await?;
+
+    // Rozpracované přenosy se při odpojení nezahazují, klient je může později dokončit
+    let mut transfers = Transfers::new(&config.upload_dir, config.max_file_size);
 
     // Pokračování standardní komunikace
     loop {
         let mut reader = reader.lock().await;
-        let mut len_bytes = [0u8; 4];
-        if reader.read_exact(&mut len_bytes).await.is_err() {
-            break; // Connection closed
-        }
-        let len = u32::from_be_bytes(len_bytes) as usize;
+        // Zavřená odchozí fronta znamená, že klient nestíhal nebo zápis selhal.
+        // Klient odpovídá na pingy, takže dlouhé ticho znamená mrtvé spojení.
+        let frame = tokio::select! {
+            frame = timeout(config.heartbeat_timeout, reader.read_frame()) => match frame {
+                Ok(frame) => frame?,
+                Err(_) => {
+                    return Err(ServerError::HeartbeatTimeout(
+                        config.heartbeat_timeout.as_secs(),
+                    ))
+                }
+            },
+            _ = outbound.closed() => return Err(ServerError::ConnectionClosed),
+        };
+        let buffer = match frame {
+            Some(buffer) => buffer,
+            None => break, // Connection closed
+        };
 
-        let mut buffer = vec![0u8; len];
-        if reader.read_exact(&mut buffer).await.is_err() {
-            break; // Connection closed
+        let envelope = deserialize_envelope(&buffer).map_err(ServerError::from)?;
+        let result = match &envelope.payload {
+            MessageType::JoinRoom(name) => join_room(
+                &pool,
+                &clients,
+                &outbound,
+                addr,
+                &user,
+                name,
+                config.history_limit,
+            )
+            .await
+            .map(|_| room = name.clone()),
+            MessageType::LeaveRoom => {
+                // Z opuštěné místnosti už klient upozornění nedostává
+                if let Some(client) = clients.lock().await.get_mut(&addr) {
+                    client.visited.remove(&room);
+                }
+                join_room(
+                    &pool,
+                    &clients,
+                    &outbound,
+                    addr,
+                    &user,
+                    DEFAULT_ROOM,
+                    config.history_limit,
+                )
+                .await
+                .map(|_| room = DEFAULT_ROOM.to_string())
+            }
+            MessageType::ListRooms => send_room_list(&pool, &clients, &outbound, &room).await,
+            MessageType::ListUsers => send_user_list(&clients, &outbound, &room).await,
+            MessageType::FetchHistory { after_id } => {
+                let missed =
+                    load_history_after(&pool, &room, *after_id, MISSED_HISTORY_LIMIT).await?;
+                if missed.is_empty() {
+                    Ok(())
+                } else {
+                    outbound.send(&Envelope::system(MessageType::History(missed), &room))
+                }
+            }
+            MessageType::Ping => outbound.send(&Envelope::system(MessageType::Pong, "")),
+            MessageType::Pong => Ok(()),
+            MessageType::History(_)
+            | MessageType::RoomJoined(_)
+            | MessageType::RoomList(_)
+            | MessageType::Error(_)
+            | MessageType::FileAccepted { .. }
+            | MessageType::UserJoined(_)
+            | MessageType::UserLeft(_)
+            | MessageType::UserList(_)
+            | MessageType::PublicKey { .. }
+            | MessageType::SearchResults { .. }
+            | MessageType::RoomActivity { .. }
+            | MessageType::Attachment { .. } => {
+                println!("Ignoring server-only message sent by client {}", addr);
+                Ok(())
+            }
+            MessageType::Search(query) => {
+                let rooms = joined_rooms(&clients, addr).await;
+                let (entries, total) =
+                    search::search_messages(&pool, query, &rooms, SEARCH_PAGE_SIZE).await?;
+                outbound.send(&Envelope::system(
+                    MessageType::SearchResults {
+                        page: query.page.max(1),
+                        total,
+                        entries,
+                    },
+                    &room,
+                ))
+            }
+            MessageType::PublishKey { key, replace } => {
+                keys::publish_key(&pool, &user, key, *replace)
+                    .await
+                    .map(|changed| {
+                        if changed {
+                            println!("{} published a new encryption key", user.username);
+                        }
+                    })
+            }
+            MessageType::RequestKey(username) => {
+                let key = keys::find_key(&pool, username).await?;
+                outbound.send(&Envelope::system(
+                    MessageType::PublicKey {
+                        username: username.clone(),
+                        key,
+                    },
+                    "",
+                ))
+            }
+            MessageType::Direct { .. } => {
+                // Server přeposílá a ukládá jen zašifrované soukromé zprávy
+                Err(direct::plaintext_rejected())
+            }
+            MessageType::EncryptedDirect { recipient, .. } => {
+                if auth::find_user(&pool, recipient).await?.is_none() {
+                    Err(ServerError::InvalidRequest(format!(
+                        "Unknown user {}",
+                        recipient
+                    )))
+                } else {
+                    // Soukromé zprávy nepatří do žádné místnosti
+                    let envelope = prepare_envelope(envelope, version, &user, "");
+                    forward_message(&sender, envelope, addr, &user).await
+                }
+            }
+            MessageType::Image { mime, data, .. }
+                if ImageFormat::detect(data).map(ImageFormat::mime) != Some(mime.as_str()) =>
+            {
+                // Typ obrázku se ověřuje podle obsahu, ne podle toho, co tvrdí odesílatel
+                Err(ServerError::InvalidRequest(
+                    "Image is not a PNG, JPEG, GIF or WebP image of the declared type".to_string(),
+                ))
+            }
+            MessageType::Text(_) => {
+                let envelope = prepare_envelope(envelope, version, &user, &room);
+                forward_message(&sender, envelope, addr, &user).await
+            }
+            MessageType::Image { .. } | MessageType::File(_, _) => {
+                // Příjemci dostanou jen odkaz na uloženou přílohu, data si vyžádají sami
+                let envelope = prepare_envelope(envelope, version, &user, &room);
+                let attachment =
+                    transfer::store_inline(&pool, &config.upload_dir, &envelope, &user).await?;
+                forward_message(&sender, attachment, addr, &user).await
+            }
+            MessageType::FileOffer { .. }
+            | MessageType::FileChunk { .. }
+            | MessageType::FileComplete { .. }
+            | MessageType::FileAbort { .. } => {
+                let envelope = prepare_envelope(envelope, version, &user, &room);
+                handle_transfer(
+                    &pool,
+                    &sender,
+                    &outbound,
+                    &mut transfers,
+                    envelope,
+                    addr,
+                    &user,
+                )
+                .await
+            }
+            MessageType::FileRequest {
+                transfer_id,
+                offset,
+            } => match transfer::load_transfer(&pool, *transfer_id as i64).await? {
+                // Cizí přílohy se tváří jako neexistující, aby nešlo zjistit, co kde je
+                Some(transfer)
+                    if transfer.sender == user.username
+                        || has_joined(&clients, addr, &transfer.room).await =>
+                {
+                    // Soubor se posílá na pozadí, aby klient mezitím mohl posílat další zprávy
+                    let outbound = outbound.clone();
+                    let upload_dir = config.upload_dir.clone();
+                    let offset = *offset;
+                    task::spawn(async move {
+                        if let Err(e) =
+                            serve_transfer(&outbound, &upload_dir, transfer, offset, version).await
+                        {
+                            println!("Error serving transfer to {}: {:?}", addr, e);
+                        }
+                    });
+                    Ok(())
+                }
+                // Přerušením přenosu klient pozná, že rozpracovaný soubor už nedostane
+                _ => outbound.send(&Envelope::system(
+                    MessageType::FileAbort {
+                        transfer_id: *transfer_id,
+                        reason: format!("Unknown transfer {}", transfer_id),
+                    },
+                    &room,
+                )),
+            },
+        };
+
+        // Chybné požadavky oznámíme klientovi, spojení kvůli nim neukončujeme
+        if let Err(ServerError::InvalidRequest(reason)) = result {
+            let envelope = Envelope::system(MessageType::Error(reason), &room);
+            outbound.send(&envelope)?;
+        } else {
+            result?;
         }
+    }
 
-        let message = deserialize_message(&buffer).map_err(ServerError::from)?;
-        sender
-            .send((message, addr))
-            .await
-            .map_err(|e| ServerError::Other(e.to_string()))?;
+    let dropped = outbound.dropped();
+    if dropped > 0 {
+        println!(
+            "Dropped {} messages for slow client {} ({})",
+            dropped, user.username, addr
+        );
     }
     Ok(())
 }
@@ -94,85 +993,298 @@ async fn handle_client(
 /// # Arguments
 ///
 /// * `address` - Adresa, na které server poslouchá
-async fn listen_and_accept(address: &str, pool: SqlitePool) -> Result<(), ServerError> {
+/// * `pool` - Databázový pool pro ukládání zpráv
+/// * `config` - Nastavení serveru
+/// * `tls` - Přijímač TLS spojení; bez něj server komunikuje nešifrovaně
+async fn listen_and_accept(
+    address: &str,
+    pool: SqlitePool,
+    config: ServerConfig,
+    tls: Option<TlsAcceptor>,
+) -> Result<(), ServerError> {
     let listener = TcpListener::bind(address).await?;
-    let clients: Arc<
-        Mutex<
-            HashMap<
-                std::net::SocketAddr,
-                (
-                    Arc<Mutex<tokio::net::tcp::OwnedReadHalf>>,
-                    Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
-                ),
-            >,
-        >,
-    > = Arc::new(Mutex::new(HashMap::new()));
+    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
     let (message_sender, mut message_receiver) =
-        mpsc::channel::<(MessageType, std::net::SocketAddr)>(32);
+        mpsc::channel::<(Envelope, std::net::SocketAddr, User)>(32);
 
     let clients_clone = Arc::clone(&clients);
+    let broadcast_pool = pool.clone();
     task::spawn(async move {
-        while let Some((message, sender_addr)) = message_receiver.recv().await {
-            println!("Received message from {}: {:?}", sender_addr, message);
-            let clients = clients_clone.lock().await;
-            for (client_addr, (_client_reader, client_writer)) in clients.iter() {
-                if client_addr != &sender_addr {
-                    let mut writer = client_writer.lock().await;
-                    if let Err(e) = send_message(&mut writer, &message).await {
-                        println!("Error sending message to {}: {:?}", client_addr, e);
-                    }
+        while let Some((mut envelope, sender_addr, user)) = message_receiver.recv().await {
+            // Části souborů nevypisujeme, zahltily by výpis serveru
+            if !matches!(envelope.payload, MessageType::FileChunk { .. }) {
+                println!(
+                    "Received message from {} ({}): {}",
+                    user.username,
+                    sender_addr,
+                    describe_payload(&envelope.payload)
+                );
+            }
+            if let MessageType::EncryptedDirect { recipient, .. } = &envelope.payload {
+                let recipient = recipient.clone();
+                if let Err(e) = route_direct_message(
+                    &broadcast_pool,
+                    &clients_clone,
+                    envelope,
+                    &user,
+                    &recipient,
+                )
+                .await
+                {
+                    println!(
+                        "Error delivering direct message from {}: {:?}",
+                        user.username, e
+                    );
                 }
+                continue;
+            }
+
+            let stored = match store_message(&broadcast_pool, &envelope, &user).await {
+                Ok(Some(id)) => {
+                    envelope.id = id;
+                    true
+                }
+                Ok(None) => false,
+                Err(e) => {
+                    println!("Error storing message from {}: {:?}", user.username, e);
+                    false
+                }
+            };
+            broadcast_message(&clients_clone, &envelope, sender_addr).await;
+            if stored {
+                announce_activity(&clients_clone, &envelope).await;
             }
         }
     });
 
     loop {
         let (stream, addr) = listener.accept().await?;
-        let (reader, writer) = stream.into_split();
         let clients = Arc::clone(&clients);
         let message_sender = message_sender.clone();
-        {
-            let mut clients_guard = clients.lock().await;
-            clients_guard.insert(
-                addr,
-                (Arc::new(Mutex::new(reader)), Arc::new(Mutex::new(writer))),
-            );
-            println!("Connected clients: {}", clients_guard.len());
-        }
-
-        let client_reader = Arc::clone(&clients.lock().await.get(&addr).unwrap().0);
-        let client_writer = Arc::clone(&clients.lock().await.get(&addr).unwrap().1);
         let pool = pool.clone();
+        let config = config.clone();
+        let tls = tls.clone();
         task::spawn(async move {
-            if let Err(err) =
-                handle_client(client_reader, client_writer, message_sender, addr, pool).await
+            // TLS handshake probíhá až v úloze spojení, aby pomalý klient nezdržel přijímání dalších
+            let stream = match secure_stream(stream, tls.as_ref(), config.heartbeat_timeout).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("Error establishing connection with {}: {:?}", addr, err);
+                    return;
+                }
+            };
+            let (reader, writer) = tokio::io::split(stream);
+            let client_reader = Arc::new(Mutex::new(FrameReader::with_max_frame_size(
+                reader,
+                config.max_frame_size,
+            )));
+            let client_writer = FrameWriter::with_max_frame_size(writer, config.max_frame_size);
+            if let Err(err) = handle_client(
+                client_reader,
+                client_writer,
+                Arc::clone(&clients),
+                message_sender,
+                addr,
+                pool,
+                config,
+            )
+            .await
             {
                 println!("Error handling client {}: {:?}", addr, err);
-                clients.lock().await.remove(&addr);
             }
-        });
+            disconnect_client(&clients, addr).await;
+        });
+    }
+}
+
+/// Zabalí přijaté spojení do TLS, pokud ho server používá.
+///
+/// # Arguments
+///
+/// * `stream` - Přijaté TCP spojení
+/// * `tls` - Přijímač TLS spojení
+/// * `handshake_timeout` - Nejdelší doba, po kterou se čeká na dokončení TLS handshaku
+async fn secure_stream(
+    stream: TcpStream,
+    tls: Option<&TlsAcceptor>,
+    handshake_timeout: Duration,
+) -> Result<BoxedStream, ServerError> {
+    match tls {
+        Some(acceptor) => timeout(handshake_timeout, tls::accept(acceptor, stream))
+            .await
+            .map_err(|_| ServerError::HeartbeatTimeout(handshake_timeout.as_secs()))?
+            .map_err(ServerError::from),
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// Rozešle zprávu všem ostatním klientům v místnosti, do které byla odeslána.
+///
+/// # Arguments
+///
+/// * `clients` - Mapa připojených klientů
+/// * `envelope` - Obálka se zprávou k rozeslání
+/// * `sender_addr` - Adresa odesílatele, kterému se zpráva neposílá
+async fn broadcast_message(
+    clients: &Clients,
+    envelope: &Envelope,
+    sender_addr: std::net::SocketAddr,
+) {
+    // Zpráva se serializuje jen jednou, do front klientů se vkládá bez čekání na síť
+    let frame = match serialize_envelope(envelope) {
+        Ok(frame) => frame,
+        Err(e) => {
+            println!("Error serializing message: {:?}", e);
+            return;
+        }
+    };
+    let clients = clients.lock().await;
+    for (client_addr, client) in clients.iter() {
+        if client_addr != &sender_addr && client.room == envelope.room {
+            if let Err(e) = client.outbound.push(frame.clone()) {
+                println!(
+                    "Error sending message to {} ({}): {:?}",
+                    client.username, client_addr, e
+                );
+            }
+        }
+    }
+}
+
+/// Upozorní klienty, kteří místnost navštívili, ale právě jsou jinde, na novou zprávu v ní.
+///
+/// # Arguments
+///
+/// * `clients` - Mapa připojených klientů
+/// * `envelope` - Obálka s uloženou zprávou
+async fn announce_activity(clients: &Clients, envelope: &Envelope) {
+    let activity = Envelope::system(
+        MessageType::RoomActivity {
+            room: envelope.room.clone(),
+            sender: envelope.sender.clone(),
+        },
+        &envelope.room,
+    );
+    let frame = match serialize_envelope(&activity) {
+        Ok(frame) => frame,
+        Err(e) => {
+            println!("Error serializing message: {:?}", e);
+            return;
+        }
+    };
+    let clients = clients.lock().await;
+    for (client_addr, client) in clients.iter() {
+        if client.room != envelope.room && client.visited.contains(&envelope.room) {
+            if let Err(e) = client.outbound.push(frame.clone()) {
+                println!(
+                    "Error sending message to {} ({}): {:?}",
+                    client.username, client_addr, e
+                );
+            }
+        }
     }
 }
 
-/// Funkce pro odesílání zpráv na klienty
+/// Uloží soukromou zprávu a doručí ji všem připojeným relacím příjemce.
+///
+/// Pokud příjemce není připojen, zpráva zůstane nedoručená a dostane ji
+/// po příštím přihlášení. Zpráva se uloží a relace příjemce se vyberou pod
+/// zámkem mapy klientů, pod kterým se přihlášený klient také zaregistruje a
+/// načte své nedoručené zprávy. Relace tak zprávu dostane buď živě, nebo
+/// mezi nedoručenými, nikdy oběma cestami.
 ///
 /// # Arguments
 ///
-/// * `writer` - Asynchronní zapisovací část TCP spojení
-/// * `message` - Typ zprávy k odeslání
-async fn send_message(
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-    message: &MessageType,
+/// * `pool` - Databázový pool se soukromými zprávami
+/// * `clients` - Mapa připojených klientů
+/// * `envelope` - Obálka se soukromou zprávou
+/// * `sender` - Uživatel, který zprávu odeslal
+/// * `recipient` - Uživatelské jméno příjemce
+async fn route_direct_message(
+    pool: &SqlitePool,
+    clients: &Clients,
+    mut envelope: Envelope,
+    sender: &User,
+    recipient: &str,
 ) -> Result<(), ServerError> {
-    let serialized = serialize_message(message).map_err(ServerError::from)?;
-
-    let len = serialized.len() as u32;
-    writer.write(&len.to_be_bytes()).await?;
-    writer.write_all(&serialized).await?;
+    let recipient = auth::find_user(pool, recipient)
+        .await?
+        .ok_or_else(|| ServerError::InvalidRequest(format!("Unknown user {}", recipient)))?;
+    let sessions = {
+        let clients = clients.lock().await;
+        if let Some(id) = direct::store_direct_message(pool, &envelope, sender, &recipient).await? {
+            envelope.id = id;
+        }
+        user_sessions(&clients, &recipient.username)
+    };
 
+    // Doručení běží na pozadí, aby pomalý příjemce nezdržel rozesílání ostatních zpráv
+    let pool = pool.clone();
+    task::spawn(async move {
+        if send_to_sessions(&sessions, &envelope).await > 0 {
+            if let Err(e) = direct::mark_delivered(&pool, envelope.id).await {
+                println!(
+                    "Error marking direct message {} delivered: {:?}",
+                    envelope.id, e
+                );
+            }
+        }
+    });
     Ok(())
 }
 
+/// Vybere odchozí fronty všech připojených relací daného uživatele.
+///
+/// # Arguments
+///
+/// * `clients` - Zamčená mapa připojených klientů
+/// * `username` - Uživatelské jméno příjemce
+fn user_sessions(
+    clients: &HashMap<std::net::SocketAddr, ConnectedClient>,
+    username: &str,
+) -> Vec<(std::net::SocketAddr, OutboundQueue)> {
+    clients
+        .iter()
+        .filter(|(_, client)| client.username == username)
+        .map(|(client_addr, client)| (*client_addr, client.outbound.clone()))
+        .collect()
+}
+
+/// Pošle zprávu relacím uživatele a počká, až ji jejich zapisovací úlohy zapíšou.
+///
+/// Zpráva se do odchozích front vkládá přes `send_timeout`, takže se při plné
+/// frontě nezahodí. Relace, která místo neuvolní do `DIRECT_SEND_TIMEOUT`, zprávu
+/// nedostane (při politice `Disconnect` se odpojí).
+///
+/// # Arguments
+///
+/// * `sessions` - Adresy a odchozí fronty relací příjemce
+/// * `envelope` - Obálka se zprávou k odeslání
+///
+/// # Returns
+///
+/// Vrací počet relací, do jejichž spojení se zprávu podařilo zapsat.
+async fn send_to_sessions(
+    sessions: &[(std::net::SocketAddr, OutboundQueue)],
+    envelope: &Envelope,
+) -> usize {
+    let mut written = Vec::with_capacity(sessions.len());
+    for (client_addr, outbound) in sessions {
+        match outbound.send_timeout(envelope, DIRECT_SEND_TIMEOUT).await {
+            Ok(confirmation) => written.push(confirmation),
+            Err(e) => println!("Error sending message to {}: {:?}", client_addr, e),
+        }
+    }
+
+    let mut delivered = 0;
+    for confirmation in written {
+        if confirmation.await.is_ok() {
+            delivered += 1;
+        }
+    }
+    delivered
+}
+
 /// Inicializuje databázi a vytváří potřebné tabulky.
 ///
 /// # Arguments
@@ -198,7 +1310,8 @@ async fn init_db(pool: &SqlitePool) -> Result<(), ServerError> {
         "
         CREATE TABLE IF NOT EXISTS users (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
-            username TEXT NOT NULL UNIQUE
+            username TEXT NOT NULL UNIQUE,
+            password_hash TEXT
         );
         CREATE TABLE IF NOT EXISTS messages (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -211,9 +1324,222 @@ async fn init_db(pool: &SqlitePool) -> Result<(), ServerError> {
     .execute(pool)
     .await?;
 
+    // Doplnění sloupců do databází vytvořených starší verzí serveru
+    ensure_column(pool, "users", "password_hash", "TEXT").await?;
+    ensure_column(pool, "messages", "sender", "TEXT NOT NULL DEFAULT ''").await?;
+    ensure_column(pool, "messages", "room", "TEXT NOT NULL DEFAULT 'general'").await?;
+    ensure_column(pool, "messages", "kind", "TEXT NOT NULL DEFAULT 'text'").await?;
+    ensure_column(pool, "messages", "filename", "TEXT").await?;
+    ensure_column(pool, "messages", "size", "INTEGER").await?;
+    ensure_column(pool, "messages", "timestamp", "INTEGER NOT NULL DEFAULT 0").await?;
+    rooms::init_rooms(pool).await?;
+    direct::init_direct_messages(pool).await?;
+    keys::init_public_keys(pool).await?;
+    transfer::init_transfers(pool).await?;
+    attachments::init_attachments(pool).await?;
+    search::init_search(pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_messages_room_timestamp ON messages(room, timestamp)",
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Přidá sloupec do tabulky, pokud v ní ještě neexistuje.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `table` - Název tabulky
+/// * `column` - Název sloupce
+/// * `definition` - Typ a omezení sloupce
+async fn ensure_column(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), ServerError> {
+    let columns = sqlx::query(&format!("PRAGMA table_info({})", table))
+        .fetch_all(pool)
+        .await?;
+    let exists = columns
+        .iter()
+        .any(|row| row.get::<String, _>("name") == column);
+
+    if !exists {
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))
+        .execute(pool)
+        .await?;
+    }
+
     Ok(())
 }
 
+/// Uloží zprávu do tabulky `messages`.
+///
+/// U textových zpráv se ukládá jejich obsah, u obrázků a souborů pouze metadata
+/// (název souboru a velikost), samotná data se neukládají. Místnost a čas odeslání
+/// se přebírají z obálky.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `envelope` - Obálka se zprávou k uložení
+/// * `sender` - Přihlášený uživatel, který zprávu odeslal
+///
+/// # Returns
+///
+/// Vrací ID nově uloženého záznamu, nebo `None`, pokud se daný typ zprávy neukládá.
+async fn store_message(
+    pool: &SqlitePool,
+    envelope: &Envelope,
+    sender: &User,
+) -> Result<Option<i64>, ServerError> {
+    let (kind, content, filename, size) = match &envelope.payload {
+        MessageType::Text(text) => ("text", text.as_str(), None, None),
+        MessageType::Image { filename, data, .. } => (
+            "image",
+            "",
+            Some(filename.as_str()),
+            Some(data.len() as i64),
+        ),
+        MessageType::File(filename, data) => {
+            ("file", "", Some(filename.as_str()), Some(data.len() as i64))
+        }
+        MessageType::Attachment {
+            filename,
+            size,
+            image,
+            ..
+        } => (
+            if *image { "image" } else { "file" },
+            "",
+            Some(filename.as_str()),
+            Some(*size as i64),
+        ),
+        _ => return Ok(None),
+    };
+
+    insert_message(pool, envelope, sender, kind, content, filename, size)
+        .await
+        .map(Some)
+}
+
+/// Vloží zprávu do tabulky `messages`, místnost a čas odeslání přebírá z obálky.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `envelope` - Obálka ukládané zprávy
+/// * `sender` - Přihlášený uživatel, který zprávu odeslal
+/// * `kind` - Druh zprávy (`text`, `image` nebo `file`)
+/// * `content` - Text zprávy, u souborů prázdný
+/// * `filename` - Název souboru
+/// * `size` - Velikost souboru v bajtech
+async fn insert_message(
+    pool: &SqlitePool,
+    envelope: &Envelope,
+    sender: &User,
+    kind: &str,
+    content: &str,
+    filename: Option<&str>,
+    size: Option<i64>,
+) -> Result<i64, ServerError> {
+    let result = sqlx::query(
+        "INSERT INTO messages (user_id, sender, room, kind, content, filename, size, timestamp)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(sender.id)
+    .bind(&sender.username)
+    .bind(&envelope.room)
+    .bind(kind)
+    .bind(content)
+    .bind(filename)
+    .bind(size)
+    .bind(envelope.timestamp)
+    .execute(pool)
+    .await?;
+
+    Ok(result.last_insert_rowid())
+}
+
+/// Načte posledních `limit` zpráv z místnosti seřazených od nejstarší.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `room` - Místnost, ze které se historie načítá
+/// * `limit` - Maximální počet načtených zpráv
+async fn load_history(
+    pool: &SqlitePool,
+    room: &str,
+    limit: u32,
+) -> Result<Vec<HistoryEntry>, ServerError> {
+    let rows = sqlx::query(
+        "SELECT id, sender, room, timestamp, kind, content, filename, size FROM (
+            SELECT * FROM messages WHERE room = ? ORDER BY id DESC LIMIT ?
+         ) ORDER BY id ASC",
+    )
+    .bind(room)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(history_entry).collect())
+}
+
+/// Načte nejvýše `limit` zpráv z místnosti s ID větším než `after_id`
+/// seřazených od nejstarší.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `room` - Místnost, ze které se historie načítá
+/// * `after_id` - ID poslední zprávy, kterou klient zná
+/// * `limit` - Maximální počet načtených zpráv
+async fn load_history_after(
+    pool: &SqlitePool,
+    room: &str,
+    after_id: i64,
+    limit: u32,
+) -> Result<Vec<HistoryEntry>, ServerError> {
+    let rows = sqlx::query(
+        "SELECT id, sender, room, timestamp, kind, content, filename, size FROM messages
+         WHERE room = ? AND id > ? ORDER BY id ASC LIMIT ?",
+    )
+    .bind(room)
+    .bind(after_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(history_entry).collect())
+}
+
+/// Převede řádek tabulky `messages` na záznam historie
+///
+/// # Arguments
+///
+/// * `row` - Řádek se sloupci záznamu historie
+fn history_entry(row: &sqlx::sqlite::SqliteRow) -> HistoryEntry {
+    HistoryEntry {
+        id: row.get("id"),
+        sender: row.get("sender"),
+        room: row.get("room"),
+        timestamp: row.get("timestamp"),
+        kind: row.get("kind"),
+        content: row.get("content"),
+        filename: row.get("filename"),
+        size: row.get("size"),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), ServerError> {
     dotenv().ok();
@@ -223,8 +1549,31 @@ async fn main() -> Result<(), ServerError> {
     let pool = SqlitePool::connect(&args.database_url).await?;
     init_db(&pool).await?;
 
+    if let Some(username) = &args.set_password {
+        let password = args.password.as_deref().ok_or_else(|| {
+            ServerError::Other("--set-password needs a password in CHAT_PASSWORD".to_string())
+        })?;
+        auth::set_password(&pool, username, password).await?;
+        println!("Password of {} has been set", username);
+        return Ok(());
+    }
+
+    tokio::fs::create_dir_all(&args.upload_dir).await?;
+
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(certificate), Some(private_key)) => {
+            let (acceptor, fingerprint) = tls::server_acceptor(certificate, private_key)?;
+            println!(
+                "TLS enabled, certificate SHA-256 fingerprint: {}",
+                fingerprint
+            );
+            Some(acceptor)
+        }
+        _ => None,
+    };
+
     println!("Listening on: {}", address);
-    listen_and_accept(&address, pool).await?;
+    listen_and_accept(&address, pool, ServerConfig::from(&args), tls).await?;
 
     Ok(())
 }
@@ -235,6 +1584,12 @@ mod tests {
     use sqlx::SqlitePool;
     use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
 
+    /// Rozdělí TCP spojení na čtecí a zapisovací část s rámci
+    fn framed(stream: tokio::net::TcpStream) -> (ClientReader, ClientWriter) {
+        let (reader, writer) = tokio::io::split(Box::new(stream) as BoxedStream);
+        (FrameReader::new(reader), FrameWriter::new(writer))
+    }
+
     #[tokio::test]
     async fn test_db_connection() -> Result<(), ServerError> {
         let pool = SqlitePool::connect("sqlite::memory:").await?;
@@ -244,11 +1599,11 @@ mod tests {
 
     #[tokio::test]
     async fn test_send_message() -> Result<(), ServerError> {
-        let message = MessageType::Text("Hello, World!".to_string());
-        let (mut client, mut server) = duplex(64);
+        let message = Envelope::new(MessageType::Text("Hello, World!".to_string()), DEFAULT_ROOM);
+        let (mut client, mut server) = duplex(256);
 
         // Send message to server side (server acts as the client for this test)
-        let serialized = serialize_message(&message).map_err(ServerError::from)?;
+        let serialized = serialize_envelope(&message).map_err(ServerError::from)?;
         let len = serialized.len() as u32;
         server.write_all(&len.to_be_bytes()).await?;
         server.write_all(&serialized).await?;
@@ -261,9 +1616,551 @@ mod tests {
         let mut buffer = vec![0u8; len];
         client.read_exact(&mut buffer).await?;
 
-        let received_message = deserialize_message(&buffer).map_err(ServerError::from)?;
+        let received_message = deserialize_envelope(&buffer).map_err(ServerError::from)?;
         assert_eq!(message, received_message);
 
         Ok(())
     }
+
+    /// Creates a client in `room` and returns the stream on which it receives broadcasts
+    async fn connect_client(
+        listener: &TcpListener,
+        username: &str,
+        room: &str,
+    ) -> Result<(ConnectedClient, FrameReader<tokio::net::TcpStream>), ServerError> {
+        let remote = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+        let (local, _) = listener.accept().await?;
+        let (_reader, writer) = framed(local);
+        let (outbound, receiver) = queue::outbound_queue(16, OverflowPolicy::DropOldest);
+        tokio::spawn(queue::write_frames(receiver, writer));
+        let client = ConnectedClient {
+            outbound,
+            username: username.to_string(),
+            room: room.to_string(),
+            visited: HashSet::from([room.to_string()]),
+        };
+        Ok((client, FrameReader::new(remote)))
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_is_routed_by_room() -> Result<(), ServerError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (alice, mut alice_stream) = connect_client(&listener, "alice", "backend").await?;
+        let (bob, mut bob_stream) = connect_client(&listener, "bob", "backend").await?;
+        let (carol, mut carol_stream) = connect_client(&listener, "carol", DEFAULT_ROOM).await?;
+
+        let alice_addr: std::net::SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        {
+            let mut map = clients.lock().await;
+            map.insert(alice_addr, alice);
+            map.insert("127.0.0.1:2".parse().unwrap(), bob);
+            map.insert("127.0.0.1:3".parse().unwrap(), carol);
+        }
+
+        let envelope = Envelope::new(MessageType::Text("deploy?".to_string()), "backend");
+        broadcast_message(&clients, &envelope, alice_addr).await;
+        // Closing all connections lets the receivers observe that nothing else was sent
+        drop(clients);
+
+        let received = bob_stream
+            .read_frame()
+            .await?
+            .expect("bob should get the message");
+        assert_eq!(deserialize_envelope(&received)?, envelope);
+        assert_eq!(bob_stream.read_frame().await?, None);
+        assert_eq!(alice_stream.read_frame().await?, None);
+        assert_eq!(carol_stream.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_activity_is_announced_to_visitors() -> Result<(), ServerError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (alice, mut alice_stream) = connect_client(&listener, "alice", "backend").await?;
+        let (mut bob, mut bob_stream) = connect_client(&listener, "bob", DEFAULT_ROOM).await?;
+        let (carol, mut carol_stream) = connect_client(&listener, "carol", DEFAULT_ROOM).await?;
+        // Bob was in the backend room earlier, carol never was
+        bob.visited.insert("backend".to_string());
+
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        {
+            let mut map = clients.lock().await;
+            map.insert("127.0.0.1:1".parse().unwrap(), alice);
+            map.insert("127.0.0.1:2".parse().unwrap(), bob);
+            map.insert("127.0.0.1:3".parse().unwrap(), carol);
+        }
+
+        let mut envelope = Envelope::new(MessageType::Text("deploy?".to_string()), "backend");
+        envelope.sender = "alice".to_string();
+        announce_activity(&clients, &envelope).await;
+        drop(clients);
+
+        let received = bob_stream
+            .read_frame()
+            .await?
+            .expect("bob should be notified");
+        assert_eq!(
+            deserialize_envelope(&received)?.payload,
+            MessageType::RoomActivity {
+                room: "backend".to_string(),
+                sender: "alice".to_string()
+            }
+        );
+        assert_eq!(bob_stream.read_frame().await?, None);
+        // The room's own members get the message itself, not a notification
+        assert_eq!(alice_stream.read_frame().await?, None);
+        assert_eq!(carol_stream.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_to_sessions() -> Result<(), ServerError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (bob_laptop, mut laptop_stream) = connect_client(&listener, "bob", "backend").await?;
+        let (bob_phone, mut phone_stream) = connect_client(&listener, "bob", DEFAULT_ROOM).await?;
+        let (carol, mut carol_stream) = connect_client(&listener, "carol", DEFAULT_ROOM).await?;
+
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        {
+            let mut map = clients.lock().await;
+            map.insert("127.0.0.1:1".parse().unwrap(), bob_laptop);
+            map.insert("127.0.0.1:2".parse().unwrap(), bob_phone);
+            map.insert("127.0.0.1:3".parse().unwrap(), carol);
+        }
+
+        let envelope = Envelope::new(
+            MessageType::Direct {
+                recipient: "bob".to_string(),
+                text: "psst".to_string(),
+            },
+            "",
+        );
+        let (bob_sessions, dave_sessions) = {
+            let map = clients.lock().await;
+            (user_sessions(&map, "bob"), user_sessions(&map, "dave"))
+        };
+        assert_eq!(send_to_sessions(&bob_sessions, &envelope).await, 2);
+        assert_eq!(send_to_sessions(&dave_sessions, &envelope).await, 0);
+        drop((clients, bob_sessions));
+
+        for stream in [&mut laptop_stream, &mut phone_stream] {
+            let received = stream
+                .read_frame()
+                .await?
+                .expect("bob should get the message");
+            assert_eq!(deserialize_envelope(&received)?, envelope);
+        }
+        assert_eq!(carol_stream.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_payload_hides_content() {
+        let image = MessageType::Image {
+            filename: "cat.png".to_string(),
+            mime: "image/png".to_string(),
+            data: vec![0xAB; 2048],
+        };
+        assert_eq!(describe_payload(&image), "image (2048 bytes)");
+        assert_eq!(
+            describe_payload(&MessageType::Text("secret".to_string())),
+            "text (6 bytes)"
+        );
+        assert_eq!(describe_payload(&MessageType::ListUsers), "control message");
+    }
+
+    #[tokio::test]
+    async fn test_has_joined() -> Result<(), ServerError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (mut bob, _bob_stream) = connect_client(&listener, "bob", DEFAULT_ROOM).await?;
+        bob.visited.insert("backend".to_string());
+        let bob_addr: std::net::SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        clients.lock().await.insert(bob_addr, bob);
+
+        assert!(has_joined(&clients, bob_addr, DEFAULT_ROOM).await);
+        assert!(has_joined(&clients, bob_addr, "backend").await);
+        // Attachments of rooms the client never joined stay out of reach
+        assert!(!has_joined(&clients, bob_addr, "secret").await);
+        assert!(!has_joined(&clients, "127.0.0.1:3".parse().unwrap(), DEFAULT_ROOM).await);
+
+        // Search is scoped by the same rooms
+        let mut rooms = joined_rooms(&clients, bob_addr).await;
+        rooms.sort();
+        assert_eq!(rooms, ["backend", DEFAULT_ROOM]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_direct_message_is_refused() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+        sqlx::query("INSERT INTO users (id, username) VALUES (1, 'alice'), (2, 'bob')")
+            .execute(&pool)
+            .await?;
+        let alice = auth::find_user(&pool, "alice").await?.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (bob, mut bob_stream) = connect_client(&listener, "bob", DEFAULT_ROOM).await?;
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        clients
+            .lock()
+            .await
+            .insert("127.0.0.1:2".parse().unwrap(), bob);
+
+        let envelope = Envelope::new(
+            MessageType::Direct {
+                recipient: "bob".to_string(),
+                text: "psst".to_string(),
+            },
+            "",
+        );
+        assert!(matches!(
+            route_direct_message(&pool, &clients, envelope, &alice, "bob").await,
+            Err(ServerError::InvalidRequest(_))
+        ));
+        drop(clients);
+
+        // Nothing is stored and nothing reaches the recipient
+        let stored: i64 = sqlx::query("SELECT COUNT(*) AS count FROM direct_messages")
+            .fetch_one(&pool)
+            .await?
+            .get("count");
+        assert_eq!(stored, 0);
+        assert_eq!(bob_stream.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slow_recipient_does_not_block_direct_messages() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+        sqlx::query("INSERT INTO users (id, username) VALUES (1, 'alice'), (2, 'bob')")
+            .execute(&pool)
+            .await?;
+        let alice = auth::find_user(&pool, "alice").await?.unwrap();
+
+        // Bob's queue is full and nobody drains it
+        let (outbound, _receiver) = queue::outbound_queue(1, OverflowPolicy::DropOldest);
+        outbound.push_wait(b"busy".to_vec()).await?;
+        let bob = ConnectedClient {
+            outbound,
+            username: "bob".to_string(),
+            room: DEFAULT_ROOM.to_string(),
+            visited: HashSet::new(),
+        };
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        clients
+            .lock()
+            .await
+            .insert("127.0.0.1:2".parse().unwrap(), bob);
+
+        let envelope = Envelope::new(
+            MessageType::EncryptedDirect {
+                recipient: "bob".to_string(),
+                sealed: shared::SealedMessage {
+                    sender_key: [1; 32],
+                    recipient_key: [2; 32],
+                    nonce: [3; 12],
+                    ciphertext: vec![4; 20],
+                },
+            },
+            "",
+        );
+        timeout(
+            Duration::from_secs(1),
+            route_direct_message(&pool, &clients, envelope, &alice, "bob"),
+        )
+        .await
+        .expect("routing must not wait for the recipient")?;
+
+        // The message stays pending for the next login
+        let bob_user = auth::find_user(&pool, "bob").await?.unwrap();
+        assert_eq!(direct::load_pending(&pool, &bob_user, 1).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_direct_message_is_delivered_once_written() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+        sqlx::query("INSERT INTO users (id, username) VALUES (1, 'alice'), (2, 'bob')")
+            .execute(&pool)
+            .await?;
+        let alice = auth::find_user(&pool, "alice").await?.unwrap();
+        let bob_user = auth::find_user(&pool, "bob").await?.unwrap();
+        let envelope = Envelope::new(
+            MessageType::EncryptedDirect {
+                recipient: "bob".to_string(),
+                sealed: shared::SealedMessage {
+                    sender_key: [1; 32],
+                    recipient_key: [2; 32],
+                    nonce: [3; 12],
+                    ciphertext: vec![4; 20],
+                },
+            },
+            "",
+        );
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let bob_addr: std::net::SocketAddr = "127.0.0.1:2".parse().unwrap();
+
+        // Bob disconnects before his queue is written: the message stays pending
+        let (outbound, receiver) = queue::outbound_queue(4, OverflowPolicy::DropOldest);
+        let bob = ConnectedClient {
+            outbound,
+            username: "bob".to_string(),
+            room: DEFAULT_ROOM.to_string(),
+            visited: HashSet::new(),
+        };
+        clients.lock().await.insert(bob_addr, bob);
+        route_direct_message(&pool, &clients, envelope.clone(), &alice, "bob").await?;
+        drop(receiver);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(direct::load_pending(&pool, &bob_user, 1).await?.len(), 1);
+
+        // Once the frame is written, both messages leave the pending set
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (bob, mut bob_stream) = connect_client(&listener, "bob", DEFAULT_ROOM).await?;
+        let pending = {
+            let mut map = clients.lock().await;
+            map.insert(bob_addr, bob);
+            direct::load_pending(&pool, &bob_user, 1).await?
+        };
+        let outbound = clients.lock().await[&bob_addr].outbound.clone();
+        deliver_pending_direct_messages(&pool, &outbound, pending).await?;
+        route_direct_message(&pool, &clients, envelope, &alice, "bob").await?;
+        for _ in 0..2 {
+            assert!(bob_stream.read_frame().await?.is_some());
+        }
+        let mut delivered = false;
+        for _ in 0..50 {
+            if direct::load_pending(&pool, &bob_user, 1).await?.is_empty() {
+                delivered = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(delivered);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_announces_departure() -> Result<(), ServerError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (alice, _alice_stream) = connect_client(&listener, "alice", "backend").await?;
+        let (bob, mut bob_stream) = connect_client(&listener, "bob", "backend").await?;
+        let (carol, mut carol_stream) = connect_client(&listener, "carol", DEFAULT_ROOM).await?;
+
+        let alice_addr: std::net::SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        {
+            let mut map = clients.lock().await;
+            map.insert(alice_addr, alice);
+            map.insert("127.0.0.1:2".parse().unwrap(), bob);
+            map.insert("127.0.0.1:3".parse().unwrap(), carol);
+        }
+
+        disconnect_client(&clients, alice_addr).await;
+        // A second removal of the same client is a no-op
+        disconnect_client(&clients, alice_addr).await;
+        assert_eq!(clients.lock().await.len(), 2);
+        drop(clients);
+
+        let received = bob_stream
+            .read_frame()
+            .await?
+            .expect("bob should be told that alice left");
+        let envelope = deserialize_envelope(&received)?;
+        assert_eq!(envelope.payload, MessageType::UserLeft("alice".to_string()));
+        assert_eq!(envelope.room, "backend");
+        assert_eq!(bob_stream.read_frame().await?, None);
+        assert_eq!(carol_stream.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_message() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+
+        let text = MessageType::Text("Hello".to_string());
+        let file = MessageType::File("notes.txt".to_string(), vec![0u8; 42]);
+        let alice = auth::register_user(&pool, "alice", "alice password").await?;
+        let bob = auth::register_user(&pool, "bob", "bob password").await?;
+        store_message(&pool, &Envelope::new(text, DEFAULT_ROOM), &alice).await?;
+        let id = store_message(&pool, &Envelope::new(file, DEFAULT_ROOM), &bob)
+            .await?
+            .expect("file message should be stored");
+
+        let row = sqlx::query(
+            "SELECT user_id, sender, room, kind, content, filename, size FROM messages WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(row.get::<Option<i64>, _>("user_id"), Some(bob.id));
+        assert_eq!(row.get::<String, _>("sender"), "bob");
+        assert_eq!(row.get::<String, _>("room"), DEFAULT_ROOM);
+        assert_eq!(row.get::<String, _>("kind"), "file");
+        assert_eq!(row.get::<String, _>("content"), "");
+        assert_eq!(
+            row.get::<Option<String>, _>("filename").as_deref(),
+            Some("notes.txt")
+        );
+        assert_eq!(row.get::<Option<i64>, _>("size"), Some(42));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_init_db_migrates_old_schema() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        sqlx::query(
+            "CREATE TABLE users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL UNIQUE
+            );
+            CREATE TABLE messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                content TEXT NOT NULL
+            );
+            INSERT INTO users (username) VALUES ('alice');",
+        )
+        .execute(&pool)
+        .await?;
+
+        init_db(&pool).await?;
+        // Users from the old schema get their password from the administrator
+        assert!(auth::register_user(&pool, "alice", "alice password")
+            .await
+            .is_err());
+        let alice = auth::set_password(&pool, "alice", "alice password").await?;
+        assert_eq!(alice.id, 1);
+        let message = Envelope::new(MessageType::Text("Hi".to_string()), DEFAULT_ROOM);
+        store_message(&pool, &message, &alice).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_history() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+        let alice = auth::register_user(&pool, "alice", "alice password").await?;
+        let bob = auth::register_user(&pool, "bob", "bob password").await?;
+
+        for i in 0..5 {
+            let message = MessageType::Text(format!("message {}", i));
+            store_message(&pool, &Envelope::new(message, DEFAULT_ROOM), &alice).await?;
+        }
+        let elsewhere = Envelope::new(MessageType::Text("elsewhere".to_string()), "other");
+        store_message(&pool, &elsewhere, &bob).await?;
+
+        let history = load_history(&pool, DEFAULT_ROOM, 3).await?;
+        let contents: Vec<&str> = history.iter().map(|e| e.content.as_str()).collect();
+        assert_eq!(contents, vec!["message 2", "message 3", "message 4"]);
+        assert!(history.iter().all(|e| e.sender == "alice"));
+
+        let missed = load_history_after(&pool, DEFAULT_ROOM, history[0].id, 100).await?;
+        let contents: Vec<&str> = missed.iter().map(|e| e.content.as_str()).collect();
+        assert_eq!(contents, vec!["message 3", "message 4"]);
+        assert_eq!(load_history_after(&pool, "other", 0, 100).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_protocol_rejects_legacy_client() -> Result<(), ServerError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let client_task = tokio::spawn(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (mut reader, mut writer) = framed(stream);
+            // Older clients started the conversation with a bare token
+            writer.write_frame(b"SECRET_TOKEN").await.unwrap();
+            let response = reader.read_frame().await.unwrap().unwrap();
+            shared::protocol::deserialize_handshake_response(&response).unwrap()
+        });
+
+        let (stream, _) = listener.accept().await?;
+        let (mut reader, mut writer) = framed(stream);
+        let result = negotiate_protocol(&mut reader, &mut writer).await;
+        assert!(matches!(result, Err(ServerError::IncompatibleProtocol(_))));
+
+        let response = client_task.await.unwrap();
+        assert!(matches!(response, HandshakeResponse::Rejected { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_protocol_rejects_outdated_client() -> Result<(), ServerError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let client_task = tokio::spawn(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (mut reader, mut writer) = framed(stream);
+            // A client that only speaks a version below the minimum
+            let handshake = shared::protocol::Handshake {
+                version: MIN_PROTOCOL_VERSION - 1,
+                min_version: MIN_PROTOCOL_VERSION - 1,
+                ..shared::protocol::Handshake::new()
+            };
+            let handshake = shared::protocol::serialize_handshake(&handshake).unwrap();
+            writer.write_frame(&handshake).await.unwrap();
+            let response = reader.read_frame().await.unwrap().unwrap();
+            shared::protocol::deserialize_handshake_response(&response).unwrap()
+        });
+
+        let (stream, _) = listener.accept().await?;
+        let (mut reader, mut writer) = framed(stream);
+        let result = negotiate_protocol(&mut reader, &mut writer).await;
+        assert!(matches!(result, Err(ServerError::IncompatibleProtocol(_))));
+
+        let response = client_task.await.unwrap();
+        assert!(matches!(
+            response,
+            HandshakeResponse::Rejected { min_version, .. } if min_version == MIN_PROTOCOL_VERSION
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_protocol_accepts_current_client() -> Result<(), ServerError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let client_task = tokio::spawn(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (mut reader, mut writer) = framed(stream);
+            let handshake =
+                shared::protocol::serialize_handshake(&shared::protocol::Handshake::new()).unwrap();
+            writer.write_frame(&handshake).await.unwrap();
+            let response = reader.read_frame().await.unwrap().unwrap();
+            shared::protocol::deserialize_handshake_response(&response).unwrap()
+        });
+
+        let (stream, _) = listener.accept().await?;
+        let (mut reader, mut writer) = framed(stream);
+        let version = negotiate_protocol(&mut reader, &mut writer).await?;
+        assert_eq!(version, PROTOCOL_VERSION);
+
+        let response = client_task.await.unwrap();
+        assert_eq!(response, HandshakeResponse::Accepted { version });
+
+        Ok(())
+    }
 }
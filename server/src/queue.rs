@@ -0,0 +1,497 @@
+use shared::frame::FrameWriter;
+use shared::protocol::{serialize_envelope, Envelope};
+use shared::server_error::ServerError;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWrite;
+use tokio::sync::{oneshot, Notify};
+use tokio::time::{timeout, Duration};
+
+/// Co udělat s klientem, jehož odchozí fronta je plná
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    /// Zahodí nejstarší čekající zprávu a novou zařadí na konec fronty.
+    ///
+    /// Rámce vložené přes `push_wait` se nikdy nezahazují; pokud ve frontě
+    /// žádný jiný rámec není, zahodí se místo nich nová zpráva.
+    DropOldest,
+    /// Odpojí klienta, který nestíhá zprávy přijímat
+    Disconnect,
+}
+
+/// Rámec čekající ve frontě
+struct Frame {
+    data: Vec<u8>,
+    /// Rámec se při zaplnění fronty smí zahodit
+    droppable: bool,
+    /// Odesílatel, kterému se ohlásí, že byl rámec zapsán do spojení
+    written: Option<oneshot::Sender<()>>,
+}
+
+/// Stav fronty chráněný zámkem
+struct State {
+    frames: VecDeque<Frame>,
+    /// Fronta byla zavřena a čekající zprávy se už neodešlou
+    closed: bool,
+    /// Počet zpráv zahozených kvůli plné frontě
+    dropped: u64,
+}
+
+/// Data sdílená odesílateli a zapisovací úlohou
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Počet živých odesílatelů; po odchodu posledního se fronta jen vyprázdní
+    senders: AtomicUsize,
+    /// Probouzí zapisovací úlohu, když přibude zpráva nebo se fronta zavře
+    readable: Notify,
+    /// Probouzí odesílatele, kteří čekají na volné místo nebo na zavření fronty
+    writable: Notify,
+}
+
+impl Shared {
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.frames.clear();
+        drop(state);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+}
+
+/// Odesílací strana odchozí fronty jednoho klienta.
+///
+/// Odesílatelé do fronty jen vkládají serializované rámce a nikdy nečekají na síť,
+/// o samotný zápis do spojení se stará zapisovací úloha klienta.
+pub struct OutboundQueue {
+    shared: Arc<Shared>,
+}
+
+/// Přijímací strana odchozí fronty, kterou vlastní zapisovací úloha klienta
+pub struct OutboundReceiver {
+    shared: Arc<Shared>,
+}
+
+/// Vytvoří omezenou odchozí frontu pro jednoho klienta.
+///
+/// # Arguments
+///
+/// * `capacity` - Maximální počet čekajících rámců
+/// * `policy` - Chování při zaplnění fronty
+pub fn outbound_queue(
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> (OutboundQueue, OutboundReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            frames: VecDeque::new(),
+            closed: false,
+            dropped: 0,
+        }),
+        capacity: capacity.max(1),
+        policy,
+        senders: AtomicUsize::new(1),
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (
+        OutboundQueue {
+            shared: Arc::clone(&shared),
+        },
+        OutboundReceiver { shared },
+    )
+}
+
+impl OutboundQueue {
+    /// Zařadí rámec do fronty bez čekání.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - Serializovaný rámec
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ServerError::ConnectionClosed`, pokud je fronta zavřená, a
+    /// `ServerError::SlowConsumer`, pokud je plná a klient se má odpojit.
+    pub fn push(&self, frame: Vec<u8>) -> Result<(), ServerError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(ServerError::ConnectionClosed);
+        }
+        if state.frames.len() >= self.shared.capacity {
+            match self.shared.policy {
+                OverflowPolicy::DropOldest => {
+                    state.dropped += 1;
+                    match state.frames.iter().position(|frame| frame.droppable) {
+                        Some(oldest) => {
+                            state.frames.remove(oldest);
+                        }
+                        // Ve frontě jsou jen rámce, které se zahodit nesmí
+                        None => return Ok(()),
+                    }
+                }
+                OverflowPolicy::Disconnect => {
+                    drop(state);
+                    self.shared.close();
+                    return Err(ServerError::SlowConsumer);
+                }
+            }
+        }
+        state.frames.push_back(Frame {
+            data: frame,
+            droppable: true,
+            written: None,
+        });
+        drop(state);
+        self.shared.readable.notify_one();
+        Ok(())
+    }
+
+    /// Zařadí rámec do fronty, a pokud je plná, počká na volné místo.
+    ///
+    /// Slouží pro data, která si klient sám vyžádal, a pro soukromé zprávy.
+    /// Takový rámec se ani později nezahodí kvůli politice `DropOldest`.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - Serializovaný rámec
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ServerError::ConnectionClosed`, pokud se fronta mezitím zavře.
+    pub async fn push_wait(&self, frame: Vec<u8>) -> Result<(), ServerError> {
+        self.enqueue(Frame {
+            data: frame,
+            droppable: false,
+            written: None,
+        })
+        .await
+    }
+
+    /// Zařadí rámec, který se nesmí zahodit, a počká na volné místo
+    async fn enqueue(&self, frame: Frame) -> Result<(), ServerError> {
+        loop {
+            let notified = self.shared.writable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closed {
+                    return Err(ServerError::ConnectionClosed);
+                }
+                if state.frames.len() < self.shared.capacity {
+                    state.frames.push_back(frame);
+                    drop(state);
+                    self.shared.readable.notify_one();
+                    return Ok(());
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Zařadí rámec, který se nesmí zahodit, a na volné místo čeká nejvýše danou dobu.
+    ///
+    /// Pokud se místo neuvolní, klient nestíhá: při politice `Disconnect` se
+    /// odpojí, při `DropOldest` se rámec jen nezařadí.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - Serializovaný rámec
+    /// * `wait` - Nejdelší doba čekání na volné místo
+    ///
+    /// # Returns
+    ///
+    /// Vrací potvrzení, které se splní, až zapisovací úloha rámec zapíše do
+    /// spojení. Pokud se fronta dřív zavře, potvrzení skončí chybou.
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ServerError::ConnectionClosed`, pokud se fronta mezitím zavře, a
+    /// `ServerError::SlowConsumer`, pokud se místo neuvolnilo včas.
+    pub async fn push_timeout(
+        &self,
+        frame: Vec<u8>,
+        wait: Duration,
+    ) -> Result<oneshot::Receiver<()>, ServerError> {
+        let (written, confirmation) = oneshot::channel();
+        let frame = Frame {
+            data: frame,
+            droppable: false,
+            written: Some(written),
+        };
+        match timeout(wait, self.enqueue(frame)).await {
+            Ok(result) => result.map(|()| confirmation),
+            Err(_) => {
+                if self.shared.policy == OverflowPolicy::Disconnect {
+                    self.shared.close();
+                }
+                Err(ServerError::SlowConsumer)
+            }
+        }
+    }
+
+    /// Serializuje obálku a zařadí ji do fronty bez čekání.
+    ///
+    /// # Arguments
+    ///
+    /// * `envelope` - Obálka se zprávou k odeslání
+    pub fn send(&self, envelope: &Envelope) -> Result<(), ServerError> {
+        self.push(serialize_envelope(envelope)?)
+    }
+
+    /// Serializuje obálku a zařadí ji do fronty, případně počká na volné místo.
+    ///
+    /// # Arguments
+    ///
+    /// * `envelope` - Obálka se zprávou k odeslání
+    pub async fn send_wait(&self, envelope: &Envelope) -> Result<(), ServerError> {
+        self.push_wait(serialize_envelope(envelope)?).await
+    }
+
+    /// Serializuje obálku a zařadí ji do fronty, na volné místo čeká nejvýše danou dobu.
+    ///
+    /// # Arguments
+    ///
+    /// * `envelope` - Obálka se zprávou k odeslání
+    /// * `wait` - Nejdelší doba čekání na volné místo
+    pub async fn send_timeout(
+        &self,
+        envelope: &Envelope,
+        wait: Duration,
+    ) -> Result<oneshot::Receiver<()>, ServerError> {
+        self.push_timeout(serialize_envelope(envelope)?, wait).await
+    }
+
+    /// Počká, dokud se fronta nezavře kvůli odpojení nebo pomalému klientovi.
+    pub async fn closed(&self) {
+        loop {
+            let notified = self.shared.writable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.shared.state.lock().unwrap().closed {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Vrací počet zpráv zahozených kvůli plné frontě.
+    pub fn dropped(&self) -> u64 {
+        self.shared.state.lock().unwrap().dropped
+    }
+}
+
+impl Clone for OutboundQueue {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        OutboundQueue {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for OutboundQueue {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // Poslední odesílatel odešel, zapisovací úloha odešle zbytek fronty a skončí
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+impl OutboundReceiver {
+    /// Vyzvedne data dalšího rámce bez potvrzení zápisu, v testech místo zapisovací úlohy
+    #[cfg(test)]
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.next_frame().await.map(|frame| frame.data)
+    }
+
+    /// Vyzvedne další rámec k odeslání.
+    ///
+    /// # Returns
+    ///
+    /// Vrací `None`, pokud je fronta zavřená, nebo pokud je prázdná a žádný
+    /// odesílatel už neexistuje.
+    async fn next_frame(&mut self) -> Option<Frame> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if let Some(frame) = state.frames.pop_front() {
+                    drop(state);
+                    self.shared.writable.notify_waiters();
+                    return Some(frame);
+                }
+                if self.shared.senders.load(Ordering::SeqCst) == 0 {
+                    return None;
+                }
+            }
+            self.shared.readable.notified().await;
+        }
+    }
+}
+
+impl Drop for OutboundReceiver {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// Zapisovací úloha klienta, která odesílá rámce z jeho odchozí fronty.
+///
+/// # Arguments
+///
+/// * `receiver` - Přijímací strana odchozí fronty
+/// * `writer` - Zapisovací část spojení s klientem
+///
+/// # Errors
+///
+/// Vrací chybu, pokud se rámec nepodaří zapsat. Fronta se tím zavře a
+/// odesílatelé se o odpojení dozví při dalším vložení.
+pub async fn write_frames<W: AsyncWrite + Unpin>(
+    mut receiver: OutboundReceiver,
+    mut writer: FrameWriter<W>,
+) -> Result<(), ServerError> {
+    while let Some(frame) = receiver.next_frame().await {
+        writer.write_frame(&frame.data).await?;
+        if let Some(written) = frame.written {
+            // Odesílatel už na potvrzení čekat nemusí
+            let _ = written.send(());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::frame::FrameReader;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_newest_frames() -> Result<(), ServerError> {
+        let (queue, mut receiver) = outbound_queue(2, OverflowPolicy::DropOldest);
+        for frame in [b"1", b"2", b"3"] {
+            queue.push(frame.to_vec())?;
+        }
+        assert_eq!(queue.dropped(), 1);
+
+        drop(queue);
+        assert_eq!(receiver.recv().await, Some(b"2".to_vec()));
+        assert_eq!(receiver.recv().await, Some(b"3".to_vec()));
+        assert_eq!(receiver.recv().await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_waited_frames() -> Result<(), ServerError> {
+        let (queue, mut receiver) = outbound_queue(2, OverflowPolicy::DropOldest);
+        queue.push_wait(b"dm".to_vec()).await?;
+        queue.push(b"1".to_vec())?;
+        queue.push(b"2".to_vec())?;
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(receiver.recv().await, Some(b"dm".to_vec()));
+        assert_eq!(receiver.recv().await, Some(b"2".to_vec()));
+
+        // A queue holding only frames that must be kept drops the new frame instead
+        queue.push_wait(b"dm2".to_vec()).await?;
+        queue.push_wait(b"dm3".to_vec()).await?;
+        queue.push(b"3".to_vec())?;
+        assert_eq!(queue.dropped(), 2);
+
+        drop(queue);
+        assert_eq!(receiver.recv().await, Some(b"dm2".to_vec()));
+        assert_eq!(receiver.recv().await, Some(b"dm3".to_vec()));
+        assert_eq!(receiver.recv().await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_policy_closes_queue() -> Result<(), ServerError> {
+        let (queue, mut receiver) = outbound_queue(1, OverflowPolicy::Disconnect);
+        queue.push(b"1".to_vec())?;
+        assert!(matches!(
+            queue.push(b"2".to_vec()),
+            Err(ServerError::SlowConsumer)
+        ));
+        assert!(matches!(
+            queue.push(b"3".to_vec()),
+            Err(ServerError::ConnectionClosed)
+        ));
+        queue.closed().await;
+        // Pending frames of a disconnected client are discarded
+        assert_eq!(receiver.recv().await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_push_timeout_applies_policy() -> Result<(), ServerError> {
+        let wait = Duration::from_millis(20);
+        let (queue, mut receiver) = outbound_queue(1, OverflowPolicy::DropOldest);
+        queue.push_timeout(b"1".to_vec(), wait).await?;
+        assert!(matches!(
+            queue.push_timeout(b"2".to_vec(), wait).await,
+            Err(ServerError::SlowConsumer)
+        ));
+        // The frame that did not fit is left out, the client stays connected
+        queue.push(b"3".to_vec())?;
+        assert_eq!(receiver.recv().await, Some(b"1".to_vec()));
+
+        let (queue, mut receiver) = outbound_queue(1, OverflowPolicy::Disconnect);
+        queue.push_timeout(b"1".to_vec(), wait).await?;
+        assert!(matches!(
+            queue.push_timeout(b"2".to_vec(), wait).await,
+            Err(ServerError::SlowConsumer)
+        ));
+        assert_eq!(receiver.recv().await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_push_timeout_confirms_written_frames() -> Result<(), ServerError> {
+        let wait = Duration::from_millis(20);
+        let (queue, receiver) = outbound_queue(4, OverflowPolicy::DropOldest);
+        let (local, remote) = duplex(1024);
+        let confirmation = queue.push_timeout(b"dm".to_vec(), wait).await?;
+        tokio::spawn(write_frames(receiver, FrameWriter::new(local)));
+        assert!(confirmation.await.is_ok());
+        assert_eq!(
+            FrameReader::new(remote).read_frame().await?,
+            Some(b"dm".to_vec())
+        );
+
+        // Frames discarded with a closed queue are never confirmed
+        let (queue, receiver) = outbound_queue(4, OverflowPolicy::DropOldest);
+        let confirmation = queue.push_timeout(b"dm".to_vec(), wait).await?;
+        drop(receiver);
+        assert!(confirmation.await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_push_wait_applies_backpressure() -> Result<(), ServerError> {
+        let (queue, receiver) = outbound_queue(1, OverflowPolicy::Disconnect);
+        let (local, remote) = duplex(1024);
+        let writer = tokio::spawn(write_frames(receiver, FrameWriter::new(local)));
+
+        // Waiting for space never trips the disconnect policy
+        for i in 0..10u8 {
+            queue.push_wait(vec![i]).await?;
+        }
+        drop(queue);
+
+        let mut reader = FrameReader::new(remote);
+        for i in 0..10u8 {
+            assert_eq!(reader.read_frame().await?, Some(vec![i]));
+        }
+        assert_eq!(reader.read_frame().await?, None);
+        writer.await.unwrap()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use shared::server_error::ServerError;
+use shared::DEFAULT_ROOM;
+use sqlx::sqlite::SqlitePool;
+use sqlx::Row;
+
+/// Maximální délka názvu místnosti
+const MAX_ROOM_NAME_LENGTH: usize = 32;
+
+/// Ověří, že název místnosti obsahuje pouze povolené znaky.
+///
+/// # Arguments
+///
+/// * `name` - Název místnosti k ověření
+pub fn validate_room_name(name: &str) -> Result<(), ServerError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if !valid {
+        return Err(ServerError::InvalidRequest(format!(
+            "Room name must be 1-{} characters of letters, digits, '_' or '-'",
+            MAX_ROOM_NAME_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Vytvoří tabulku místností a výchozí místnost.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+pub async fn init_rooms(pool: &SqlitePool) -> Result<(), ServerError> {
+    sqlx::query(
+        "
+        CREATE TABLE IF NOT EXISTS rooms (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE,
+            created_by INTEGER,
+            created_at INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY(created_by) REFERENCES users(id)
+        );
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("INSERT OR IGNORE INTO rooms (name) VALUES (?)")
+        .bind(DEFAULT_ROOM)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Zajistí existenci místnosti, neexistující místnost vytvoří.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `name` - Název místnosti
+/// * `user_id` - ID uživatele, který místnost případně zakládá
+/// * `timestamp` - Čas založení v sekundách od počátku unixové epochy
+pub async fn ensure_room(
+    pool: &SqlitePool,
+    name: &str,
+    user_id: i64,
+    timestamp: i64,
+) -> Result<(), ServerError> {
+    validate_room_name(name)?;
+    sqlx::query("INSERT OR IGNORE INTO rooms (name, created_by, created_at) VALUES (?, ?, ?)")
+        .bind(name)
+        .bind(user_id)
+        .bind(timestamp)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Vrací názvy všech místností seřazené podle abecedy.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+pub async fn list_rooms(pool: &SqlitePool) -> Result<Vec<String>, ServerError> {
+    let rows = sqlx::query("SELECT name FROM rooms ORDER BY name")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(|row| row.get("name")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_db;
+
+    #[tokio::test]
+    async fn test_rooms() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+
+        assert_eq!(list_rooms(&pool).await?, vec![DEFAULT_ROOM.to_string()]);
+
+        sqlx::query("INSERT INTO users (id, username) VALUES (1, 'alice'), (2, 'bob')")
+            .execute(&pool)
+            .await?;
+
+        ensure_room(&pool, "backend", 1, 0).await?;
+        ensure_room(&pool, "backend", 2, 0).await?;
+        ensure_room(&pool, "design", 1, 0).await?;
+        assert_eq!(
+            list_rooms(&pool).await?,
+            vec!["backend", "design", DEFAULT_ROOM]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_room_name() {
+        assert!(validate_room_name("team-1_backend").is_ok());
+        assert!(validate_room_name("").is_err());
+        assert!(validate_room_name("two words").is_err());
+        assert!(validate_room_name(&"x".repeat(MAX_ROOM_NAME_LENGTH + 1)).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::history_entry;
+use shared::server_error::ServerError;
+use shared::{HistoryEntry, SearchQuery};
+use sqlx::sqlite::SqlitePool;
+use sqlx::Row;
+
+/// Vytvoří fulltextový index nad tabulkou `messages`.
+///
+/// Index se udržuje triggery při každé změně tabulky. Pokud vzniká poprvé,
+/// naplní se zprávami uloženými starší verzí serveru.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+pub async fn init_search(pool: &SqlitePool) -> Result<(), ServerError> {
+    let exists = sqlx::query("SELECT 1 FROM sqlite_master WHERE name = 'messages_fts'")
+        .fetch_optional(pool)
+        .await?
+        .is_some();
+
+    sqlx::query(
+        "
+        CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+            content, filename, content='messages', content_rowid='id'
+        );
+        CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts(rowid, content, filename)
+                VALUES (new.id, new.content, new.filename);
+        END;
+        CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts(messages_fts, rowid, content, filename)
+                VALUES ('delete', old.id, old.content, old.filename);
+        END;
+        CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE ON messages BEGIN
+            INSERT INTO messages_fts(messages_fts, rowid, content, filename)
+                VALUES ('delete', old.id, old.content, old.filename);
+            INSERT INTO messages_fts(rowid, content, filename)
+                VALUES (new.id, new.content, new.filename);
+        END;
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    if !exists {
+        sqlx::query("INSERT INTO messages_fts(messages_fts) VALUES ('rebuild')")
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Převede hledaný text na dotaz FTS5.
+///
+/// Každé slovo se uzavře do uvozovek, takže znaky se zvláštním významem
+/// v syntaxi FTS5 nemohou způsobit chybu dotazu. Zpráva musí obsahovat všechna slova.
+fn match_expression(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Vyhledá zprávy odpovídající dotazu.
+///
+/// Hledá jen v místnostech, do kterých hledající vstoupil, stejně jako se
+/// omezuje stahování příloh.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `query` - Hledaná slova a filtry
+/// * `rooms` - Místnosti, ve kterých se smí hledat
+/// * `page_size` - Počet zpráv na jedné stránce výsledků
+///
+/// # Returns
+///
+/// Vrací požadovanou stránku výsledků seřazenou od nejnovější zprávy a celkový počet nalezených zpráv.
+pub async fn search_messages(
+    pool: &SqlitePool,
+    query: &SearchQuery,
+    rooms: &[String],
+    page_size: u32,
+) -> Result<(Vec<HistoryEntry>, u32), ServerError> {
+    const FILTER: &str = "
+        (?1 = '' OR id IN (SELECT rowid FROM messages_fts WHERE messages_fts MATCH ?1))
+        AND (?2 IS NULL OR sender = ?2)
+        AND (?3 IS NULL OR room = ?3)
+        AND (?4 IS NULL OR timestamp >= ?4)
+        AND (?5 IS NULL OR timestamp < ?5)
+        AND room IN (SELECT value FROM json_each(?6))";
+    let expression = match_expression(&query.text);
+    let rooms = serde_json::to_string(rooms).map_err(|e| ServerError::Other(e.to_string()))?;
+    let offset = (query.page.max(1) - 1).saturating_mul(page_size);
+
+    let total: i64 = sqlx::query(&format!(
+        "SELECT COUNT(*) AS total FROM messages WHERE {}",
+        FILTER
+    ))
+    .bind(&expression)
+    .bind(&query.sender)
+    .bind(&query.room)
+    .bind(query.since)
+    .bind(query.until)
+    .bind(&rooms)
+    .fetch_one(pool)
+    .await?
+    .get("total");
+
+    let rows = sqlx::query(&format!(
+        "SELECT id, sender, room, timestamp, kind, content, filename, size FROM messages
+         WHERE {} ORDER BY id DESC LIMIT ?7 OFFSET ?8",
+        FILTER
+    ))
+    .bind(&expression)
+    .bind(&query.sender)
+    .bind(&query.room)
+    .bind(query.since)
+    .bind(query.until)
+    .bind(&rooms)
+    .bind(page_size)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+
+    Ok((rows.iter().map(history_entry).collect(), total as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_db;
+
+    /// Inserts a text message and returns its ID
+    async fn insert(
+        pool: &SqlitePool,
+        sender: &str,
+        room: &str,
+        timestamp: i64,
+        text: &str,
+    ) -> i64 {
+        sqlx::query(
+            "INSERT INTO messages (user_id, sender, room, kind, content, timestamp)
+             VALUES (1, ?, ?, 'text', ?, ?)",
+        )
+        .bind(sender)
+        .bind(room)
+        .bind(text)
+        .bind(timestamp)
+        .execute(pool)
+        .await
+        .unwrap()
+        .last_insert_rowid()
+    }
+
+    /// Rooms the searching client has joined in these tests
+    fn joined() -> Vec<String> {
+        vec!["general".to_string(), "dev".to_string()]
+    }
+
+    fn search(text: &str) -> SearchQuery {
+        SearchQuery {
+            text: text.to_string(),
+            page: 1,
+            ..SearchQuery::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_messages() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+        sqlx::query("INSERT INTO users (id, username) VALUES (1, 'alice')")
+            .execute(&pool)
+            .await?;
+        let deploy = insert(&pool, "alice", "general", 100, "Deploy is done").await;
+        insert(&pool, "bob", "dev", 200, "the deploy failed again").await;
+        insert(&pool, "alice", "dev", 300, "lunch?").await;
+        let latest = insert(&pool, "bob", "general", 400, "deploy retry worked").await;
+
+        let (entries, total) = search_messages(&pool, &search("deploy"), &joined(), 10).await?;
+        assert_eq!(total, 3);
+        assert_eq!(entries[0].id, latest);
+        assert_eq!(entries[2].id, deploy);
+
+        // All words must match, in any order and case
+        let (entries, _) = search_messages(&pool, &search("DONE deploy"), &joined(), 10).await?;
+        assert_eq!(entries.len(), 1);
+
+        let filtered = SearchQuery {
+            sender: Some("bob".to_string()),
+            room: Some("dev".to_string()),
+            ..search("deploy")
+        };
+        let (entries, total) = search_messages(&pool, &filtered, &joined(), 10).await?;
+        assert_eq!((entries.len(), total), (1, 1));
+        assert_eq!(entries[0].content, "the deploy failed again");
+
+        let range = SearchQuery {
+            since: Some(200),
+            until: Some(400),
+            ..search("")
+        };
+        let (entries, _) = search_messages(&pool, &range, &joined(), 10).await?;
+        assert_eq!(entries.len(), 2);
+
+        // Pages follow each other and the total stays the same
+        let second = SearchQuery {
+            page: 2,
+            ..search("deploy")
+        };
+        let (entries, total) = search_messages(&pool, &second, &joined(), 2).await?;
+        assert_eq!((entries.len(), total), (1, 3));
+        assert_eq!(entries[0].id, deploy);
+
+        // FTS5 syntax in the query is searched for literally instead of failing
+        let (entries, _) =
+            search_messages(&pool, &search("\"deploy OR lunch* ("), &joined(), 10).await?;
+        assert!(entries.is_empty());
+
+        // Deleted messages disappear from the index
+        sqlx::query("DELETE FROM messages WHERE id = ?")
+            .bind(latest)
+            .execute(&pool)
+            .await?;
+        assert_eq!(
+            search_messages(&pool, &search("deploy"), &joined(), 10)
+                .await?
+                .1,
+            2
+        );
+
+        // Rooms the client has not joined are not searched, even when asked for
+        insert(&pool, "carol", "private", 500, "deploy secrets").await;
+        assert_eq!(
+            search_messages(&pool, &search("deploy"), &joined(), 10)
+                .await?
+                .1,
+            2
+        );
+        let private = SearchQuery {
+            room: Some("private".to_string()),
+            ..search("deploy")
+        };
+        assert_eq!(search_messages(&pool, &private, &joined(), 10).await?.1, 0);
+        let rooms = vec!["private".to_string()];
+        assert_eq!(search_messages(&pool, &private, &rooms, 10).await?.1, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_existing_messages_are_indexed() -> Result<(), ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+        sqlx::query("INSERT INTO users (id, username) VALUES (1, 'alice')")
+            .execute(&pool)
+            .await?;
+        insert(
+            &pool,
+            "alice",
+            "general",
+            100,
+            "stored before the index existed",
+        )
+        .await;
+
+        // Simulate a database created before search was added
+        sqlx::query("DROP TABLE messages_fts")
+            .execute(&pool)
+            .await?;
+        init_search(&pool).await?;
+
+        assert_eq!(
+            search_messages(&pool, &search("stored"), &joined(), 10)
+                .await?
+                .1,
+            1
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,718 @@
+use crate::attachments;
+use crate::auth::User;
+use shared::checksum::{is_sha256_hex, sha256_file};
+use shared::protocol::Envelope;
+use shared::server_error::ServerError;
+use shared::MessageType;
+use sqlx::sqlite::SqlitePool;
+use sqlx::Row;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+/// Výchozí největší velikost souboru v bajtech, který server přijme
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Vytvoří tabulku přenosů souborů.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+pub async fn init_transfers(pool: &SqlitePool) -> Result<(), ServerError> {
+    sqlx::query(
+        "
+        CREATE TABLE IF NOT EXISTS transfers (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            sender_id INTEGER NOT NULL,
+            sender TEXT NOT NULL,
+            room TEXT NOT NULL,
+            filename TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            image INTEGER NOT NULL DEFAULT 0,
+            sha256 TEXT NOT NULL,
+            completed INTEGER NOT NULL DEFAULT 0,
+            timestamp INTEGER NOT NULL,
+            FOREIGN KEY(sender_id) REFERENCES users(id)
+        );
+        CREATE INDEX IF NOT EXISTS idx_transfers_resume ON transfers(sender_id, sha256, size);
+        ",
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Přenos souboru uložený na serveru
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredTransfer {
+    pub id: i64,
+    pub sender: String,
+    pub room: String,
+    pub filename: String,
+    pub size: u64,
+    pub image: bool,
+    pub sha256: String,
+    /// Zda server přijal celý soubor a ověřil jeho kontrolní součet
+    pub completed: bool,
+    pub timestamp: i64,
+}
+
+impl StoredTransfer {
+    /// Vytvoří nabídku souboru, kterou server posílá příjemcům
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - Verze protokolu dohodnutá s příjemcem
+    pub fn offer(&self, version: u16) -> Envelope {
+        Envelope {
+            version,
+            id: 0,
+            sender: self.sender.clone(),
+            timestamp: self.timestamp,
+            room: self.room.clone(),
+            payload: MessageType::FileOffer {
+                transfer_id: self.id as u64,
+                filename: self.filename.clone(),
+                size: self.size,
+                image: self.image,
+                sha256: self.sha256.clone(),
+            },
+        }
+    }
+}
+
+/// Načte přenos souboru podle jeho serverového ID.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool pro připojení k SQLite databázi
+/// * `id` - ID přenosu
+pub async fn load_transfer(
+    pool: &SqlitePool,
+    id: i64,
+) -> Result<Option<StoredTransfer>, ServerError> {
+    let row = sqlx::query(
+        "SELECT id, sender, room, filename, size, image, sha256, completed, timestamp
+         FROM transfers WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| StoredTransfer {
+        id: row.get("id"),
+        sender: row.get("sender"),
+        room: row.get("room"),
+        filename: row.get("filename"),
+        size: row.get::<i64, _>("size") as u64,
+        image: row.get("image"),
+        sha256: row.get("sha256"),
+        completed: row.get("completed"),
+        timestamp: row.get("timestamp"),
+    }))
+}
+
+/// Otevře data přenosu pro čtení, ať už je přenos dokončený, nebo rozpracovaný.
+///
+/// Data dokončeného přenosu jsou v úložišti příloh pod svým SHA-256.
+///
+/// # Arguments
+///
+/// * `upload_dir` - Adresář s přijatými soubory
+/// * `transfer` - Přenos, jehož data se čtou
+pub async fn open_data(upload_dir: &Path, transfer: &StoredTransfer) -> Result<File, ServerError> {
+    if !transfer.completed {
+        return Ok(File::open(spool_path(upload_dir, transfer.id)).await?);
+    }
+    match attachments::open(upload_dir, &transfer.sha256).await {
+        Ok(file) => Ok(file),
+        // Přenosy dokončené před zavedením úložiště příloh jsou uložené podle ID
+        Err(_) => Ok(File::open(upload_dir.join(transfer.id.to_string())).await?),
+    }
+}
+
+/// Uloží přílohu poslanou celou v jedné zprávě jako dokončený přenos.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool s přenosy a přílohami
+/// * `upload_dir` - Adresář s přijatými soubory
+/// * `envelope` - Obálka se zprávou `MessageType::Image` nebo `MessageType::File`,
+///   metadata už musí být doplněna serverem
+/// * `sender` - Přihlášený uživatel, který přílohu poslal
+///
+/// # Returns
+///
+/// Vrací obálku s odkazem na uloženou přílohu, kterou server pošle příjemcům místo dat.
+pub async fn store_inline(
+    pool: &SqlitePool,
+    upload_dir: &Path,
+    envelope: &Envelope,
+    sender: &User,
+) -> Result<Envelope, ServerError> {
+    let (filename, data, image) = match &envelope.payload {
+        MessageType::Image { filename, data, .. } => (filename, data, true),
+        MessageType::File(filename, data) => (filename, data, false),
+        _ => {
+            return Err(ServerError::InvalidRequest(
+                "Message does not carry an attachment".to_string(),
+            ))
+        }
+    };
+
+    let sha256 = attachments::store_bytes(pool, upload_dir, data).await?;
+    let size = data.len() as u64;
+    let id = sqlx::query(
+        "INSERT INTO transfers
+            (sender_id, sender, room, filename, size, image, sha256, completed, timestamp)
+         VALUES (?, ?, ?, ?, ?, ?, ?, 1, ?)",
+    )
+    .bind(sender.id)
+    .bind(&sender.username)
+    .bind(&envelope.room)
+    .bind(filename)
+    .bind(size as i64)
+    .bind(image)
+    .bind(&sha256)
+    .bind(envelope.timestamp)
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+
+    Ok(Envelope {
+        payload: MessageType::Attachment {
+            transfer_id: id as u64,
+            filename: filename.clone(),
+            size,
+            image,
+            sha256,
+        },
+        ..envelope.clone()
+    })
+}
+
+/// Vytvoří z nabídky souboru odkaz na uložený soubor, který server posílá příjemcům
+fn attachment(offer: &Envelope) -> Envelope {
+    let payload = match &offer.payload {
+        MessageType::FileOffer {
+            transfer_id,
+            filename,
+            size,
+            image,
+            sha256,
+        } => MessageType::Attachment {
+            transfer_id: *transfer_id,
+            filename: filename.clone(),
+            size: *size,
+            image: *image,
+            sha256: sha256.clone(),
+        },
+        other => other.clone(),
+    };
+    Envelope {
+        payload,
+        ..offer.clone()
+    }
+}
+
+/// Cesta k datům rozpracovaného přenosu
+fn spool_path(upload_dir: &Path, id: i64) -> PathBuf {
+    upload_dir.join(format!("{}.part", id))
+}
+
+/// Výsledek zpracování zprávy přenosu
+#[derive(Debug, PartialEq)]
+pub enum Processed {
+    /// Server přijal nabídku; odesílatel má pokračovat od pozice `offset`
+    Accepted { offset: u64 },
+    /// Přenos pokračuje, příjemcům se zatím nic neposílá
+    Pending,
+    /// Přenos byl dokončen a ověřen, obsahuje odkaz na uložený soubor pro příjemce
+    Completed(Envelope),
+}
+
+/// Přenos souboru, který klient právě odesílá
+struct ActiveTransfer {
+    /// ID přenosu přidělené serverem
+    id: i64,
+    /// Nabídka souboru s metadaty doplněnými serverem
+    offer: Envelope,
+    size: u64,
+    received: u64,
+    sha256: String,
+    /// Rozpracovaná data; `None`, pokud server stejný obsah už má uložený
+    file: Option<File>,
+}
+
+/// Přenosy souborů odesílané jedním spojením.
+///
+/// Přijatá data se průběžně zapisují do adresáře pro nahrané soubory, takže
+/// server drží v paměti jen jednu část souboru a přerušený přenos lze po
+/// opětovném připojení dokončit. Dokončený soubor se přesune do úložiště
+/// příloh; obsah, který server už má, se znovu nenahrává. ID přenosů zvolená
+/// klientem se přepisují na serverová, aby se přenosy různých klientů u
+/// příjemců nepletly.
+pub struct Transfers {
+    upload_dir: PathBuf,
+    /// Největší soubor v bajtech, který server přijme
+    max_size: u64,
+    active: HashMap<u64, ActiveTransfer>,
+}
+
+impl Transfers {
+    /// Vytvoří prázdný seznam přenosů
+    ///
+    /// # Arguments
+    ///
+    /// * `upload_dir` - Adresář, do kterého se ukládají přijatá data
+    /// * `max_size` - Největší soubor v bajtech, který server přijme
+    pub fn new(upload_dir: impl Into<PathBuf>, max_size: u64) -> Self {
+        Transfers {
+            upload_dir: upload_dir.into(),
+            max_size,
+            active: HashMap::new(),
+        }
+    }
+
+    /// Zpracuje zprávu přenosu souboru a přepíše v ní ID přenosu na serverové.
+    ///
+    /// Nabídka souboru, který už stejný uživatel začal nahrávat, na nedokončený
+    /// přenos naváže.
+    ///
+    /// # Arguments
+    ///
+    /// * `pool` - Databázový pool s přenosy
+    /// * `envelope` - Obálka se zprávou přenosu, metadata už musí být doplněna serverem
+    /// * `sender` - Přihlášený uživatel, který soubor odesílá
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ServerError::InvalidRequest`, pokud zpráva neodpovídá stavu přenosu,
+    /// nabízený soubor je větší než povolený limit nebo soubor neodpovídá
+    /// kontrolnímu součtu.
+    pub async fn process(
+        &mut self,
+        pool: &SqlitePool,
+        envelope: &mut Envelope,
+        sender: &User,
+    ) -> Result<Processed, ServerError> {
+        let room = envelope.room.clone();
+        let timestamp = envelope.timestamp;
+        match &mut envelope.payload {
+            MessageType::FileOffer {
+                transfer_id,
+                filename,
+                size,
+                image,
+                sha256,
+            } => {
+                if self.active.contains_key(transfer_id) {
+                    return Err(ServerError::InvalidRequest(format!(
+                        "Transfer {} is already in progress",
+                        transfer_id
+                    )));
+                }
+                if *size > self.max_size {
+                    return Err(ServerError::InvalidRequest(format!(
+                        "{} is larger than the server limit of {} bytes",
+                        filename, self.max_size
+                    )));
+                }
+                if !is_sha256_hex(sha256) {
+                    return Err(ServerError::InvalidRequest(
+                        "File offer does not carry a valid SHA-256 hash".to_string(),
+                    ));
+                }
+                *sha256 = sha256.to_ascii_lowercase();
+
+                let resumed = sqlx::query(
+                    "SELECT id FROM transfers
+                     WHERE sender_id = ? AND sha256 = ? AND size = ? AND completed = 0
+                     ORDER BY id DESC LIMIT 1",
+                )
+                .bind(sender.id)
+                .bind(&*sha256)
+                .bind(*size as i64)
+                .fetch_optional(pool)
+                .await?;
+
+                let id = match resumed {
+                    Some(row) => {
+                        let id: i64 = row.get("id");
+                        sqlx::query("UPDATE transfers SET room = ?, filename = ? WHERE id = ?")
+                            .bind(&room)
+                            .bind(&*filename)
+                            .bind(id)
+                            .execute(pool)
+                            .await?;
+                        id
+                    }
+                    None => sqlx::query(
+                        "INSERT INTO transfers
+                            (sender_id, sender, room, filename, size, image, sha256, timestamp)
+                         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                    )
+                    .bind(sender.id)
+                    .bind(&sender.username)
+                    .bind(&room)
+                    .bind(&*filename)
+                    .bind(*size as i64)
+                    .bind(*image)
+                    .bind(&*sha256)
+                    .bind(timestamp)
+                    .execute(pool)
+                    .await?
+                    .last_insert_rowid(),
+                };
+
+                let spool = spool_path(&self.upload_dir, id);
+                let (file, received) =
+                    if attachments::contains(pool, &self.upload_dir, sha256, *size).await? {
+                        // Odesílatel rovnou pošle konec přenosu, data už na serveru jsou
+                        let _ = tokio::fs::remove_file(&spool).await;
+                        (None, *size)
+                    } else {
+                        let file = OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(&spool)
+                            .await?;
+                        let mut received = file.metadata().await?.len();
+                        if received > *size {
+                            file.set_len(0).await?;
+                            received = 0;
+                        }
+                        (Some(file), received)
+                    };
+
+                let local_id = *transfer_id;
+                let (size, sha256) = (*size, sha256.clone());
+                *transfer_id = id as u64;
+                self.active.insert(
+                    local_id,
+                    ActiveTransfer {
+                        id,
+                        offer: envelope.clone(),
+                        size,
+                        received,
+                        sha256,
+                        file,
+                    },
+                );
+                Ok(Processed::Accepted { offset: received })
+            }
+            MessageType::FileChunk {
+                transfer_id,
+                offset,
+                data,
+            } => {
+                let transfer = self
+                    .active
+                    .get_mut(transfer_id)
+                    .ok_or_else(|| unknown_transfer(*transfer_id))?;
+
+                if *offset != transfer.received {
+                    return Err(ServerError::InvalidRequest(format!(
+                        "Transfer {} expected offset {}, got {}",
+                        transfer_id, transfer.received, offset
+                    )));
+                }
+                let received = transfer.received + data.len() as u64;
+                if received > transfer.size {
+                    return Err(ServerError::InvalidRequest(format!(
+                        "Transfer {} exceeds its announced size of {} bytes",
+                        transfer_id, transfer.size
+                    )));
+                }
+
+                if let Some(file) = &mut transfer.file {
+                    file.write_all(data).await?;
+                    file.flush().await?;
+                }
+                transfer.received = received;
+                *transfer_id = transfer.id as u64;
+                envelope.room = transfer.offer.room.clone();
+                Ok(Processed::Pending)
+            }
+            MessageType::FileComplete { transfer_id } => {
+                let transfer = self
+                    .active
+                    .get(transfer_id)
+                    .ok_or_else(|| unknown_transfer(*transfer_id))?;
+
+                if transfer.received != transfer.size {
+                    return Err(ServerError::InvalidRequest(format!(
+                        "Transfer {} ended after {} of {} bytes",
+                        transfer_id, transfer.received, transfer.size
+                    )));
+                }
+                let spool = spool_path(&self.upload_dir, transfer.id);
+                if transfer.file.is_some() && sha256_file(&spool).await? != transfer.sha256 {
+                    return Err(ServerError::InvalidRequest(format!(
+                        "Transfer {} does not match its SHA-256 hash",
+                        transfer_id
+                    )));
+                }
+
+                let transfer = self.active.remove(transfer_id).unwrap();
+                if let Some(file) = transfer.file {
+                    drop(file);
+                    attachments::store_file(
+                        pool,
+                        &self.upload_dir,
+                        &spool,
+                        &transfer.sha256,
+                        transfer.size,
+                    )
+                    .await?;
+                }
+                sqlx::query("UPDATE transfers SET completed = 1 WHERE id = ?")
+                    .bind(transfer.id)
+                    .execute(pool)
+                    .await?;
+
+                *transfer_id = transfer.id as u64;
+                envelope.room = transfer.offer.room.clone();
+                Ok(Processed::Completed(attachment(&transfer.offer)))
+            }
+            MessageType::FileAbort { transfer_id, .. } => {
+                let transfer = self
+                    .active
+                    .remove(transfer_id)
+                    .ok_or_else(|| unknown_transfer(*transfer_id))?;
+                self.discard(pool, transfer.id).await?;
+
+                *transfer_id = transfer.id as u64;
+                envelope.room = transfer.offer.room.clone();
+                Ok(Processed::Pending)
+            }
+            _ => Ok(Processed::Pending),
+        }
+    }
+
+    /// Zruší přenos a smaže jeho data; příjemci o nedokončeném přenosu nevědí.
+    ///
+    /// # Arguments
+    ///
+    /// * `pool` - Databázový pool s přenosy
+    /// * `transfer_id` - ID přenosu zvolené klientem
+    ///
+    /// # Returns
+    ///
+    /// Vrací `false`, pokud takový přenos neprobíhá.
+    pub async fn abort(
+        &mut self,
+        pool: &SqlitePool,
+        transfer_id: u64,
+    ) -> Result<bool, ServerError> {
+        let Some(transfer) = self.active.remove(&transfer_id) else {
+            return Ok(false);
+        };
+        self.discard(pool, transfer.id).await?;
+        Ok(true)
+    }
+
+    /// Smaže data a záznam přenosu
+    async fn discard(&self, pool: &SqlitePool, id: i64) -> Result<(), ServerError> {
+        let _ = tokio::fs::remove_file(spool_path(&self.upload_dir, id)).await;
+        sqlx::query("DELETE FROM transfers WHERE id = ?")
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Vrací ID přenosu zvolené klientem, pokud jde o zprávu přenosu souboru
+///
+/// # Arguments
+///
+/// * `payload` - Obsah zprávy
+pub fn transfer_id(payload: &MessageType) -> Option<u64> {
+    match payload {
+        MessageType::FileOffer { transfer_id, .. }
+        | MessageType::FileChunk { transfer_id, .. }
+        | MessageType::FileComplete { transfer_id }
+        | MessageType::FileAbort { transfer_id, .. } => Some(*transfer_id),
+        _ => None,
+    }
+}
+
+fn unknown_transfer(transfer_id: u64) -> ServerError {
+    ServerError::InvalidRequest(format!("Unknown transfer {}", transfer_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_db;
+    use shared::DEFAULT_ROOM;
+
+    /// SHA-256 of "hello"
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    struct Fixture {
+        pool: SqlitePool,
+        /// Upload directory, removed when the test ends
+        uploads: tempfile::TempDir,
+        user: User,
+    }
+
+    async fn fixture() -> Result<Fixture, ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        init_db(&pool).await?;
+        sqlx::query("INSERT INTO users (id, username) VALUES (1, 'alice')")
+            .execute(&pool)
+            .await?;
+
+        let user = User {
+            id: 1,
+            username: "alice".to_string(),
+        };
+        Ok(Fixture {
+            pool,
+            uploads: tempfile::tempdir()?,
+            user,
+        })
+    }
+
+    fn envelope(payload: MessageType) -> Envelope {
+        Envelope::new(payload, DEFAULT_ROOM)
+    }
+
+    fn offer(sha256: &str) -> Envelope {
+        envelope(MessageType::FileOffer {
+            transfer_id: 7,
+            filename: "hello.txt".to_string(),
+            size: 5,
+            image: false,
+            sha256: sha256.to_string(),
+        })
+    }
+
+    fn chunk(offset: u64, data: &[u8]) -> Envelope {
+        envelope(MessageType::FileChunk {
+            transfer_id: 7,
+            offset,
+            data: data.to_vec(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_transfer_lifecycle() -> Result<(), ServerError> {
+        let Fixture {
+            pool,
+            uploads,
+            user,
+        } = fixture().await?;
+        let upload_dir = uploads.path();
+        let mut transfers = Transfers::new(upload_dir, 1024);
+
+        let mut first_offer = offer(HELLO_SHA256);
+        let result = transfers.process(&pool, &mut first_offer, &user).await?;
+        assert_eq!(result, Processed::Accepted { offset: 0 });
+        let id = transfer_id(&first_offer.payload).unwrap();
+
+        let mut first = chunk(0, b"hel");
+        transfers.process(&pool, &mut first, &user).await?;
+        assert_eq!(transfer_id(&first.payload), Some(id));
+
+        // The client has to send the chunks in order and within the announced size
+        assert!(transfers
+            .process(&pool, &mut chunk(0, b"lo"), &user)
+            .await
+            .is_err());
+        assert!(transfers
+            .process(&pool, &mut chunk(3, b"lo!"), &user)
+            .await
+            .is_err());
+
+        // After a reconnect the upload continues where the server left off
+        let mut transfers = Transfers::new(upload_dir, 1024);
+        let mut resumed_offer = offer(HELLO_SHA256);
+        let result = transfers.process(&pool, &mut resumed_offer, &user).await?;
+        assert_eq!(result, Processed::Accepted { offset: 3 });
+        assert_eq!(transfer_id(&resumed_offer.payload), Some(id));
+
+        transfers
+            .process(&pool, &mut chunk(3, b"lo"), &user)
+            .await?;
+        let mut complete = envelope(MessageType::FileComplete { transfer_id: 7 });
+        let result = transfers.process(&pool, &mut complete, &user).await?;
+        assert_eq!(result, Processed::Completed(attachment(&resumed_offer)));
+
+        let stored = load_transfer(&pool, id as i64).await?.unwrap();
+        assert!(stored.completed);
+        let path = attachments::blob_path(upload_dir, HELLO_SHA256)?;
+        assert_eq!(std::fs::read(path)?, b"hello");
+
+        // The same content uploaded again is not sent a second time
+        let mut transfers = Transfers::new(upload_dir, 1024);
+        let mut repeated_offer = offer(HELLO_SHA256);
+        let result = transfers.process(&pool, &mut repeated_offer, &user).await?;
+        assert_eq!(result, Processed::Accepted { offset: 5 });
+        assert_ne!(transfer_id(&repeated_offer.payload), Some(id));
+        let mut complete = envelope(MessageType::FileComplete { transfer_id: 7 });
+        assert!(matches!(
+            transfers.process(&pool, &mut complete, &user).await?,
+            Processed::Completed(Envelope {
+                payload: MessageType::Attachment { .. },
+                ..
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_transfer_is_rejected() -> Result<(), ServerError> {
+        let Fixture {
+            pool,
+            uploads,
+            user,
+        } = fixture().await?;
+        let upload_dir = uploads.path();
+        let mut transfers = Transfers::new(upload_dir, 1024);
+
+        assert!(transfers
+            .process(&pool, &mut offer("not a hash"), &user)
+            .await
+            .is_err());
+
+        // Offers above the server limit are refused before anything is stored
+        let mut small_limit = Transfers::new(upload_dir, 4);
+        assert!(matches!(
+            small_limit
+                .process(&pool, &mut offer(HELLO_SHA256), &user)
+                .await,
+            Err(ServerError::InvalidRequest(_))
+        ));
+        let stored: i64 = sqlx::query("SELECT COUNT(*) AS count FROM transfers")
+            .fetch_one(&pool)
+            .await?
+            .get("count");
+        assert_eq!(stored, 0);
+
+        let mut bad_offer = offer(&"0".repeat(64));
+        transfers.process(&pool, &mut bad_offer, &user).await?;
+        let id = transfer_id(&bad_offer.payload).unwrap() as i64;
+        transfers
+            .process(&pool, &mut chunk(0, b"hello"), &user)
+            .await?;
+
+        let mut complete = envelope(MessageType::FileComplete { transfer_id: 7 });
+        assert!(transfers
+            .process(&pool, &mut complete, &user)
+            .await
+            .is_err());
+
+        assert!(transfers.abort(&pool, 7).await?);
+        assert_eq!(load_transfer(&pool, id).await?, None);
+        assert!(!spool_path(upload_dir, id).exists());
+        assert!(!transfers.abort(&pool, 7).await?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// Velikost bufferu při čtení souboru pro výpočet kontrolního součtu
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Spočítá SHA-256 obsahu souboru.
+///
+/// Soubor se čte postupně, takže i velké soubory zaberou v paměti jen velikost bufferu.
+///
+/// # Arguments
+///
+/// * `path` - Cesta k souboru
+///
+/// # Returns
+///
+/// Vrací kontrolní součet v hexadecimálním zápisu malými písmeny.
+pub async fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Spočítá SHA-256 dat v paměti.
+///
+/// # Returns
+///
+/// Vrací kontrolní součet v hexadecimálním zápisu malými písmeny.
+pub fn sha256_bytes(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Ověří, že text je SHA-256 v hexadecimálním zápisu.
+///
+/// # Arguments
+///
+/// * `value` - Ověřovaný text
+pub fn is_sha256_hex(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sha256_file() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("abc.txt");
+        tokio::fs::write(&path, b"abc").await?;
+
+        let hash = sha256_file(&path).await?;
+        assert_eq!(
+            hash,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(sha256_bytes(b"abc"), hash);
+        assert!(is_sha256_hex(&hash));
+        assert!(!is_sha256_hex("abc"));
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use crate::frame::FrameError;
+use crate::tls::TlsError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -14,6 +16,43 @@ pub enum ClientError {
     #[error("Connection error: {0}")]
     ConnectionError(String),
 
+    #[error("Frame error: {0}")]
+    Frame(#[from] FrameError),
+
+    #[error("Authentication failed: {0}")]
+    Authentication(String),
+
+    #[error("TLS error: {0}")]
+    Tls(#[from] TlsError),
+
+    #[error("Incompatible protocol: {0}")]
+    IncompatibleProtocol(String),
+
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Server error: {0}")]
+    Server(String),
+
     #[error("Other error: {0}")]
     Other(String),
 }
+
+impl ClientError {
+    /// Vrací návratový kód procesu, kterým klient ukončí běh kvůli této chybě.
+    ///
+    /// Skripty podle něj rozliší chybné použití, odmítnuté přihlášení a
+    /// nedostupný server.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ClientError::InvalidInput(_) => 2,
+            ClientError::Authentication(_) => 3,
+            // Chyby vstupu a výstupu vznikají hlavně při spojení se serverem
+            ClientError::ConnectionError(_) | ClientError::Frame(_) | ClientError::Io(_) => 4,
+            ClientError::Tls(_) => 5,
+            ClientError::IncompatibleProtocol(_) => 6,
+            ClientError::Server(_) => 7,
+            ClientError::Serialization(_) | ClientError::Encryption(_) | ClientError::Other(_) => 1,
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::io;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Výchozí maximální velikost jednoho rámce (16 MiB)
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Velikost hlavičky rámce s délkou obsahu
+const HEADER_SIZE: usize = 4;
+
+/// Velikost, po které se při čtení rámce postupně zvětšuje buffer
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Frame of {len} bytes exceeds the maximum of {max} bytes")]
+    TooLarge { len: usize, max: usize },
+
+    #[error(
+        "Connection closed in the middle of a frame ({received} of {expected} bytes received)"
+    )]
+    Truncated { expected: usize, received: usize },
+}
+
+/// Čte rámce ve tvaru 4bajtová big-endian délka následovaná obsahem
+pub struct FrameReader<R> {
+    inner: R,
+    max_frame_size: usize,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    /// Vytvoří čtečku s výchozí maximální velikostí rámce
+    pub fn new(inner: R) -> Self {
+        Self::with_max_frame_size(inner, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Vytvoří čtečku, která odmítne rámce větší než `max_frame_size` bajtů
+    pub fn with_max_frame_size(inner: R, max_frame_size: usize) -> Self {
+        FrameReader {
+            inner,
+            max_frame_size,
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Přečte jeden rámec.
+    ///
+    /// Buffer pro obsah se zvětšuje postupně podle přijatých dat, takže protistrana
+    /// nemůže ohlášením velké délky přimět čtečku k alokaci paměti, kterou nikdy nepošle.
+    ///
+    /// # Returns
+    ///
+    /// Vrací obsah rámce, nebo `None`, pokud protistrana spojení ukončila mezi rámci.
+    ///
+    /// # Errors
+    ///
+    /// Vrací `FrameError::TooLarge`, pokud ohlášená délka přesahuje maximum,
+    /// a `FrameError::Truncated`, pokud spojení skončí uprostřed rámce.
+    pub async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let mut header = [0u8; HEADER_SIZE];
+        let mut received = 0;
+        while received < HEADER_SIZE {
+            let read = self.inner.read(&mut header[received..]).await?;
+            if read == 0 {
+                if received == 0 {
+                    return Ok(None);
+                }
+                return Err(FrameError::Truncated {
+                    expected: HEADER_SIZE,
+                    received,
+                });
+            }
+            received += read;
+        }
+
+        let len = u32::from_be_bytes(header) as usize;
+        if len > self.max_frame_size {
+            return Err(FrameError::TooLarge {
+                len,
+                max: self.max_frame_size,
+            });
+        }
+
+        let mut buffer = Vec::with_capacity(len.min(READ_CHUNK_SIZE));
+        (&mut self.inner)
+            .take(len as u64)
+            .read_to_end(&mut buffer)
+            .await?;
+        if buffer.len() < len {
+            return Err(FrameError::Truncated {
+                expected: len,
+                received: buffer.len(),
+            });
+        }
+
+        Ok(Some(buffer))
+    }
+}
+
+/// Zapisuje rámce ve tvaru 4bajtová big-endian délka následovaná obsahem
+pub struct FrameWriter<W> {
+    inner: W,
+    max_frame_size: usize,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /// Vytvoří zapisovač s výchozí maximální velikostí rámce
+    pub fn new(inner: W) -> Self {
+        Self::with_max_frame_size(inner, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Vytvoří zapisovač, který odmítne odeslat rámce větší než `max_frame_size` bajtů
+    pub fn with_max_frame_size(inner: W, max_frame_size: usize) -> Self {
+        FrameWriter {
+            inner,
+            max_frame_size,
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Zapíše jeden rámec a vyprázdní buffer spojení.
+    ///
+    /// # Errors
+    ///
+    /// Vrací `FrameError::TooLarge`, pokud obsah přesahuje maximální velikost rámce.
+    pub async fn write_frame(&mut self, data: &[u8]) -> Result<(), FrameError> {
+        if data.len() > self.max_frame_size || data.len() > u32::MAX as usize {
+            return Err(FrameError::TooLarge {
+                len: data.len(),
+                max: self.max_frame_size,
+            });
+        }
+
+        self.inner
+            .write_all(&(data.len() as u32).to_be_bytes())
+            .await?;
+        self.inner.write_all(data).await?;
+        self.inner.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() -> Result<(), FrameError> {
+        let (client, server) = duplex(1024);
+        let mut writer = FrameWriter::new(client);
+        let mut reader = FrameReader::new(server);
+
+        writer.write_frame(b"hello").await?;
+        writer.write_frame(b"").await?;
+        drop(writer);
+
+        assert_eq!(reader.read_frame().await?, Some(b"hello".to_vec()));
+        assert_eq!(reader.read_frame().await?, Some(Vec::new()));
+        assert_eq!(reader.read_frame().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_rejected() -> Result<(), FrameError> {
+        let (mut client, server) = duplex(1024);
+        let mut reader = FrameReader::with_max_frame_size(server, 16);
+
+        // Announce a 4 GB frame whose body never arrives
+        client.write_all(&u32::MAX.to_be_bytes()).await?;
+
+        let result = reader.read_frame().await;
+        assert!(matches!(result, Err(FrameError::TooLarge { max: 16, .. })));
+
+        let (client, _server) = duplex(1024);
+        let mut writer = FrameWriter::with_max_frame_size(client, 4);
+        let result = writer.write_frame(b"too long").await;
+        assert!(matches!(
+            result,
+            Err(FrameError::TooLarge { len: 8, max: 4 })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frame() -> Result<(), FrameError> {
+        let (mut client, server) = duplex(1024);
+        let mut reader = FrameReader::new(server);
+
+        client.write_all(&10u32.to_be_bytes()).await?;
+        client.write_all(b"abc").await?;
+        drop(client);
+
+        let result = reader.read_frame().await;
+        assert!(matches!(
+            result,
+            Err(FrameError::Truncated {
+                expected: 10,
+                received: 3
+            })
+        ));
+
+        let (mut client, server) = duplex(1024);
+        let mut reader = FrameReader::new(server);
+        client.write_all(&[0u8, 0]).await?;
+        drop(client);
+
+        let result = reader.read_frame().await;
+        assert!(matches!(
+            result,
+            Err(FrameError::Truncated {
+                expected: 4,
+                received: 2
+            })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Kolik prvních bajtů obsahu stačí k rozpoznání formátu obrázku
+pub const SNIFF_LENGTH: usize = 12;
+
+/// Podporovaný formát obrázku rozpoznaný podle prvních bajtů obsahu, ne podle přípony
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+    WebP,
+}
+
+impl ImageFormat {
+    /// Rozpozná formát obrázku podle jeho prvních bajtů.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Začátek obsahu, stačí prvních `SNIFF_LENGTH` bajtů
+    ///
+    /// # Returns
+    ///
+    /// Vrací `None`, pokud obsah nezačíná hlavičkou žádného podporovaného formátu.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+            Some(ImageFormat::WebP)
+        } else {
+            None
+        }
+    }
+
+    /// Rozpozná formát obrázku uloženého v souboru.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Cesta k souboru; čte se jen jeho začátek
+    ///
+    /// # Returns
+    ///
+    /// Vrací `None`, pokud soubor není obrázek v podporovaném formátu.
+    pub fn detect_file(path: &Path) -> io::Result<Option<Self>> {
+        let mut header = Vec::with_capacity(SNIFF_LENGTH);
+        File::open(path)?
+            .take(SNIFF_LENGTH as u64)
+            .read_to_end(&mut header)?;
+        Ok(Self::detect(&header))
+    }
+
+    /// Rozpozná formát podle MIME typu, například `image/png`
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        [
+            ImageFormat::Png,
+            ImageFormat::Jpeg,
+            ImageFormat::Gif,
+            ImageFormat::WebP,
+        ]
+        .into_iter()
+        .find(|format| format.mime().eq_ignore_ascii_case(mime.trim()))
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::WebP => "image/webp",
+        }
+    }
+
+    /// Přípony souborů daného formátu bez tečky; první je výchozí
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            ImageFormat::Png => &["png"],
+            ImageFormat::Jpeg => &["jpg", "jpeg"],
+            ImageFormat::Gif => &["gif"],
+            ImageFormat::WebP => &["webp"],
+        }
+    }
+
+    /// Upraví název souboru tak, aby jeho přípona odpovídala formátu.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Název souboru bez cesty, například `photo.png`
+    ///
+    /// # Returns
+    ///
+    /// Vrací název se správnou příponou, například `photo.jpg` pro obrázek JPEG;
+    /// název s už správnou příponou (i `photo.JPEG`) se nemění.
+    pub fn file_name(self, filename: &str) -> String {
+        let (stem, extension) = match filename.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => (stem, extension),
+            _ => (filename, ""),
+        };
+        let extension = extension.to_ascii_lowercase();
+        if self.extensions().contains(&extension.as_str()) {
+            return filename.to_string();
+        }
+        format!("{}.{}", stem, self.extensions()[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_by_magic_bytes() {
+        assert_eq!(
+            ImageFormat::detect(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::detect(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10]),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(ImageFormat::detect(b"GIF89a\x01\0"), Some(ImageFormat::Gif));
+        assert_eq!(
+            ImageFormat::detect(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some(ImageFormat::WebP)
+        );
+        // RIFF containers other than WebP, text and truncated headers are not images
+        assert_eq!(ImageFormat::detect(b"RIFF\x24\0\0\0WAVEfmt "), None);
+        assert_eq!(ImageFormat::detect(b"not an image"), None);
+        assert_eq!(ImageFormat::detect(b"\x89PN"), None);
+    }
+
+    #[test]
+    fn test_mime_and_file_name() {
+        assert_eq!(
+            ImageFormat::from_mime("image/JPEG"),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(ImageFormat::from_mime("text/plain"), None);
+        assert_eq!(ImageFormat::WebP.mime(), "image/webp");
+
+        assert_eq!(ImageFormat::Jpeg.file_name("photo.png"), "photo.jpg");
+        assert_eq!(ImageFormat::Jpeg.file_name("photo.JPEG"), "photo.JPEG");
+        assert_eq!(ImageFormat::Gif.file_name("animation"), "animation.gif");
+    }
+}
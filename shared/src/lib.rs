@@ -1,19 +1,238 @@
-use bincode;
-
+pub mod checksum;
 pub mod client_error;
+pub mod frame;
+pub mod image;
+pub mod protocol;
 pub mod server_error;
+pub mod tls;
 
-#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+/// Výchozí místnost, do které patří každá zpráva bez explicitně zvolené místnosti
+pub const DEFAULT_ROOM: &str = "general";
+
+/// Velikost jedné části souboru při přenosu po částech (64 KiB)
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Počet zpráv na jedné stránce výsledků hledání
+pub const SEARCH_PAGE_SIZE: u32 = 20;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum MessageType {
     Text(String),
-    Image(Vec<u8>),
+    /// Obrázek poslaný najednou; formát se ověřuje podle obsahu, ne podle přípony
+    Image {
+        /// Původní název souboru od odesílatele
+        filename: String,
+        /// MIME typ rozpoznaný z obsahu, například `image/jpeg`
+        mime: String,
+        data: Vec<u8>,
+    },
     File(String, Vec<u8>),
+    History(Vec<HistoryEntry>),
+    /// Požadavek klienta na vstup do místnosti (neexistující místnost se vytvoří)
+    JoinRoom(String),
+    /// Požadavek klienta na návrat do výchozí místnosti
+    LeaveRoom,
+    /// Požadavek klienta na seznam místností
+    ListRooms,
+    /// Potvrzení serveru, že klient je nyní v uvedené místnosti
+    RoomJoined(String),
+    RoomList(Vec<RoomInfo>),
+    /// Chybové hlášení serveru k poslednímu požadavku klienta
+    Error(String),
+    /// Soukromá zpráva pro jednoho uživatele; odesílatele doplňuje server do obálky
+    Direct {
+        recipient: String,
+        text: String,
+    },
+    /// Začátek přenosu souboru po částech; ID přenosu volí klient, server ho
+    /// před přeposláním nahradí vlastním jedinečným ID
+    FileOffer {
+        transfer_id: u64,
+        filename: String,
+        size: u64,
+        image: bool,
+        /// SHA-256 celého souboru v hexadecimálním zápisu
+        sha256: String,
+    },
+    /// Odpověď serveru odesílateli na nabídku souboru; odesílatel pokračuje
+    /// od pozice `offset`, kterou server už má uloženou
+    FileAccepted {
+        transfer_id: u64,
+        offset: u64,
+    },
+    /// Požadavek příjemce na zaslání souboru od pozice `offset`, například
+    /// po přerušeném spojení
+    FileRequest {
+        transfer_id: u64,
+        offset: u64,
+    },
+    /// Jedna část přenášeného souboru začínající na pozici `offset`
+    FileChunk {
+        transfer_id: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Konec přenosu, všechny části byly odeslány
+    FileComplete {
+        transfer_id: u64,
+    },
+    /// Přerušení přenosu, přijatá data je třeba zahodit
+    FileAbort {
+        transfer_id: u64,
+        reason: String,
+    },
+    /// Uživatel vstoupil do místnosti uvedené v obálce (zprávu vytváří server)
+    UserJoined(String),
+    /// Uživatel opustil místnost uvedenou v obálce nebo se odpojil (zprávu vytváří server)
+    UserLeft(String),
+    /// Žádost o seznam připojených uživatelů
+    ListUsers,
+    /// Seznam připojených uživatelů
+    UserList(Vec<OnlineUser>),
+    /// Ověření, že protistrana žije; odpovídá se zprávou `MessageType::Pong`
+    Ping,
+    /// Odpověď na `MessageType::Ping`
+    Pong,
+    /// Žádost o zprávy aktuální místnosti s ID větším než `after_id`,
+    /// například těch, které klient zmeškal během výpadku spojení
+    FetchHistory {
+        after_id: i64,
+    },
+    /// Zveřejnění veřejného klíče X25519 přihlášeného uživatele pro šifrované soukromé zprávy.
+    /// Účet má jediný klíč; jiný než dosud zveřejněný server přijme, jen pokud je `replace`
+    PublishKey {
+        key: [u8; 32],
+        replace: bool,
+    },
+    /// Žádost o veřejný klíč uživatele
+    RequestKey(String),
+    /// Odpověď na `MessageType::RequestKey`; `None`, pokud uživatel klíč nezveřejnil
+    PublicKey {
+        username: String,
+        key: Option<[u8; 32]>,
+    },
+    /// Soukromá zpráva zašifrovaná pro příjemce; server ji jen přeposílá a ukládá
+    EncryptedDirect {
+        recipient: String,
+        sealed: SealedMessage,
+    },
+    /// Hledání ve zprávách uložených na serveru
+    Search(SearchQuery),
+    /// Jedna stránka výsledků hledání seřazených od nejnovější zprávy
+    SearchResults {
+        page: u32,
+        /// Celkový počet nalezených zpráv na všech stránkách
+        total: u32,
+        entries: Vec<HistoryEntry>,
+    },
+    /// Upozornění na novou zprávu v místnosti, kterou klient během relace navštívil,
+    /// ale právě v ní není
+    RoomActivity {
+        room: String,
+        sender: String,
+    },
+    /// Odkaz na soubor uložený na serveru, který server posílá místo jeho obsahu;
+    /// data si klient vyžádá zprávou `MessageType::FileRequest` s uvedeným ID přenosu
+    Attachment {
+        transfer_id: u64,
+        filename: String,
+        size: u64,
+        image: bool,
+        /// SHA-256 obsahu, podle kterého server soubor ukládá
+        sha256: String,
+    },
+}
+
+/// Parametry hledání ve zprávách; nevyplněné filtry se neuplatní
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SearchQuery {
+    /// Hledaná slova; zpráva musí obsahovat všechna
+    pub text: String,
+    /// Odesílatel zprávy
+    pub sender: Option<String>,
+    pub room: Option<String>,
+    /// Nejdřívější čas odeslání (Unix timestamp)
+    pub since: Option<i64>,
+    /// Čas, před kterým musela být zpráva odeslána (Unix timestamp)
+    pub until: Option<i64>,
+    /// Stránka výsledků číslovaná od 1
+    pub page: u32,
 }
 
+/// Obsah soukromé zprávy zašifrovaný tak, že ho přečte jen příjemce
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SealedMessage {
+    /// Veřejný klíč odesílatele, ze kterého příjemce odvodí společný klíč
+    pub sender_key: [u8; 32],
+    /// Veřejný klíč příjemce, pro který byla zpráva zašifrována
+    pub recipient_key: [u8; 32],
+    pub nonce: [u8; 12],
+    /// Zašifrovaný text zprávy včetně autentizačního tagu
+    pub ciphertext: Vec<u8>,
+}
+
+/// Informace o místnosti posílaná v odpovědi na `MessageType::ListRooms`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    /// Počet právě připojených uživatelů v místnosti
+    pub online: u32,
+}
+
+/// Připojený uživatel posílaný v odpovědi na `MessageType::ListUsers`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+pub struct OnlineUser {
+    pub username: String,
+    /// Místnost, ve které se uživatel právě nachází
+    pub room: String,
+}
+
+/// Uložená zpráva, kterou server posílá nově připojeným klientům jako historii
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HistoryEntry {
+    /// ID zprávy v databázi serveru
+    pub id: i64,
+    pub sender: String,
+    pub room: String,
+    /// Čas odeslání v sekundách od počátku unixové epochy
+    pub timestamp: i64,
+    /// Druh zprávy (`text`, `image` nebo `file`)
+    pub kind: String,
+    pub content: String,
+    pub filename: Option<String>,
+    pub size: Option<i64>,
+}
+
+/// Požadavek na přihlášení nebo registraci, který klient posílá hned po připojení
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum AuthRequest {
+    Login { username: String, password: String },
+    Register { username: String, password: String },
+}
+
+/// Odpověď serveru na úspěšné přihlášení
+pub const AUTH_SUCCESS: &str = "Authentication Successful";
+
 pub fn serialize_message(message: &MessageType) -> Result<Vec<u8>, bincode::Error> {
     bincode::serialize(&message)
 }
 
 pub fn deserialize_message(data: &[u8]) -> Result<MessageType, bincode::Error> {
-    bincode::deserialize(&data)
+    bincode::deserialize(data)
+}
+
+pub fn serialize_auth_request(request: &AuthRequest) -> Result<Vec<u8>, bincode::Error> {
+    bincode::serialize(request)
+}
+
+pub fn deserialize_auth_request(data: &[u8]) -> Result<AuthRequest, bincode::Error> {
+    bincode::deserialize(data)
+}
+
+pub fn serialize_sealed_message(sealed: &SealedMessage) -> Result<Vec<u8>, bincode::Error> {
+    bincode::serialize(sealed)
+}
+
+pub fn deserialize_sealed_message(data: &[u8]) -> Result<SealedMessage, bincode::Error> {
+    bincode::deserialize(data)
 }
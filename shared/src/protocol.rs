@@ -0,0 +1,225 @@
+use crate::MessageType;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Aktuální verze protokolu, kterou tato knihovna mluví.
+///
+/// Zvyšuje se s každou změnou podoby `MessageType` nebo `Envelope` na drátě;
+/// verze 2 přinesla obrázky s názvem a typem, soukromé zprávy, přenosy po
+/// částech, hledání a přílohy, verze 3 výslovné nahrazení klíče
+/// v `MessageType::PublishKey`.
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// Nejstarší verze protokolu, se kterou je tato knihovna ještě kompatibilní.
+///
+/// Zprávy starších verzí nejdou zpětně dekódovat, starší protistrana se proto
+/// odmítne už při handshaku.
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
+
+/// Značka na začátku handshaku, podle které se pozná, že protistrana mluví tímto protokolem
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"RCHT";
+
+/// Odesílatel zpráv generovaných samotným serverem
+pub const SYSTEM_SENDER: &str = "server";
+
+/// První rámec, který klient po připojení posílá.
+///
+/// Jeho podoba se mezi verzemi protokolu nesmí měnit, aby se i nekompatibilní
+/// protistrany dokázaly domluvit, že spolu mluvit nemohou.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Handshake {
+    pub magic: [u8; 4],
+    /// Nejvyšší verze protokolu, kterou klient podporuje
+    pub version: u16,
+    /// Nejnižší verze protokolu, kterou klient podporuje
+    pub min_version: u16,
+}
+
+impl Handshake {
+    /// Vytvoří handshake s verzemi podporovanými touto knihovnou
+    pub fn new() -> Self {
+        Handshake {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Odpověď serveru na handshake
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum HandshakeResponse {
+    /// Server přijal spojení a obě strany budou mluvit uvedenou verzí
+    Accepted { version: u16 },
+    /// Server spojení odmítl, protože nemají žádnou společnou verzi
+    Rejected {
+        version: u16,
+        min_version: u16,
+        reason: String,
+    },
+}
+
+/// Obálka, ve které se po přihlášení posílají všechny zprávy
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Envelope {
+    /// Verze protokolu dohodnutá při handshaku
+    pub version: u16,
+    /// ID zprávy přidělené serverem, `0` pokud zpráva ID nemá
+    pub id: i64,
+    /// Uživatelské jméno odesílatele, doplňuje ho server
+    pub sender: String,
+    /// Čas odeslání v sekundách od počátku unixové epochy
+    pub timestamp: i64,
+    pub room: String,
+    pub payload: MessageType,
+}
+
+impl Envelope {
+    /// Vytvoří novou obálku se zprávou odeslanou právě teď
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - Obsah zprávy
+    /// * `room` - Místnost, do které zpráva patří
+    pub fn new(payload: MessageType, room: &str) -> Self {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            id: 0,
+            sender: String::new(),
+            timestamp: unix_timestamp(),
+            room: room.to_string(),
+            payload,
+        }
+    }
+
+    /// Vytvoří obálku se zprávou, kterou generuje server
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - Obsah zprávy
+    /// * `room` - Místnost, do které zpráva patří
+    pub fn system(payload: MessageType, room: &str) -> Self {
+        Envelope {
+            sender: SYSTEM_SENDER.to_string(),
+            ..Envelope::new(payload, room)
+        }
+    }
+}
+
+/// Vrací aktuální čas jako počet sekund od počátku unixové epochy.
+pub fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Vybere verzi protokolu, kterou budou obě strany používat.
+///
+/// Pokud protistrana podporuje novější verzi, použije se naše nejvyšší verze;
+/// pokud podporuje jen starší, přejdeme na její verzi, je-li pro nás ještě podporovaná.
+///
+/// # Arguments
+///
+/// * `handshake` - Handshake přijatý od protistrany
+///
+/// # Returns
+///
+/// Vrací dohodnutou verzi, nebo `None`, pokud žádná společná verze neexistuje.
+pub fn negotiate_version(handshake: &Handshake) -> Option<u16> {
+    if handshake.magic != PROTOCOL_MAGIC {
+        return None;
+    }
+
+    let version = PROTOCOL_VERSION.min(handshake.version);
+    if version >= MIN_PROTOCOL_VERSION.max(handshake.min_version) {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+pub fn serialize_handshake(handshake: &Handshake) -> Result<Vec<u8>, bincode::Error> {
+    bincode::serialize(handshake)
+}
+
+pub fn deserialize_handshake(data: &[u8]) -> Result<Handshake, bincode::Error> {
+    bincode::deserialize(data)
+}
+
+pub fn serialize_handshake_response(
+    response: &HandshakeResponse,
+) -> Result<Vec<u8>, bincode::Error> {
+    bincode::serialize(response)
+}
+
+pub fn deserialize_handshake_response(data: &[u8]) -> Result<HandshakeResponse, bincode::Error> {
+    bincode::deserialize(data)
+}
+
+pub fn serialize_envelope(envelope: &Envelope) -> Result<Vec<u8>, bincode::Error> {
+    bincode::serialize(envelope)
+}
+
+pub fn deserialize_envelope(data: &[u8]) -> Result<Envelope, bincode::Error> {
+    bincode::deserialize(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_same_version() {
+        assert_eq!(negotiate_version(&Handshake::new()), Some(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn test_negotiate_downgrades_newer_peer() {
+        let handshake = Handshake {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION + 5,
+            min_version: MIN_PROTOCOL_VERSION,
+        };
+        assert_eq!(negotiate_version(&handshake), Some(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn test_negotiate_rejects_incompatible_peer() {
+        let too_new = Handshake {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION + 5,
+            min_version: PROTOCOL_VERSION + 1,
+        };
+        assert_eq!(negotiate_version(&too_new), None);
+
+        let wrong_magic = Handshake {
+            magic: *b"HTTP",
+            ..Handshake::new()
+        };
+        assert_eq!(negotiate_version(&wrong_magic), None);
+    }
+
+    #[test]
+    fn test_negotiate_rejects_outdated_peer() {
+        let outdated = Handshake {
+            magic: PROTOCOL_MAGIC,
+            version: MIN_PROTOCOL_VERSION - 1,
+            min_version: MIN_PROTOCOL_VERSION - 1,
+        };
+        assert_eq!(negotiate_version(&outdated), None);
+    }
+
+    #[test]
+    fn test_envelope_roundtrip() -> Result<(), bincode::Error> {
+        let envelope = Envelope::new(MessageType::Text("Hello".to_string()), "general");
+        let decoded = deserialize_envelope(&serialize_envelope(&envelope)?)?;
+        assert_eq!(envelope, decoded);
+        Ok(())
+    }
+}
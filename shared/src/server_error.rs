@@ -1,3 +1,5 @@
+use crate::frame::FrameError;
+use crate::tls::TlsError;
 use bincode::ErrorKind;
 use sqlx::Error as SqlxError;
 use std::io;
@@ -14,6 +16,30 @@ pub enum ServerError {
     #[error("Bincode error")]
     Bincode(#[from] Box<ErrorKind>),
 
+    #[error("Authentication error: {0}")]
+    Authentication(String),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Frame error: {0}")]
+    Frame(#[from] FrameError),
+
+    #[error("TLS error: {0}")]
+    Tls(#[from] TlsError),
+
+    #[error("Incompatible protocol: {0}")]
+    IncompatibleProtocol(String),
+
+    #[error("Client is too slow to receive messages")]
+    SlowConsumer,
+
+    #[error("Connection closed")]
+    ConnectionClosed,
+
+    #[error("No message received for {0} seconds")]
+    HeartbeatTimeout(u64),
+
     #[error("Other error: {0}")]
     Other(String),
 }
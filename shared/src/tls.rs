@@ -0,0 +1,343 @@
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsConnector;
+
+pub use tokio_rustls::TlsAcceptor;
+
+/// Spojení, po kterém běží protokol chatu, ať už je šifrované, nebo ne
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Spojení se skrytým typem, aby šifrované i nešifrované šlo obsluhovat stejným kódem
+pub type BoxedStream = Box<dyn Stream>;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Cannot read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("No certificate found in {0}")]
+    NoCertificate(PathBuf),
+
+    #[error("No private key found in {0}")]
+    NoPrivateKey(PathBuf),
+
+    #[error("Invalid certificate fingerprint: {0}")]
+    InvalidFingerprint(String),
+
+    #[error("Invalid server name: {0}")]
+    InvalidServerName(String),
+
+    #[error("TLS error: {0}")]
+    Rustls(#[from] rustls::Error),
+
+    #[error("TLS handshake failed: {0}")]
+    Handshake(io::Error),
+}
+
+/// Čím klient ověřuje certifikát serveru
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerTrust {
+    /// Certifikát musí být podepsaný certifikační autoritou z daného PEM souboru
+    CaFile(PathBuf),
+    /// Certifikát musí mít daný SHA-256 otisk (hodí se pro certifikáty podepsané sebou samými)
+    Fingerprint(String),
+}
+
+/// Načte certifikáty z PEM souboru.
+///
+/// # Arguments
+///
+/// * `path` - Cesta k PEM souboru
+///
+/// # Errors
+///
+/// Vrací `TlsError::NoCertificate`, pokud soubor žádný certifikát neobsahuje.
+pub fn load_certificates(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let mut reader = open_pem(path)?;
+    let certificates = rustls_pemfile::certs(&mut reader).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+/// Načte první soukromý klíč (PKCS#8, RSA nebo EC) z PEM souboru.
+///
+/// # Arguments
+///
+/// * `path` - Cesta k PEM souboru
+///
+/// # Errors
+///
+/// Vrací `TlsError::NoPrivateKey`, pokud soubor žádný klíč neobsahuje.
+pub fn load_private_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let mut reader = open_pem(path)?;
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader).map_err(|source| TlsError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        match item {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(TlsError::NoPrivateKey(path.to_path_buf())),
+        }
+    }
+}
+
+fn open_pem(path: &Path) -> Result<io::BufReader<std::fs::File>, TlsError> {
+    let file = std::fs::File::open(path).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(io::BufReader::new(file))
+}
+
+/// Spočítá SHA-256 otisk certifikátu.
+///
+/// # Returns
+///
+/// Vrací otisk v hexadecimálním zápisu malými písmeny.
+pub fn fingerprint(certificate: &Certificate) -> String {
+    hex::encode(Sha256::digest(&certificate.0))
+}
+
+/// Převede otisk zadaný uživatelem na bajty.
+///
+/// Přijímá i zápis s dvojtečkami a velkými písmeny, jak ho vypisuje `openssl x509 -fingerprint`.
+fn parse_fingerprint(value: &str) -> Result<Vec<u8>, TlsError> {
+    let normalized: String = value.chars().filter(|c| *c != ':').collect();
+    match hex::decode(normalized.to_ascii_lowercase()) {
+        Ok(bytes) if bytes.len() == 32 => Ok(bytes),
+        _ => Err(TlsError::InvalidFingerprint(value.to_string())),
+    }
+}
+
+/// Vytvoří přijímač TLS spojení pro server.
+///
+/// # Arguments
+///
+/// * `certificate` - PEM soubor s certifikátem serveru (případně i s mezilehlými certifikáty)
+/// * `private_key` - PEM soubor se soukromým klíčem serveru
+///
+/// # Returns
+///
+/// Vrací přijímač a SHA-256 otisk certifikátu serveru, který mohou klienti připnout.
+pub fn server_acceptor(
+    certificate: &Path,
+    private_key: &Path,
+) -> Result<(TlsAcceptor, String), TlsError> {
+    let certificates = load_certificates(certificate)?;
+    let fingerprint = fingerprint(&certificates[0]);
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, load_private_key(private_key)?)?;
+    Ok((TlsAcceptor::from(Arc::new(config)), fingerprint))
+}
+
+/// Provede TLS handshake na straně serveru.
+///
+/// # Arguments
+///
+/// * `acceptor` - Přijímač vytvořený funkcí `server_acceptor`
+/// * `stream` - Právě přijaté spojení
+pub async fn accept<S>(acceptor: &TlsAcceptor, stream: S) -> Result<BoxedStream, TlsError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stream = acceptor.accept(stream).await.map_err(TlsError::Handshake)?;
+    Ok(Box::new(stream))
+}
+
+/// Ověřovač, který přijme jen certifikát s připnutým otiskem.
+///
+/// Jméno serveru ani platnost certifikátu se nekontrolují; server ale i tak
+/// musí během handshaku prokázat, že vlastní soukromý klíč k certifikátu.
+struct PinnedCertificate {
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(&end_entity.0).as_slice() == self.fingerprint.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "server certificate fingerprint {} does not match the pinned one",
+                fingerprint(end_entity)
+            )))
+        }
+    }
+}
+
+/// Klientská strana TLS spojení se serverem
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName,
+}
+
+impl TlsClient {
+    /// Připraví TLS spojení se serverem.
+    ///
+    /// # Arguments
+    ///
+    /// * `trust` - Čím se ověří certifikát serveru
+    /// * `server_name` - Jméno nebo IP adresa serveru, pro kterou musí certifikát platit
+    ///
+    /// # Errors
+    ///
+    /// Vrací chybu, pokud nejde načíst certifikační autoritu, otisk není platné
+    /// SHA-256 nebo jméno serveru není platné.
+    pub fn new(trust: &ServerTrust, server_name: &str) -> Result<Self, TlsError> {
+        let builder = ClientConfig::builder().with_safe_defaults();
+        let config = match trust {
+            ServerTrust::CaFile(path) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in load_certificates(path)? {
+                    roots.add(&certificate)?;
+                }
+                builder.with_root_certificates(roots).with_no_client_auth()
+            }
+            ServerTrust::Fingerprint(value) => builder
+                .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                    fingerprint: parse_fingerprint(value)?,
+                }))
+                .with_no_client_auth(),
+        };
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|_| TlsError::InvalidServerName(server_name.to_string()))?;
+        Ok(TlsClient {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    /// Provede TLS handshake na straně klienta.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Navázané spojení se serverem
+    pub async fn connect<S>(&self, stream: S) -> Result<BoxedStream, TlsError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await
+            .map_err(TlsError::Handshake)?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{FrameReader, FrameWriter};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Vygeneruje certifikát podepsaný sebou samým a uloží ho i s klíčem do adresáře `dir`
+    fn self_signed(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let certificate =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert_path, key_path) = (
+            dir.join(format!("{}-cert.pem", name)),
+            dir.join(format!("{}-key.pem", name)),
+        );
+        std::fs::write(&cert_path, certificate.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, certificate.serialize_private_key_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    /// Spojí klienta se serverem přes TLS a ověří, že jimi projde rámec
+    async fn exchange(acceptor: TlsAcceptor, client: TlsClient) -> Result<Vec<u8>, TlsError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = accept(&acceptor, stream).await?;
+            let mut writer = FrameWriter::new(stream);
+            writer.write_frame(b"hello over tls").await.unwrap();
+            Ok::<_, TlsError>(())
+        });
+
+        let stream = client
+            .connect(TcpStream::connect(addr).await.unwrap())
+            .await?;
+        let frame = FrameReader::new(stream).read_frame().await.unwrap();
+        server.await.unwrap()?;
+        Ok(frame.unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_ca_and_pinned_fingerprint() -> Result<(), TlsError> {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = self_signed(dir.path(), "trusted");
+        let (acceptor, server_fingerprint) = server_acceptor(&cert_path, &key_path)?;
+        assert_eq!(
+            server_fingerprint,
+            fingerprint(&load_certificates(&cert_path)?[0])
+        );
+
+        // A self-signed certificate is its own certificate authority
+        let client = TlsClient::new(&ServerTrust::CaFile(cert_path.clone()), "localhost")?;
+        assert_eq!(exchange(acceptor.clone(), client).await?, b"hello over tls");
+
+        // OpenSSL style fingerprints are accepted as well
+        let colons = server_fingerprint
+            .to_ascii_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        let client = TlsClient::new(&ServerTrust::Fingerprint(colons), "127.0.0.1")?;
+        assert_eq!(exchange(acceptor, client).await?, b"hello over tls");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_certificate_is_rejected() -> Result<(), TlsError> {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = self_signed(dir.path(), "server");
+        let (other_cert, _) = self_signed(dir.path(), "other");
+        let (acceptor, _) = server_acceptor(&cert_path, &key_path)?;
+
+        let client = TlsClient::new(&ServerTrust::CaFile(other_cert.clone()), "localhost")?;
+        assert!(exchange(acceptor.clone(), client).await.is_err());
+
+        let other_fingerprint = fingerprint(&load_certificates(&other_cert)?[0]);
+        let client = TlsClient::new(&ServerTrust::Fingerprint(other_fingerprint), "localhost")?;
+        assert!(exchange(acceptor, client).await.is_err());
+
+        assert!(matches!(
+            TlsClient::new(&ServerTrust::Fingerprint("abc".to_string()), "localhost"),
+            Err(TlsError::InvalidFingerprint(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+use shared::client_error::ClientError;
+use shared::frame::{FrameReader, FrameWriter};
+use shared::image::ImageFormat;
+use shared::protocol::{
+    deserialize_envelope, deserialize_handshake_response, serialize_envelope, serialize_handshake,
+    Envelope, Handshake, HandshakeResponse,
+};
+use shared::tls::{BoxedStream, TlsClient};
+use shared::{serialize_auth_request, AuthRequest, MessageType, AUTH_SUCCESS, DEFAULT_ROOM};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+type ServerReader = FrameReader<ReadHalf<BoxedStream>>;
+type ServerWriter = FrameWriter<WriteHalf<BoxedStream>>;
+
+/// Jak dlouho se nejvýše čeká na odpověď chatovacího serveru
+const SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Přihlašovací údaje uživatele chatovacího serveru
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Připojení k chatovacímu serveru, přes které webové rozhraní posílá zprávy
+#[derive(Clone)]
+pub struct ChatServer {
+    /// Adresa serveru ve tvaru `ip:port`
+    pub address: String,
+    /// TLS spojení se serverem, pokud server TLS používá
+    pub tls: Option<TlsClient>,
+}
+
+impl ChatServer {
+    /// Přihlásí se k serveru jako uživatel a pošle do místnosti zprávy.
+    ///
+    /// Webové rozhraní se k serveru připojuje stejně jako klient příkazem
+    /// `send`, takže zprávy se uloží a doručí stejně jako ostatní zprávy.
+    /// Každé odeslání používá vlastní spojení, které se po potvrzení zpráv
+    /// serverem zavře.
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - Přihlašovací údaje uživatele, za kterého se zprávy pošlou
+    /// * `room` - Místnost, do které se zprávy pošlou
+    /// * `messages` - Zprávy, například `MessageType::Text`
+    ///
+    /// # Errors
+    ///
+    /// Vrací `ClientError::Authentication`, pokud server přihlášení odmítl, a
+    /// `ClientError::Server`, pokud server odmítl vstup do místnosti nebo zprávu.
+    pub async fn post(
+        &self,
+        credentials: &Credentials,
+        room: &str,
+        messages: Vec<MessageType>,
+    ) -> Result<(), ClientError> {
+        timeout(SERVER_TIMEOUT, self.deliver(credentials, room, messages))
+            .await
+            .map_err(|_| {
+                ClientError::ConnectionError("Server did not answer in time".to_string())
+            })?
+    }
+
+    async fn deliver(
+        &self,
+        credentials: &Credentials,
+        room: &str,
+        messages: Vec<MessageType>,
+    ) -> Result<(), ClientError> {
+        let stream = TcpStream::connect(&self.address).await?;
+        let stream: BoxedStream = match &self.tls {
+            Some(tls) => tls.connect(stream).await?,
+            None => Box::new(stream),
+        };
+        let (reader, writer) = tokio::io::split(stream);
+        let mut reader = FrameReader::new(reader);
+        let mut writer = FrameWriter::new(writer);
+
+        let version = negotiate_protocol(&mut reader, &mut writer).await?;
+        let login = AuthRequest::Login {
+            username: credentials.username.clone(),
+            password: credentials.password.clone(),
+        };
+        writer.write_frame(&serialize_auth_request(&login)?).await?;
+        let response = read_required_frame(&mut reader).await?;
+        let response = String::from_utf8_lossy(&response).to_string();
+        if !response.contains(AUTH_SUCCESS) {
+            return Err(ClientError::Authentication(response));
+        }
+
+        // Po přihlášení je uživatel ve výchozí místnosti
+        wait_for(
+            &mut reader,
+            |payload| matches!(payload, MessageType::RoomJoined(name) if name == DEFAULT_ROOM),
+        )
+        .await?;
+        if room != DEFAULT_ROOM {
+            write(
+                &mut writer,
+                version,
+                MessageType::JoinRoom(room.to_string()),
+            )
+            .await?;
+            wait_for(
+                &mut reader,
+                |payload| matches!(payload, MessageType::RoomJoined(name) if name == room),
+            )
+            .await?;
+        }
+
+        // Server zpracovává zprávy jednoho spojení popořadě, odpověď na seznam
+        // uživatelů proto přijde až po zpracování zpráv nebo po chybě
+        for message in messages {
+            write(&mut writer, version, message).await?;
+        }
+        write(&mut writer, version, MessageType::ListUsers).await?;
+        wait_for(&mut reader, |payload| {
+            matches!(payload, MessageType::UserList(_))
+        })
+        .await
+    }
+}
+
+/// Vytvoří zprávu se souborem nahraným z prohlížeče.
+///
+/// Obrázek v podporovaném formátu se pozná podle obsahu a pošle jako
+/// `MessageType::Image` s příponou odpovídající formátu, ostatní soubory jako
+/// `MessageType::File`.
+///
+/// # Arguments
+///
+/// * `filename` - Název souboru z prohlížeče
+/// * `data` - Obsah souboru
+pub fn file_message(filename: &str, data: Vec<u8>) -> MessageType {
+    match ImageFormat::detect(&data) {
+        Some(format) => MessageType::Image {
+            filename: format.file_name(filename),
+            mime: format.mime().to_string(),
+            data,
+        },
+        None => MessageType::File(filename.to_string(), data),
+    }
+}
+
+/// Přečte rámec, jehož absence znamená, že server spojení ukončil
+async fn read_required_frame(reader: &mut ServerReader) -> Result<Vec<u8>, ClientError> {
+    reader
+        .read_frame()
+        .await?
+        .ok_or_else(|| ClientError::ConnectionError("Connection closed by server".to_string()))
+}
+
+/// Dohodne se serverem verzi protokolu
+async fn negotiate_protocol(
+    reader: &mut ServerReader,
+    writer: &mut ServerWriter,
+) -> Result<u16, ClientError> {
+    writer
+        .write_frame(&serialize_handshake(&Handshake::new())?)
+        .await?;
+
+    let response = read_required_frame(reader).await?;
+    match deserialize_handshake_response(&response) {
+        Ok(HandshakeResponse::Accepted { version }) => Ok(version),
+        Ok(HandshakeResponse::Rejected { reason, .. }) => {
+            Err(ClientError::IncompatibleProtocol(reason))
+        }
+        Err(_) => Err(ClientError::IncompatibleProtocol(
+            "server did not answer the protocol handshake".to_string(),
+        )),
+    }
+}
+
+/// Zabalí zprávu do obálky a pošle ji na server
+async fn write(
+    writer: &mut ServerWriter,
+    version: u16,
+    payload: MessageType,
+) -> Result<(), ClientError> {
+    let envelope = Envelope {
+        version,
+        ..Envelope::new(payload, "")
+    };
+    writer.write_frame(&serialize_envelope(&envelope)?).await?;
+    Ok(())
+}
+
+/// Čte zprávy serveru, dokud nepřijde zpráva splňující podmínku.
+///
+/// Ostatní zprávy, například zprávy jiných uživatelů, se přeskočí.
+///
+/// # Errors
+///
+/// Vrací `ClientError::Server`, pokud server místo očekávané zprávy ohlásí chybu.
+async fn wait_for(
+    reader: &mut ServerReader,
+    expected: impl Fn(&MessageType) -> bool,
+) -> Result<(), ClientError> {
+    loop {
+        let envelope = deserialize_envelope(&read_required_frame(reader).await?)?;
+        match envelope.payload {
+            MessageType::Error(reason) => return Err(ClientError::Server(reason)),
+            payload if expected(&payload) => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_message_detects_images() {
+        let jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10];
+        assert_eq!(
+            file_message("photo.png", jpeg.clone()),
+            MessageType::Image {
+                filename: "photo.jpg".to_string(),
+                mime: "image/jpeg".to_string(),
+                data: jpeg
+            }
+        );
+        assert_eq!(
+            file_message("notes.txt", b"hello".to_vec()),
+            MessageType::File("notes.txt".to_string(), b"hello".to_vec())
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use serde::Deserialize;
+use shared::server_error::ServerError;
+use shared::HistoryEntry;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqliteRow};
+use sqlx::Row;
+use std::str::FromStr;
+
+/// Podmínky pro výběr zobrazených zpráv; nevyplněná podmínka zprávy neomezuje
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct MessageFilter {
+    /// Odesílatel zpráv
+    pub user: Option<String>,
+    /// Místnost, do které zprávy patří
+    pub room: Option<String>,
+}
+
+impl MessageFilter {
+    /// Odesílatel, pokud je vyplněný; prázdné pole formuláře se nebere v úvahu
+    fn user(&self) -> Option<&str> {
+        self.user
+            .as_deref()
+            .map(str::trim)
+            .filter(|user| !user.is_empty())
+    }
+
+    /// Místnost, pokud je vyplněná
+    fn room(&self) -> Option<&str> {
+        self.room
+            .as_deref()
+            .map(str::trim)
+            .filter(|room| !room.is_empty())
+    }
+}
+
+/// Otevře databázi chatovacího serveru jen pro čtení.
+///
+/// Webové rozhraní zprávy jen zobrazuje, do databáze zapisuje výhradně server.
+///
+/// # Arguments
+///
+/// * `database_url` - Stejná hodnota jako `--database-url` serveru, například `sqlite:chat.db`
+pub async fn connect_read_only(database_url: &str) -> Result<SqlitePool, ServerError> {
+    let options = SqliteConnectOptions::from_str(database_url)?.read_only(true);
+    Ok(SqlitePool::connect_with(options).await?)
+}
+
+/// Načte nejvýše `limit` posledních zpráv z databáze chatovacího serveru
+/// seřazených od nejstarší.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool se stejnou databází, do které zapisuje server
+/// * `filter` - Podmínky výběru zpráv
+/// * `limit` - Maximální počet načtených zpráv
+pub async fn load_messages(
+    pool: &SqlitePool,
+    filter: &MessageFilter,
+    limit: u32,
+) -> Result<Vec<HistoryEntry>, ServerError> {
+    let rows = sqlx::query(
+        "SELECT id, sender, room, timestamp, kind, content, filename, size FROM (
+            SELECT * FROM messages
+            WHERE (? IS NULL OR sender = ?) AND (? IS NULL OR room = ?)
+            ORDER BY id DESC LIMIT ?
+         ) ORDER BY id ASC",
+    )
+    .bind(filter.user())
+    .bind(filter.user())
+    .bind(filter.room())
+    .bind(filter.room())
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(history_entry).collect())
+}
+
+/// Načte nejvýše `limit` zpráv s ID větším než `after_id` seřazených od nejstarší.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool se stejnou databází, do které zapisuje server
+/// * `filter` - Podmínky výběru zpráv
+/// * `after_id` - ID poslední zprávy, kterou prohlížeč už zobrazil
+/// * `limit` - Maximální počet načtených zpráv
+pub async fn load_messages_after(
+    pool: &SqlitePool,
+    filter: &MessageFilter,
+    after_id: i64,
+    limit: u32,
+) -> Result<Vec<HistoryEntry>, ServerError> {
+    let rows = sqlx::query(
+        "SELECT id, sender, room, timestamp, kind, content, filename, size FROM messages
+         WHERE id > ? AND (? IS NULL OR sender = ?) AND (? IS NULL OR room = ?)
+         ORDER BY id ASC LIMIT ?",
+    )
+    .bind(after_id)
+    .bind(filter.user())
+    .bind(filter.user())
+    .bind(filter.room())
+    .bind(filter.room())
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(history_entry).collect())
+}
+
+/// Převede řádek tabulky `messages` na záznam historie
+fn history_entry(row: &SqliteRow) -> HistoryEntry {
+    HistoryEntry {
+        id: row.get("id"),
+        sender: row.get("sender"),
+        room: row.get("room"),
+        timestamp: row.get("timestamp"),
+        kind: row.get("kind"),
+        content: row.get("content"),
+        filename: row.get("filename"),
+        size: row.get("size"),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Vytvoří databázi se stejnou tabulkou zpráv, jakou zakládá server
+    pub(crate) async fn database() -> Result<SqlitePool, ServerError> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        sqlx::query(
+            "CREATE TABLE messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                content TEXT NOT NULL,
+                sender TEXT NOT NULL DEFAULT '',
+                room TEXT NOT NULL DEFAULT 'general',
+                kind TEXT NOT NULL DEFAULT 'text',
+                filename TEXT,
+                size INTEGER,
+                timestamp INTEGER NOT NULL DEFAULT 0
+            );
+            INSERT INTO messages (sender, room, content, timestamp) VALUES
+                ('alice', 'general', 'hello', 100),
+                ('bob', 'general', 'hi', 101),
+                ('alice', 'dev', 'build passed', 102);
+            INSERT INTO messages (sender, room, kind, content, filename, size, timestamp)
+                VALUES ('bob', 'dev', 'file', '', 'notes.txt', 42, 103);",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(pool)
+    }
+
+    #[tokio::test]
+    async fn test_load_messages() -> Result<(), ServerError> {
+        let pool = database().await?;
+
+        let all = load_messages(&pool, &MessageFilter::default(), 10).await?;
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[3].filename.as_deref(), Some("notes.txt"));
+
+        // The newest messages are kept, but listed from the oldest
+        let latest = load_messages(&pool, &MessageFilter::default(), 2).await?;
+        let ids: Vec<i64> = latest.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![3, 4]);
+
+        let filter = MessageFilter {
+            user: Some("alice".to_string()),
+            room: Some(" ".to_string()),
+        };
+        let alice = load_messages(&pool, &filter, 10).await?;
+        assert!(alice.iter().all(|entry| entry.sender == "alice"));
+        assert_eq!(alice.len(), 2);
+
+        let dev = MessageFilter {
+            user: None,
+            room: Some("dev".to_string()),
+        };
+        let newer = load_messages_after(&pool, &dev, 3, 10).await?;
+        assert_eq!(newer.len(), 1);
+        assert_eq!(newer[0].id, 4);
+        assert!(load_messages_after(&pool, &dev, 4, 10).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_is_read_only() -> Result<(), ServerError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("chat.db");
+        let url = format!("sqlite:{}", path.display());
+        let writer = SqlitePool::connect(&format!("{}?mode=rwc", url)).await?;
+        sqlx::query("CREATE TABLE messages (id INTEGER PRIMARY KEY, sender TEXT)")
+            .execute(&writer)
+            .await?;
+
+        let pool = connect_read_only(&url).await?;
+        assert!(sqlx::query("DELETE FROM messages")
+            .execute(&pool)
+            .await
+            .is_err());
+
+        writer.close().await;
+        pool.close().await;
+        Ok(())
+    }
+}
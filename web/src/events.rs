@@ -0,0 +1,107 @@
+use crate::db::{self, MessageFilter};
+use crate::page;
+use axum::response::sse::Event;
+use futures::stream::{self, Stream, StreamExt};
+use sqlx::sqlite::SqlitePool;
+use std::convert::Infallible;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+/// Stav sledování nových zpráv jednoho prohlížeče
+struct Watch {
+    pool: SqlitePool,
+    filter: MessageFilter,
+    /// ID poslední odeslané zprávy
+    last_id: i64,
+    limit: u32,
+}
+
+/// Vytvoří nekonečný stream událostí s novými zprávami uloženými serverem.
+///
+/// Webové rozhraní se serverem nekomunikuje, nové zprávy proto v zadaném
+/// intervalu hledá v jeho databázi. Každá událost nese ID zprávy, aby
+/// prohlížeč po obnovení spojení pokračoval tam, kde skončil, a jako data
+/// hotovou položku seznamu zpráv.
+///
+/// # Arguments
+///
+/// * `pool` - Databázový pool se stejnou databází, do které zapisuje server
+/// * `filter` - Podmínky výběru zpráv
+/// * `after_id` - ID poslední zprávy, kterou prohlížeč už zobrazil
+/// * `limit` - Maximální počet zpráv načtených při jednom dotazu
+/// * `poll_interval` - Jak často se databáze dotazuje na nové zprávy
+pub fn message_events(
+    pool: SqlitePool,
+    filter: MessageFilter,
+    after_id: i64,
+    limit: u32,
+    poll_interval: Duration,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let mut ticks = interval(poll_interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let watch = Watch {
+        pool,
+        filter,
+        last_id: after_id,
+        limit,
+    };
+
+    stream::unfold((watch, ticks), |(mut watch, mut ticks)| async move {
+        ticks.tick().await;
+        let messages =
+            match db::load_messages_after(&watch.pool, &watch.filter, watch.last_id, watch.limit)
+                .await
+            {
+                Ok(messages) => messages,
+                Err(e) => {
+                    // Databáze může být chvíli zamčená serverem, zkusí se to znovu
+                    println!("Error loading new messages: {:?}", e);
+                    Vec::new()
+                }
+            };
+        if let Some(last) = messages.last() {
+            watch.last_id = last.id;
+        }
+        Some((stream::iter(messages), (watch, ticks)))
+    })
+    .flatten()
+    .map(|message| {
+        Ok(Event::default()
+            .id(message.id.to_string())
+            .data(page::message_item(&message)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::database;
+
+    #[tokio::test]
+    async fn test_line_breaks_do_not_break_events() -> Result<(), shared::server_error::ServerError>
+    {
+        let pool = database().await?;
+        // Browsers submit textarea line breaks as CRLF
+        sqlx::query("INSERT INTO messages (sender, room, content, timestamp) VALUES (?, ?, ?, ?)")
+            .bind("alice")
+            .bind("general")
+            .bind("line one\r\nline two\rthree")
+            .bind(104)
+            .execute(&pool)
+            .await?;
+
+        let events: Vec<Event> = message_events(
+            pool,
+            MessageFilter::default(),
+            4,
+            10,
+            Duration::from_millis(1),
+        )
+        .take(1)
+        .map(|event| event.unwrap())
+        .collect()
+        .await;
+        let event = format!("{:?}", events[0]);
+        assert!(event.contains("line one<br>line two<br>three"));
+        Ok(())
+    }
+}
@@ -1,74 +1,409 @@
+mod chat;
+mod db;
+mod events;
+mod page;
+
 use axum::{
-    extract::{Extension, Path, Query},
-    http::StatusCode,
-    response::Html,
-    routing::{delete, get},
-    Router,
+    extract::{DefaultBodyLimit, Extension, Multipart, Query},
+    headers::{authorization::Basic, Authorization},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Redirect, Response,
+    },
+    routing::get,
+    Router, TypedHeader,
 };
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use chat::{ChatServer, Credentials};
+use clap::Parser;
+use db::MessageFilter;
+use futures::stream::Stream;
+use serde::Deserialize;
+use shared::client_error::ClientError;
+use shared::server_error::ServerError;
+use shared::tls::{ServerTrust, TlsClient};
+use shared::{MessageType, DEFAULT_ROOM};
+use sqlx::sqlite::SqlitePool;
+use std::convert::Infallible;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::path::PathBuf;
+use tokio::time::Duration;
+
+/// Výchozí největší velikost souboru nahraného z prohlížeče v bajtech (8 MiB)
+const DEFAULT_MAX_UPLOAD_SIZE: usize = 8 * 1024 * 1024;
+
+/// Rezerva pro ostatní pole formuláře nad rámec velikosti souboru
+const FORM_OVERHEAD: usize = 64 * 1024;
+
+/// Webové rozhraní pro čtení zpráv uložených chatovacím serverem
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, default_value = "127.0.0.1")]
+    ip: String,
+
+    #[arg(short, long, default_value = "3000")]
+    port: u16,
+
+    /// Databáze chatovacího serveru (stejná hodnota jako `--database-url` serveru)
+    #[arg(short, long, default_value = "sqlite:chat.db")]
+    database_url: String,
+
+    /// Maximální počet zpráv zobrazených na stránce
+    #[arg(long, default_value = "100")]
+    limit: u32,
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Message {
-    user: String,
-    content: String,
+    /// Interval v milisekundách, ve kterém se v databázi hledají nové zprávy pro živé zobrazení
+    #[arg(long, default_value = "500")]
+    poll_interval: u64,
+
+    /// Adresa chatovacího serveru, přes který se posílají zprávy z prohlížeče
+    #[arg(long, default_value = "127.0.0.1:11111")]
+    server: String,
+
+    /// Největší soubor v bajtech, který lze nahrát z prohlížeče
+    #[arg(long, default_value_t = DEFAULT_MAX_UPLOAD_SIZE)]
+    max_upload_size: usize,
+
+    /// PEM soubor s certifikační autoritou, kterou musí být podepsaný certifikát serveru; zapne TLS
+    #[arg(long, conflicts_with = "tls_fingerprint")]
+    tls_ca: Option<PathBuf>,
+
+    /// SHA-256 otisk certifikátu serveru, který se přijme bez certifikační autority; zapne TLS
+    #[arg(long)]
+    tls_fingerprint: Option<String>,
+
+    /// Jméno serveru, pro které musí platit jeho certifikát; výchozí je IP adresa z `--server`
+    #[arg(long)]
+    tls_server_name: Option<String>,
 }
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 struct AppState {
-    messages: Arc<RwLock<Vec<Message>>>,
+    /// Databáze, do které zprávy ukládá chatovací server
+    pool: SqlitePool,
+    limit: u32,
+    poll_interval: Duration,
+    /// Chatovací server, kterému se předávají zprávy z prohlížeče
+    chat: ChatServer,
+    max_upload_size: usize,
+}
+
+/// Parametry streamu nových zpráv kromě podmínek výběru
+#[derive(Debug, Deserialize)]
+struct EventsQuery {
+    /// ID poslední zprávy, kterou prohlížeč zobrazil
+    after: Option<i64>,
 }
 
 #[tokio::main]
-async fn main() {
-    let app_state = AppState::default();
+async fn main() -> Result<(), ServerError> {
+    let args = Args::parse();
+    let pool = db::connect_read_only(&args.database_url).await?;
+    let trust = match (args.tls_ca, args.tls_fingerprint) {
+        (Some(path), _) => Some(ServerTrust::CaFile(path)),
+        (None, Some(fingerprint)) => Some(ServerTrust::Fingerprint(fingerprint)),
+        (None, None) => None,
+    };
+    let tls = match trust {
+        Some(trust) => {
+            let host = args
+                .server
+                .rsplit_once(':')
+                .map_or(args.server.as_str(), |(host, _)| host);
+            let server_name = args.tls_server_name.as_deref().unwrap_or(host);
+            Some(TlsClient::new(&trust, server_name)?)
+        }
+        None => None,
+    };
+    let app_state = AppState {
+        pool,
+        limit: args.limit,
+        poll_interval: Duration::from_millis(args.poll_interval.max(1)),
+        chat: ChatServer {
+            address: args.server,
+            tls,
+        },
+        max_upload_size: args.max_upload_size,
+    };
+    let body_limit = args.max_upload_size + FORM_OVERHEAD;
 
     let app = Router::new()
         .route("/", get(show_messages))
         .route("/filter", get(filter_messages))
-        .route("/delete_user/:user", delete(delete_user))
+        .route("/events", get(stream_messages))
+        .route(
+            "/send",
+            get(show_send_form)
+                .post(send_message)
+                .layer(DefaultBodyLimit::max(body_limit)),
+        )
         .layer(Extension(app_state));
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let addr: SocketAddr = format!("{}:{}", args.ip, args.port)
+        .parse()
+        .map_err(|e| ServerError::Other(format!("Invalid address: {}", e)))?;
     println!("Listening on {}", addr);
     axum::Server::bind(&addr)
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
-        .unwrap();
+        .map_err(|e| ServerError::Other(e.to_string()))
 }
 
-async fn show_messages(Extension(state): Extension<AppState>) -> Html<String> {
-    let messages = state.messages.read().await;
-    let mut html = String::from("<h1>Messages</h1><ul>");
-    for message in messages.iter() {
-        html.push_str(&format!("<li>{}: {}</li>", message.user, message.content));
-    }
-    html.push_str("</ul>");
-    Html(html)
+async fn show_messages(Extension(state): Extension<AppState>) -> Result<Html<String>, StatusCode> {
+    let messages = db::load_messages(&state.pool, &MessageFilter::default(), state.limit)
+        .await
+        .map_err(internal_error)?;
+    Ok(Html(page::messages_page("Messages", &messages)))
 }
 
 async fn filter_messages(
     Extension(state): Extension<AppState>,
-    Query(params): Query<HashMap<String, String>>,
-) -> Html<String> {
-    let user = params.get("user").cloned().unwrap_or_default();
-    let messages = state.messages.read().await;
-    let mut html = String::from("<h1>Filtered Messages</h1><ul>");
-    for message in messages.iter().filter(|m| m.user == user) {
-        html.push_str(&format!("<li>{}: {}</li>", message.user, message.content));
-    }
-    html.push_str("</ul>");
-    Html(html)
+    Query(filter): Query<MessageFilter>,
+) -> Result<Html<String>, StatusCode> {
+    let messages = db::load_messages(&state.pool, &filter, state.limit)
+        .await
+        .map_err(internal_error)?;
+    Ok(Html(page::messages_page("Filtered Messages", &messages)))
+}
+
+/// Posílá prohlížeči nové zprávy jako Server-Sent Events.
+///
+/// Při obnovení spojení prohlížeč pošle hlavičku `Last-Event-ID`, která má
+/// přednost před parametrem `after` z původní adresy.
+async fn stream_messages(
+    Extension(state): Extension<AppState>,
+    Query(filter): Query<MessageFilter>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let after_id = last_event_id.or(query.after).unwrap_or_default();
+
+    let events = events::message_events(
+        state.pool,
+        filter,
+        after_id,
+        state.limit,
+        state.poll_interval,
+    );
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Parametry formuláře pro odeslání zprávy
+#[derive(Debug, Deserialize)]
+struct SendQuery {
+    room: Option<String>,
 }
 
-async fn delete_user(
+/// Zobrazí formulář pro odeslání zprávy; bez přihlašovacích údajů si je prohlížeč vyžádá
+async fn show_send_form(
+    authorization: Option<TypedHeader<Authorization<Basic>>>,
+    Query(query): Query<SendQuery>,
+) -> Response {
+    let Some(TypedHeader(authorization)) = authorization else {
+        return unauthorized();
+    };
+    let room = query.room.as_deref().unwrap_or(DEFAULT_ROOM);
+    Html(page::send_page(authorization.username(), room, None)).into_response()
+}
+
+/// Pošle text a nahraný soubor z formuláře do chatu za přihlášeného uživatele.
+///
+/// Jméno a heslo z HTTP Basic autentizace ověří až chatovací server, webové
+/// rozhraní se k němu přihlásí stejně jako klient. Po odeslání přesměruje na
+/// zprávy místnosti. Prohlížeč posílá uložené jméno a heslo s každým požadavkem,
+/// formuláře odeslané z cizích stránek se proto odmítají.
+async fn send_message(
     Extension(state): Extension<AppState>,
-    Path(user): Path<String>,
-) -> StatusCode {
-    let mut messages = state.messages.write().await;
-    messages.retain(|message| message.user != user);
-    StatusCode::NO_CONTENT
+    authorization: Option<TypedHeader<Authorization<Basic>>>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Response {
+    if !same_origin(&headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            Html("<h1>Messages can only be sent from this site</h1>".to_string()),
+        )
+            .into_response();
+    }
+    let Some(TypedHeader(authorization)) = authorization else {
+        return unauthorized();
+    };
+    let credentials = Credentials {
+        username: authorization.username().to_string(),
+        password: authorization.password().to_string(),
+    };
+
+    let (room, messages) = match read_form(multipart, state.max_upload_size).await {
+        Ok(form) => form,
+        Err(e) => return send_error(&credentials.username, DEFAULT_ROOM, e),
+    };
+    match state.chat.post(&credentials, &room, messages).await {
+        Ok(()) => {
+            let query = serde_urlencoded::to_string([("room", &room)]).unwrap_or_default();
+            Redirect::to(&format!("/filter?{}", query)).into_response()
+        }
+        Err(e) => send_error(&credentials.username, &room, e),
+    }
+}
+
+/// Přečte formulář s místností, textem a souborem.
+///
+/// # Returns
+///
+/// Vrací místnost a zprávy, které se do ní mají odeslat.
+///
+/// # Errors
+///
+/// Vrací `ClientError::InvalidInput`, pokud formulář nejde přečíst, soubor je
+/// větší než `max_upload_size` nebo formulář neobsahuje nic k odeslání.
+async fn read_form(
+    mut multipart: Multipart,
+    max_upload_size: usize,
+) -> Result<(String, Vec<MessageType>), ClientError> {
+    let invalid = |e: axum::extract::multipart::MultipartError| {
+        ClientError::InvalidInput(format!("Cannot read the form: {}", e))
+    };
+    let mut room = DEFAULT_ROOM.to_string();
+    let (mut text, mut file) = (None, None);
+    while let Some(field) = multipart.next_field().await.map_err(invalid)? {
+        match field.name() {
+            Some("room") => room = field.text().await.map_err(invalid)?.trim().to_string(),
+            Some("text") => {
+                text = Some(normalize_line_breaks(&field.text().await.map_err(invalid)?))
+            }
+            Some("file") => {
+                let filename = field.file_name().unwrap_or_default().to_string();
+                let data = field.bytes().await.map_err(invalid)?;
+                file = Some((filename, data));
+            }
+            _ => {}
+        }
+    }
+
+    let mut messages = Vec::new();
+    if let Some(text) = text.filter(|text| !text.trim().is_empty()) {
+        messages.push(MessageType::Text(text));
+    }
+    // Prázdné pole pro soubor posílá prohlížeč bez názvu souboru
+    if let Some((filename, data)) = file.filter(|(filename, _)| !filename.is_empty()) {
+        if data.len() > max_upload_size {
+            return Err(ClientError::InvalidInput(format!(
+                "{} has {} bytes, more than the upload limit of {} bytes",
+                filename,
+                data.len(),
+                max_upload_size
+            )));
+        }
+        messages.push(chat::file_message(&filename, data.to_vec()));
+    }
+    if messages.is_empty() {
+        return Err(ClientError::InvalidInput(
+            "Enter a message or choose a file".to_string(),
+        ));
+    }
+    Ok((room, messages))
+}
+
+/// Ověří, že požadavek přišel ze stránky tohoto webového rozhraní.
+///
+/// Prohlížeč u požadavků POST posílá hlavičku `Origin`, případně aspoň
+/// `Referer`; jejich adresa se musí shodovat s hlavičkou `Host`. Požadavek
+/// bez obou hlaviček neposlal prohlížeč, ale například skript, a přijme se.
+fn same_origin(headers: &HeaderMap) -> bool {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let Some(source) = header(header::ORIGIN).or_else(|| header(header::REFERER)) else {
+        return true;
+    };
+    let authority = source
+        .split_once("://")
+        .map(|(_, rest)| rest.split('/').next().unwrap_or_default());
+    match (authority, header(header::HOST)) {
+        (Some(authority), Some(host)) => authority.eq_ignore_ascii_case(host),
+        _ => false,
+    }
+}
+
+/// Převede konce řádků `\r\n` a `\r`, které posílají prohlížeče, na `\n`
+fn normalize_line_breaks(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Odpověď, po které si prohlížeč vyžádá jméno a heslo uživatele chatu
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"chat\"")],
+        Html("<h1>Log in with your chat username and password</h1>".to_string()),
+    )
+        .into_response()
+}
+
+/// Převede chybu při odesílání na odpověď; chybu vstupu zobrazí ve formuláři
+fn send_error(username: &str, room: &str, error: ClientError) -> Response {
+    let status = match &error {
+        ClientError::Authentication(_) => return unauthorized(),
+        ClientError::InvalidInput(_) | ClientError::Server(_) => StatusCode::BAD_REQUEST,
+        _ => {
+            println!("Error posting to chat server: {:?}", error);
+            StatusCode::BAD_GATEWAY
+        }
+    };
+    let page = page::send_page(username, room, Some(&error.to_string()));
+    (status, Html(page)).into_response()
+}
+
+/// Zaloguje chybu databáze; klient dostane jen obecnou odpověď
+fn internal_error(error: ServerError) -> StatusCode {
+    println!("Database error: {:?}", error);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    /// Hlavičky požadavku na webové rozhraní na adrese `127.0.0.1:3000`
+    fn headers(pairs: &[(header::HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers: HeaderMap = pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+            .collect();
+        headers.insert(header::HOST, HeaderValue::from_static("127.0.0.1:3000"));
+        headers
+    }
+
+    #[test]
+    fn test_same_origin() {
+        assert!(same_origin(&headers(&[(
+            header::ORIGIN,
+            "http://127.0.0.1:3000"
+        )])));
+        assert!(same_origin(&headers(&[(
+            header::REFERER,
+            "http://127.0.0.1:3000/send?room=dev"
+        )])));
+        // Scripts such as curl send neither header
+        assert!(same_origin(&headers(&[])));
+
+        // A form submitted from another site is refused even with a matching referer
+        assert!(!same_origin(&headers(&[
+            (header::ORIGIN, "https://evil.example"),
+            (header::REFERER, "http://127.0.0.1:3000/send")
+        ])));
+        assert!(!same_origin(&headers(&[(header::ORIGIN, "null")])));
+    }
+
+    #[test]
+    fn test_normalize_line_breaks() {
+        assert_eq!(
+            normalize_line_breaks("one\r\ntwo\rthree\n"),
+            "one\ntwo\nthree\n"
+        );
+    }
 }
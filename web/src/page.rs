@@ -0,0 +1,155 @@
+use shared::HistoryEntry;
+
+/// Nahradí znaky se zvláštním významem v HTML, aby obsah zpráv nešlo vložit jako značky.
+///
+/// Konce řádků se zapíší jako číselné entity, takže výsledek je vždy jeden
+/// řádek a lze ho poslat jako data jedné události Server-Sent Events.
+///
+/// # Arguments
+///
+/// * `text` - Text od uživatele, například obsah zprávy
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '\r' => escaped.push_str("&#13;"),
+            '\n' => escaped.push_str("&#10;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Skript, který k seznamu zpráv průběžně připojuje nové zprávy z `/events`.
+///
+/// Přebírá podmínky výběru z adresy stránky; `LAST_ID` se nahradí ID poslední
+/// zobrazené zprávy. Po výpadku spojení prohlížeč pokračuje sám od poslední
+/// přijaté události.
+const LIVE_SCRIPT: &str = r#"<script>
+const params = new URLSearchParams(window.location.search);
+params.set("after", "LAST_ID");
+const source = new EventSource("/events?" + params);
+source.addEventListener("message", (event) => {
+    document.getElementById("messages").insertAdjacentHTML("beforeend", event.data);
+    window.scrollTo(0, document.body.scrollHeight);
+});
+</script>"#;
+
+/// Vytvoří stránku se seznamem zpráv, ke kterému se nové zprávy připojují živě.
+///
+/// # Arguments
+///
+/// * `title` - Nadpis stránky
+/// * `messages` - Zprávy seřazené od nejstarší
+pub fn messages_page(title: &str, messages: &[HistoryEntry]) -> String {
+    let mut html = format!(
+        "<h1>{}</h1><p><a href=\"/send\">Send a message</a></p><ul id=\"messages\">",
+        escape_html(title)
+    );
+    for message in messages {
+        html.push_str(&message_item(message));
+    }
+    html.push_str("</ul>");
+    let last_id = messages
+        .last()
+        .map(|message| message.id)
+        .unwrap_or_default();
+    html.push_str(&LIVE_SCRIPT.replace("LAST_ID", &last_id.to_string()));
+    html
+}
+
+/// Vytvoří stránku s formulářem pro odeslání zprávy nebo souboru.
+///
+/// # Arguments
+///
+/// * `username` - Přihlášený uživatel, za kterého se zpráva odešle
+/// * `room` - Předvyplněná místnost
+/// * `error` - Důvod, proč se předchozí odeslání nepodařilo
+pub fn send_page(username: &str, room: &str, error: Option<&str>) -> String {
+    let mut html = format!("<h1>Send as {}</h1>", escape_html(username));
+    if let Some(error) = error {
+        html.push_str(&format!("<p><strong>{}</strong></p>", escape_html(error)));
+    }
+    html.push_str(&format!(
+        "<form method=\"post\" action=\"/send\" enctype=\"multipart/form-data\">\
+         <p><label>Room <input name=\"room\" value=\"{}\" required></label></p>\
+         <p><textarea name=\"text\" rows=\"4\" cols=\"60\"></textarea></p>\
+         <p><label>File or image <input type=\"file\" name=\"file\"></label></p>\
+         <p><button type=\"submit\">Send</button></p>\
+         </form>",
+        escape_html(room)
+    ));
+    html
+}
+
+/// Vytvoří položku seznamu s jednou zprávou; u souborů a obrázků zobrazí název a velikost
+pub fn message_item(message: &HistoryEntry) -> String {
+    let content = match (message.kind.as_str(), &message.filename) {
+        ("text", _) | (_, None) => escape_lines(&message.content),
+        (kind, Some(filename)) => format!(
+            "<em>sent {} {} ({} bytes)</em>",
+            kind,
+            escape_html(filename),
+            message.size.unwrap_or_default()
+        ),
+    };
+    format!(
+        "<li>[{}] #{} {}: {}</li>",
+        format_timestamp(message.timestamp),
+        escape_html(&message.room),
+        escape_html(&message.sender),
+        content
+    )
+}
+
+/// Nahradí znaky se zvláštním významem v HTML a konce řádků (i `\r\n` z formulářů) značkou `<br>`
+fn escape_lines(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .split(['\r', '\n'])
+        .map(escape_html)
+        .collect::<Vec<_>>()
+        .join("<br>")
+}
+
+/// Převede unixový čas na místní datum a čas
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| "?".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_are_escaped() {
+        let message = HistoryEntry {
+            id: 1,
+            sender: "mallory".to_string(),
+            room: "general".to_string(),
+            timestamp: 0,
+            kind: "text".to_string(),
+            content: "<script>alert('x')</script>".to_string(),
+            filename: None,
+            size: None,
+        };
+        let html = messages_page("Messages", &[message]);
+        assert!(html.contains("mallory: &lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;"));
+        assert!(!html.contains("<script>alert"));
+        assert!(html.contains(r#"params.set("after", "1")"#));
+
+        let form = send_page("alice", "\"><script>", Some("<b>denied</b>"));
+        assert!(form.contains(r#"value="&quot;&gt;&lt;script&gt;""#));
+        assert!(form.contains("&lt;b&gt;denied&lt;/b&gt;"));
+    }
+}